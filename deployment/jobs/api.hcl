@@ -48,6 +48,9 @@ job "api" {
                 INFLUXDB_ORG="{{ .INFLUXDB_ORG }}"
                 INFLUXDB_TOKEN="{{ .INFLUXDB_TOKEN }}"
                 {{ end }}
+                {{ with nomadVar "nomad/jobs/api"}}
+                API_TOKENS="{{ .API_TOKENS }}"
+                {{ end }}
                 EOT
             }
 
@@ -0,0 +1,404 @@
+//! Alerts evaluated over the latest telemetry
+//!
+//! Each rule watches a field of one device. A rule fires once its condition has held for
+//! `for_secs` and resolves as soon as the condition clears; `clear` thresholds give hysteresis so a
+//! value hovering around its threshold doesn't flap. Notifications are only sent when a rule fires
+//! or resolves, not on every evaluation.
+
+use crate::db::Db;
+use crate::measurement::{device_measurements, query_latest_fields, Latest};
+use crate::notify::{Sink, SinkConfig};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Age, in seconds, of the oldest point considered, so devices that stopped reporting are noticed
+const LOOKBACK: u64 = 86400;
+
+/// Age, in seconds, beyond which a point is too old to evaluate field conditions against
+const MAX_POINT_AGE: i64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct AlertConfig {
+    /// Interval between evaluations, in seconds
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+
+    /// Where notifications are delivered; logged only if none are configured
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+}
+
+fn default_interval_secs() -> u64 {
+    15
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        AlertConfig {
+            interval_secs: default_interval_secs(),
+            rules: Vec::new(),
+            sinks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rule {
+    /// Name identifying the rule in notifications
+    pub name: String,
+
+    /// Device watched, e.g. "mppt.lil"
+    pub device: String,
+
+    #[serde(flatten)]
+    pub condition: Condition,
+
+    /// Time the condition must hold before the rule fires, in seconds
+    #[serde(default)]
+    pub for_secs: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "condition", rename_all = "snake_case")]
+pub enum Condition {
+    /// Numeric field is below `threshold`, clearing once it reaches `clear`
+    Below {
+        field: String,
+        threshold: f64,
+        clear: Option<f64>,
+    },
+
+    /// Numeric field is above `threshold`, clearing once it drops to `clear`
+    Above {
+        field: String,
+        threshold: f64,
+        clear: Option<f64>,
+    },
+
+    /// Field differs from `value`, e.g. an MPPT error other than "No Error"
+    NotEqual { field: String, value: String },
+
+    /// Boolean field is set, e.g. the Multiplus overload LED
+    IsTrue { field: String },
+
+    /// Device has not reported for `max_age_secs`
+    NoData { max_age_secs: u64 },
+}
+
+/// Outcome of checking a condition against the latest point of a device
+#[derive(Debug, PartialEq)]
+enum Check {
+    Active(String),
+    Clear,
+
+    /// Field is missing or its point too old to tell
+    Unknown,
+}
+
+impl Condition {
+    /// Checks the condition; `firing` selects the clear threshold of the hysteresis band
+    fn check(&self, latest: Option<&Latest>, now: DateTime<Utc>, firing: bool) -> Check {
+        let age = latest.map(|(time, _)| now.signed_duration_since(*time).num_seconds());
+
+        if let Condition::NoData { max_age_secs } = self {
+            return match age {
+                None => Check::Active(format!("no data in the last {}s", LOOKBACK)),
+                Some(age) if age > *max_age_secs as i64 => {
+                    Check::Active(format!("no data for {}s", age))
+                }
+                Some(_) => Check::Clear,
+            };
+        }
+
+        let fields = match latest {
+            Some((_, fields)) if age.unwrap_or_default() <= MAX_POINT_AGE => fields,
+            _ => return Check::Unknown,
+        };
+
+        match self {
+            Condition::Below {
+                field,
+                threshold,
+                clear,
+            } => {
+                let limit = if firing { clear.unwrap_or(*threshold) } else { *threshold };
+                match fields.get(field).and_then(Value::as_f64) {
+                    None => Check::Unknown,
+                    Some(v) if v < limit => {
+                        Check::Active(format!("{} is {} (below {})", field, v, threshold))
+                    }
+                    Some(_) => Check::Clear,
+                }
+            }
+            Condition::Above {
+                field,
+                threshold,
+                clear,
+            } => {
+                let limit = if firing { clear.unwrap_or(*threshold) } else { *threshold };
+                match fields.get(field).and_then(Value::as_f64) {
+                    None => Check::Unknown,
+                    Some(v) if v > limit => {
+                        Check::Active(format!("{} is {} (above {})", field, v, threshold))
+                    }
+                    Some(_) => Check::Clear,
+                }
+            }
+            Condition::NotEqual { field, value } => match fields.get(field) {
+                None => Check::Unknown,
+                Some(Value::String(v)) if v == value => Check::Clear,
+                Some(v) => Check::Active(format!("{} is {}", field, v)),
+            },
+            Condition::IsTrue { field } => match fields.get(field).and_then(Value::as_bool) {
+                None => Check::Unknown,
+                Some(true) => Check::Active(format!("{} is set", field)),
+                Some(false) => Check::Clear,
+            },
+            Condition::NoData { .. } => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Firing,
+    Resolved,
+}
+
+/// Sent to the sinks when a rule fires or resolves
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    pub rule: String,
+    pub device: String,
+    pub status: Status,
+    pub message: String,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+enum RuleState {
+    #[default]
+    Ok,
+
+    /// Condition holds but not yet for long enough
+    Pending { since: DateTime<Utc> },
+
+    Firing,
+}
+
+impl Rule {
+    /// Advances `state` by one evaluation, returning a notification if the rule fired or resolved
+    fn step(
+        &self,
+        state: &mut RuleState,
+        latest: Option<&Latest>,
+        now: DateTime<Utc>,
+    ) -> Option<Notification> {
+        let notification = |status, message| Notification {
+            rule: self.name.clone(),
+            device: self.device.clone(),
+            status,
+            message,
+            time: now,
+        };
+
+        match self.condition.check(latest, now, *state == RuleState::Firing) {
+            Check::Active(message) => {
+                let since = match state {
+                    RuleState::Firing => return None,
+                    RuleState::Pending { since } => *since,
+                    RuleState::Ok => now,
+                };
+                if now.signed_duration_since(since).num_seconds() >= self.for_secs as i64 {
+                    *state = RuleState::Firing;
+                    Some(notification(Status::Firing, message))
+                } else {
+                    *state = RuleState::Pending { since };
+                    None
+                }
+            }
+            Check::Clear => match std::mem::take(state) {
+                RuleState::Firing => Some(notification(Status::Resolved, "cleared".to_string())),
+                _ => None,
+            },
+            Check::Unknown => None,
+        }
+    }
+}
+
+/// Starts evaluating the configured rules, if any
+pub fn spawn(db: Arc<Db>, config: &AlertConfig) -> Result<()> {
+    if config.rules.is_empty() {
+        return Ok(());
+    }
+
+    let devices: HashMap<String, &'static str> = device_measurements().into_iter().collect();
+    for rule in config.rules.iter() {
+        if !devices.contains_key(&rule.device) {
+            bail!("alert rule \"{}\" watches unknown device \"{}\"", rule.name, rule.device);
+        }
+    }
+
+    let mut sinks: Vec<Sink> = config.sinks.iter().map(Sink::connect).collect();
+    if sinks.is_empty() {
+        sinks.push(Sink::Log);
+    }
+
+    log::info!("evaluating {} alert rules", config.rules.len());
+    tokio::spawn(run(
+        db,
+        devices,
+        config.rules.clone(),
+        sinks,
+        Duration::from_secs(config.interval_secs.max(1)),
+    ));
+
+    Ok(())
+}
+
+async fn run(
+    db: Arc<Db>,
+    devices: HashMap<String, &'static str>,
+    rules: Vec<Rule>,
+    sinks: Vec<Sink>,
+    interval: Duration,
+) {
+    let mut measurements: Vec<&str> = rules.iter().map(|rule| devices[&rule.device]).collect();
+    measurements.sort_unstable();
+    measurements.dedup();
+
+    let mut states = vec![RuleState::Ok; rules.len()];
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+
+        let latest = match query_latest_fields(&db, &measurements, LOOKBACK).await {
+            Ok(latest) => latest,
+            Err(err) => {
+                log::warn!("alert: {}", err);
+                continue;
+            }
+        };
+
+        let now = Utc::now();
+        for (rule, state) in rules.iter().zip(states.iter_mut()) {
+            let latest = latest.get(devices[&rule.device]);
+            if let Some(notification) = rule.step(state, latest, now) {
+                for sink in sinks.iter() {
+                    if let Err(err) = sink.send(&notification).await {
+                        log::warn!("alert: failed to notify {:?}: {:?}", sink, err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AlertConfig, Condition, RuleState, Status};
+    use crate::measurement::Latest;
+    use chrono::{DateTime, Duration, Utc};
+    use serde_json::{json, Value};
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        let start: DateTime<Utc> = DateTime::parse_from_rfc3339("2023-08-01T12:00:00Z").unwrap().into();
+        start + Duration::seconds(secs)
+    }
+
+    fn point(secs: i64, fields: Value) -> Latest {
+        (at(secs).into(), fields.as_object().unwrap().clone())
+    }
+
+    fn config() -> AlertConfig {
+        toml::from_str(include_str!("../alerts.toml.example")).unwrap()
+    }
+
+    #[test]
+    fn parse_example_config() {
+        let config = config();
+        assert_eq!(6, config.rules.len());
+        assert_eq!(3, config.sinks.len());
+        assert!(matches!(config.rules[0].condition, Condition::Below { clear: Some(_), .. }));
+    }
+
+    #[test]
+    fn fires_after_holding_and_resolves_with_hysteresis() {
+        // battery below 12.0V for 5 minutes, clearing at 12.4V
+        let rule = &config().rules[0];
+        let mut state = RuleState::default();
+        let mut step = |secs, voltage: f64| {
+            let latest = point(secs, json!({ "voltage": voltage }));
+            rule.step(&mut state, Some(&latest), at(secs)).map(|n| n.status)
+        };
+
+        assert_eq!(None, step(0, 11.9));
+        assert_eq!(None, step(120, 11.8));
+        assert_eq!(Some(Status::Firing), step(300, 11.8));
+        assert_eq!(None, step(315, 11.7), "notified only once");
+        assert_eq!(None, step(330, 12.2), "still within hysteresis band");
+        assert_eq!(Some(Status::Resolved), step(345, 12.5));
+        assert_eq!(None, step(360, 12.5));
+    }
+
+    #[test]
+    fn pending_resets_when_condition_clears() {
+        let rule = &config().rules[0];
+        let mut state = RuleState::default();
+        let mut step = |secs, voltage: f64| {
+            let latest = point(secs, json!({ "voltage": voltage }));
+            rule.step(&mut state, Some(&latest), at(secs)).map(|n| n.status)
+        };
+
+        assert_eq!(None, step(0, 11.9));
+        assert_eq!(None, step(200, 12.1));
+        assert_eq!(None, step(300, 11.9));
+        assert_eq!(Some(Status::Firing), step(600, 11.9));
+    }
+
+    #[test]
+    fn field_conditions() {
+        let config = config();
+        let (mppt_error, overload) = (&config.rules[1], &config.rules[2]);
+
+        let mut state = RuleState::default();
+        let ok = point(0, json!({ "error": "No Error" }));
+        let failed = point(0, json!({ "error": "Bms Connection Lost" }));
+        assert_eq!(None, mppt_error.step(&mut state, Some(&ok), at(0)));
+        let notification = mppt_error.step(&mut state, Some(&failed), at(0)).unwrap();
+        assert_eq!(Status::Firing, notification.status);
+        assert_eq!("error is \"Bms Connection Lost\"", notification.message);
+
+        let mut state = RuleState::default();
+        let led = point(0, json!({ "overload": true }));
+        assert!(overload.step(&mut state, Some(&led), at(0)).is_some());
+
+        // old points are not evaluated
+        let mut state = RuleState::default();
+        assert_eq!(None, mppt_error.step(&mut state, Some(&failed), at(120)));
+        assert_eq!(RuleState::Ok, state);
+    }
+
+    #[test]
+    fn no_data() {
+        let rule = &config().rules[5];
+        let mut state = RuleState::default();
+        let latest = point(0, json!({ "panel_power": 0 }));
+
+        assert_eq!(None, rule.step(&mut state, Some(&latest), at(60)));
+        assert_eq!(
+            Some(Status::Firing),
+            rule.step(&mut state, Some(&latest), at(180)).map(|n| n.status)
+        );
+        assert_eq!(None, rule.step(&mut state, None, at(240)));
+    }
+}
@@ -0,0 +1,193 @@
+//! Bearer token authentication
+//!
+//! Every request must carry a configured API token, either in an `Authorization: Bearer <token>`
+//! header or, for clients such as `EventSource` which can't set headers, an `access_token` query
+//! parameter. Reading (GET/HEAD) requires the read scope, anything else requires the control scope.
+
+use crate::config::Config;
+use crate::error::ApiError;
+use axum::extract::State;
+use axum::http::{header, Method, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// May read telemetry
+    Read,
+
+    /// May read telemetry and change device state
+    Control,
+}
+
+impl Scope {
+    /// Scope needed to make a request with `method`
+    fn required_for(method: &Method) -> Scope {
+        if method == Method::GET || method == Method::HEAD {
+            Scope::Read
+        } else {
+            Scope::Control
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "control" => Ok(Scope::Control),
+            _ => Err(format!("unknown scope \"{}\"", s)),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ApiToken {
+    /// Name identifying the client in logs
+    pub name: String,
+    pub token: String,
+    pub scope: Scope,
+}
+
+impl std::fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiToken")
+            .field("name", &self.name)
+            .field("scope", &self.scope)
+            .finish()
+    }
+}
+
+impl FromStr for ApiToken {
+    type Err = String;
+
+    /// Parses "name:scope:token"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(scope), Some(token)) if !name.is_empty() && !token.is_empty() => {
+                Ok(ApiToken {
+                    name: name.to_string(),
+                    token: token.to_string(),
+                    scope: scope.parse()?,
+                })
+            }
+            _ => Err("expected api token like \"name:scope:token\"".to_string()),
+        }
+    }
+}
+
+/// Middleware rejecting requests without a token of sufficient scope
+pub async fn authenticate<B>(
+    State(config): State<Arc<Config>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ApiError> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let query = query_token(request.uri().query());
+    let presented = bearer.or(query.as_deref());
+
+    let token = authorize(&config.api_tokens, presented, request.method())?;
+    log::trace!("{} {} as {}", request.method(), request.uri().path(), token.name);
+
+    Ok(next.run(request).await)
+}
+
+/// Finds the configured token matching `presented` and checks it may make a `method` request
+fn authorize<'a>(
+    tokens: &'a [ApiToken],
+    presented: Option<&str>,
+    method: &Method,
+) -> Result<&'a ApiToken, ApiError> {
+    let presented = presented.ok_or(ApiError::Unauthorized)?;
+
+    let token = tokens
+        .iter()
+        .find(|token| constant_time_eq(token.token.as_bytes(), presented.as_bytes()))
+        .ok_or(ApiError::Unauthorized)?;
+
+    let required = Scope::required_for(method);
+    if token.scope < required {
+        return Err(ApiError::Forbidden(format!(
+            "token \"{}\" lacks the {:?} scope",
+            token.name, required
+        )));
+    }
+
+    Ok(token)
+}
+
+/// Percent-decoded `access_token` of a query string
+fn query_token(query: Option<&str>) -> Option<String> {
+    form_urlencoded::parse(query?.as_bytes())
+        .find(|(name, _)| name == "access_token")
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Compares without exiting early, so response timing doesn't reveal how much of a token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{authorize, query_token, ApiToken, Scope};
+    use axum::http::Method;
+
+    fn tokens() -> Vec<ApiToken> {
+        vec![
+            "dashboard:read:abc123".parse().unwrap(),
+            "ops:control:def456".parse().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn parse_token() {
+        let token: ApiToken = "dashboard:read:abc:123".parse().unwrap();
+        assert_eq!("dashboard", token.name);
+        assert_eq!("abc:123", token.token);
+        assert_eq!(Scope::Read, token.scope);
+
+        assert!("dashboard:admin:abc".parse::<ApiToken>().is_err());
+        assert!("dashboard:read:".parse::<ApiToken>().is_err());
+        assert!("abc".parse::<ApiToken>().is_err());
+    }
+
+    #[test]
+    fn authorize_scopes() {
+        let tokens = tokens();
+
+        assert_eq!("dashboard", authorize(&tokens, Some("abc123"), &Method::GET).unwrap().name);
+        assert_eq!("ops", authorize(&tokens, Some("def456"), &Method::GET).unwrap().name);
+        assert_eq!("ops", authorize(&tokens, Some("def456"), &Method::POST).unwrap().name);
+
+        let status = |presented, method| authorize(&tokens, presented, &method).unwrap_err().status();
+        assert_eq!(403, status(Some("abc123"), Method::POST).as_u16());
+        assert_eq!(401, status(Some("abc12"), Method::GET).as_u16());
+        assert_eq!(401, status(None, Method::GET).as_u16());
+    }
+
+    #[test]
+    fn no_tokens_rejects_everything() {
+        assert!(authorize(&[], Some(""), &Method::GET).is_err());
+    }
+
+    #[test]
+    fn token_from_query() {
+        let token = |query| query_token(Some(query));
+        assert_eq!(Some("abc".to_string()), token("devices=mppt.lil&access_token=abc"));
+        assert_eq!(Some("a+b%&c d".to_string()), token("access_token=a%2Bb%25%26c+d"));
+        assert_eq!(None, token("devices=mppt.lil"));
+        assert_eq!(None, query_token(None));
+    }
+}
@@ -1,3 +1,6 @@
+use crate::alert::AlertConfig;
+use crate::auth::ApiToken;
+use crate::soc::SocConfig;
 use anyhow::{Context, Result};
 use serde::Deserialize;
 use std::path::Path;
@@ -8,41 +11,94 @@ pub struct Config {
     pub influxdb_url: String,
     pub influxdb_org: String,
     pub influxdb_token: String,
+
+    /// Time a query result is reused for, in milliseconds (0 disables caching)
+    #[serde(default = "default_cache_ttl_ms")]
+    pub cache_ttl_ms: u64,
+
+    /// Tokens accepted from clients; requests are rejected if none are configured
+    #[serde(default)]
+    pub api_tokens: Vec<ApiToken>,
+
+    #[serde(default)]
+    pub alerts: AlertConfig,
+
+    /// Battery state of charge estimation, disabled if not set
+    #[serde(default)]
+    pub soc: Option<SocConfig>,
+}
+
+fn default_cache_ttl_ms() -> u64 {
+    crate::db::DEFAULT_CACHE_TTL_MS
 }
 
 impl Config {
-    /// Try to load config from current directory, or from the /etc/hab directory
+    /// Load config from the environment if BIND_ADDRESS is set, otherwise try the current
+    /// directory, or the /etc/hab directory
     pub fn load() -> Result<Config> {
-        Self::load_env().or_else(|_| {
-            let mut local_config_path = std::env::current_dir()?;
-            local_config_path.push("hab-api.toml");
-
-            let content = Self::load_file(&local_config_path)
-                .or_else(|_| Config::load_file(Path::new("/etc/hab/hab-api.toml")))
-                .with_context(|| {
-                    format!(
-                        "Failed to load config from {:?} or /etc/hab/hab-api.toml",
-                        &local_config_path
-                    )
-                })?;
-
-            toml::from_str(&content).with_context(|| "Failed to parse config file")
-        })
+        if let Some(config) = Self::load_env()? {
+            return Ok(config);
+        }
+
+        let mut local_config_path = std::env::current_dir()?;
+        local_config_path.push("hab-api.toml");
+
+        let content = Self::load_file(&local_config_path)
+            .or_else(|_| Config::load_file(Path::new("/etc/hab/hab-api.toml")))
+            .with_context(|| {
+                format!(
+                    "Failed to load config from {:?} or /etc/hab/hab-api.toml",
+                    &local_config_path
+                )
+            })?;
+
+        toml::from_str(&content).with_context(|| "Failed to parse config file")
     }
 
     fn load_file(path: &Path) -> Result<String> {
         std::fs::read_to_string(path).with_context(|| format!("Unable to read file {:?}", path))
     }
 
-    fn load_env() -> Result<Config> {
+    fn load_env() -> Result<Option<Config>> {
         // let vars: Vec<(String, String)> = std::env::vars().collect();
         // println!("Environment:\n{:?}", vars);
 
-        Ok(Config {
-            bind_address: std::env::var("BIND_ADDRESS")?,
-            influxdb_url: std::env::var("INFLUXDB_URL")?,
-            influxdb_org: std::env::var("INFLUXDB_ORG")?,
-            influxdb_token: std::env::var("INFLUXDB_TOKEN")?,
-        })
+        let bind_address = match std::env::var("BIND_ADDRESS") {
+            Ok(bind_address) => bind_address,
+            Err(_) => return Ok(None),
+        };
+        let var = |name| std::env::var(name).with_context(|| format!("{} is not set", name));
+
+        Ok(Some(Config {
+            bind_address,
+            influxdb_url: var("INFLUXDB_URL")?,
+            influxdb_org: var("INFLUXDB_ORG")?,
+            influxdb_token: var("INFLUXDB_TOKEN")?,
+            cache_ttl_ms: match std::env::var("CACHE_TTL_MS") {
+                Ok(ttl) => ttl.parse().with_context(|| "Failed to parse CACHE_TTL_MS")?,
+                Err(_) => default_cache_ttl_ms(),
+            },
+            api_tokens: match std::env::var("API_TOKENS") {
+                Ok(tokens) => tokens
+                    .split(',')
+                    .filter(|token| !token.trim().is_empty())
+                    .map(|token| token.parse().map_err(anyhow::Error::msg))
+                    .collect::<Result<_>>()
+                    .with_context(|| "Failed to parse API_TOKENS")?,
+                Err(_) => Vec::new(),
+            },
+            alerts: match std::env::var("ALERTS_CONFIG") {
+                Ok(path) => toml::from_str(&Self::load_file(Path::new(&path))?)
+                    .with_context(|| format!("Failed to parse alerts config {:?}", path))?,
+                Err(_) => AlertConfig::default(),
+            },
+            soc: match std::env::var("SOC_CONFIG") {
+                Ok(path) => Some(
+                    toml::from_str(&Self::load_file(Path::new(&path))?)
+                        .with_context(|| format!("Failed to parse soc config {:?}", path))?,
+                ),
+                Err(_) => None,
+            },
+        }))
     }
 }
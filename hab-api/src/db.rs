@@ -0,0 +1,174 @@
+//! Shared InfluxDB client with a short-lived query cache
+//!
+//! Every dashboard tab polls the same handful of queries, so results are cached by query text
+//! for a short time and reused by any request made within that time.
+
+use crate::config::Config;
+use crate::error::ApiError;
+use futures_util::stream;
+use influxdb2::models::DataPoint;
+use influxdb2::api::query::FluxRecord;
+use influxdb2::RequestError;
+use influxdb2_structmap::FromMap;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+/// Maximum time to wait on a single InfluxDB query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default time a query result is reused for
+pub const DEFAULT_CACHE_TTL_MS: u64 = 1000;
+
+type CacheEntry = (Instant, Arc<dyn Any + Send + Sync>);
+
+pub struct Db {
+    client: influxdb2::Client,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Snapshot of cache effectiveness
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub ttl_ms: u64,
+}
+
+impl Db {
+    pub fn new(config: &Config) -> Db {
+        Db {
+            client: influxdb2::Client::new(
+                &config.influxdb_url,
+                &config.influxdb_org,
+                &config.influxdb_token,
+            ),
+            ttl: Duration::from_millis(config.cache_ttl_ms),
+            cache: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Writes points to the hab bucket
+    pub async fn write(&self, points: Vec<DataPoint>) -> Result<(), ApiError> {
+        self.client.write("hab", stream::iter(points)).await?;
+        Ok(())
+    }
+
+    /// Runs a flux query, decoding each row into `T`
+    pub async fn query<T>(&self, flux: String) -> Result<Arc<Vec<T>>, ApiError>
+    where
+        T: FromMap + Send + Sync + 'static,
+    {
+        let q = influxdb2::models::Query::new(flux.clone());
+        self.cached(flux, || self.client.query::<T>(Some(q))).await
+    }
+
+    /// Runs a flux query, returning the records without grouping fields into rows
+    pub async fn query_raw(&self, flux: String) -> Result<Arc<Vec<FluxRecord>>, ApiError> {
+        let q = influxdb2::models::Query::new(flux.clone());
+        self.cached(flux, || self.client.query_raw(Some(q))).await
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.cache.lock().unwrap().len(),
+            ttl_ms: self.ttl.as_millis() as u64,
+        }
+    }
+
+    async fn cached<T, F, Fut>(&self, key: String, fetch: F) -> Result<Arc<T>, ApiError>
+    where
+        T: Send + Sync + 'static,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, RequestError>>,
+    {
+        if let Some(value) = self.lookup::<T>(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let value = Arc::new(
+            tokio::time::timeout(QUERY_TIMEOUT, fetch())
+                .await
+                .map_err(|_| ApiError::Timeout)??,
+        );
+
+        if !self.ttl.is_zero() {
+            let mut cache = self.cache.lock().unwrap();
+            let ttl = self.ttl;
+            cache.retain(|_, (inserted, _)| inserted.elapsed() < ttl);
+            cache.insert(key, (Instant::now(), value.clone()));
+        }
+
+        Ok(value)
+    }
+
+    fn lookup<T: Send + Sync + 'static>(&self, key: &str) -> Option<Arc<T>> {
+        let cache = self.cache.lock().unwrap();
+        let (inserted, value) = cache.get(key)?;
+
+        if inserted.elapsed() < self.ttl {
+            value.clone().downcast::<T>().ok()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Db;
+    use crate::config::Config;
+
+    fn db(cache_ttl_ms: u64) -> Db {
+        Db::new(&Config {
+            bind_address: "127.0.0.1:0".to_string(),
+            influxdb_url: "http://localhost:8086".to_string(),
+            influxdb_org: "hab".to_string(),
+            influxdb_token: String::new(),
+            cache_ttl_ms,
+            api_tokens: Vec::new(),
+            alerts: Default::default(),
+            soc: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn cached_results_are_reused() {
+        let db = db(60_000);
+
+        let first = db.cached("q".to_string(), || async { Ok(1) }).await.unwrap();
+        let second = db.cached("q".to_string(), || async { Ok(2) }).await.unwrap();
+        let other = db.cached("r".to_string(), || async { Ok(3) }).await.unwrap();
+
+        assert_eq!((1, 1, 3), (*first, *second, *other));
+
+        let stats = db.stats();
+        assert_eq!((1, 2, 2), (stats.hits, stats.misses, stats.entries));
+    }
+
+    #[tokio::test]
+    async fn zero_ttl_disables_cache() {
+        let db = db(0);
+
+        db.cached("q".to_string(), || async { Ok(1) }).await.unwrap();
+        let second = db.cached("q".to_string(), || async { Ok(2) }).await.unwrap();
+
+        assert_eq!(2, *second);
+        assert_eq!(0, db.stats().entries);
+    }
+}
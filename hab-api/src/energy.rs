@@ -0,0 +1,351 @@
+//! Energy accounting: power series integrated into per-period Wh totals
+
+use crate::db::Db;
+use crate::error::{ApiError, DeviceErrors, Partial};
+use crate::measurement::{query_minute_means, MPPT_DEVICES};
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Query, State};
+use axum::response::Json;
+use chrono::{DateTime, Datelike, Duration, FixedOffset, TimeZone, Utc};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+
+/// Longest run of missing minutes that is filled by interpolating between its neighbours
+const MAX_FILL_MINUTES: i64 = 5;
+
+/// Power fields integrated for each multiplus measurement
+const DC_FIELDS: [&str; 2] = ["inverter_watts", "charger_watts"];
+const AC_FIELDS: [&str; 1] = ["mains_watts"];
+const MPPT_FIELDS: [&str; 1] = ["panel_power"];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    #[default]
+    Day,
+    Week,
+}
+
+impl Period {
+    /// Largest number of periods that may be requested at once
+    fn max_count(&self) -> u32 {
+        match self {
+            Period::Day => 31,
+            Period::Week => 12,
+        }
+    }
+
+    fn duration(&self) -> Duration {
+        match self {
+            Period::Day => Duration::days(1),
+            Period::Week => Duration::weeks(1),
+        }
+    }
+
+    /// Start of the period containing `time`, in the timezone of `time`
+    fn start_of(&self, time: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        let date = match self {
+            Period::Day => time.date_naive(),
+            Period::Week => {
+                time.date_naive() - Duration::days(time.weekday().num_days_from_monday() as i64)
+            }
+        };
+
+        time.timezone()
+            .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+            .unwrap()
+    }
+
+    /// The `count` most recent periods as (start, end), oldest first, the last ending at `now`
+    pub fn ranges(
+        &self,
+        now: DateTime<FixedOffset>,
+        count: u32,
+    ) -> Vec<(DateTime<FixedOffset>, DateTime<FixedOffset>)> {
+        let current = self.start_of(now);
+
+        (0..count as i32)
+            .rev()
+            .map(|n| {
+                let start = current - self.duration() * n;
+                let end = (start + self.duration()).min(now);
+                (start, end)
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EnergyParams {
+    /// Length of each accounting period
+    period: Option<Period>,
+
+    /// Number of periods to report, ending with the current one
+    count: Option<u32>,
+
+    /// Offset of local time from UTC in hours, used for period boundaries
+    utc_offset: Option<i32>,
+}
+
+/// Energy integrated over one period
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub struct PeriodEnergy {
+    pub start: DateTime<FixedOffset>,
+
+    /// Energy over the measured and filled minutes (Wh)
+    pub wh: f64,
+
+    /// Minutes with data
+    pub measured_minutes: i64,
+
+    /// Minutes without data, interpolated from neighbouring minutes
+    pub filled_minutes: i64,
+
+    /// Minutes without data that were too far from any measurement to fill
+    pub missing_minutes: i64,
+}
+
+/// Integrates per-minute mean power (W) into energy (Wh) for each period
+///
+/// Gaps of up to `MAX_FILL_MINUTES` are filled by linear interpolation, longer gaps contribute no
+/// energy and are reported as missing.
+pub fn integrate(
+    samples: &[(DateTime<FixedOffset>, f64)],
+    ranges: &[(DateTime<FixedOffset>, DateTime<FixedOffset>)],
+) -> Vec<PeriodEnergy> {
+    let mut periods: Vec<PeriodEnergy> = ranges
+        .iter()
+        .map(|(start, _)| PeriodEnergy {
+            start: *start,
+            wh: 0.0,
+            measured_minutes: 0,
+            filled_minutes: 0,
+            missing_minutes: 0,
+        })
+        .collect();
+
+    let mut accumulate = |time: DateTime<FixedOffset>, watts: f64, filled: bool| {
+        if let Some(index) = ranges.iter().position(|(start, end)| *start <= time && time < *end) {
+            let period = &mut periods[index];
+            period.wh += watts / 60.0;
+            if filled {
+                period.filled_minutes += 1;
+            } else {
+                period.measured_minutes += 1;
+            }
+        }
+    };
+
+    for (index, (time, watts)) in samples.iter().enumerate() {
+        accumulate(*time, *watts, false);
+
+        if let Some((next_time, next_watts)) = samples.get(index + 1) {
+            let gap = (*next_time - *time).num_minutes() - 1;
+            if gap > 0 && gap <= MAX_FILL_MINUTES {
+                for minute in 1..=gap {
+                    let fraction = minute as f64 / (gap + 1) as f64;
+                    let interpolated = watts + (next_watts - watts) * fraction;
+                    accumulate(*time + Duration::minutes(minute), interpolated, true);
+                }
+            }
+        }
+    }
+
+    for (period, (start, end)) in periods.iter_mut().zip(ranges) {
+        let total = (*end - *start).num_minutes();
+        period.missing_minutes = (total - period.measured_minutes - period.filled_minutes).max(0);
+    }
+
+    periods
+}
+
+/// Energy of one power field in each period, oldest first
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FieldEnergy(pub Vec<PeriodEnergy>);
+
+/// Energy per device and power field, per period
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EnergyReport {
+    pub period: Period,
+    pub utc_offset: i32,
+
+    /// Energy keyed by device, then by power field
+    pub devices: BTreeMap<String, BTreeMap<String, FieldEnergy>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<DeviceErrors>,
+}
+
+/// Offset of the given whole hours from UTC, if less than a day
+fn offset_of_hours(hours: i32) -> Option<FixedOffset> {
+    hours.checked_mul(3600).and_then(FixedOffset::east_opt)
+}
+
+/// Energy from integrating the power fields of each device over recent periods
+#[utoipa::path(
+    get,
+    path = "/v1/energy",
+    tag = "v1",
+    params(EnergyParams),
+    responses(
+        (status = 200, description = "Energy of devices that could be queried", body = EnergyReport),
+        (status = 400, description = "Invalid parameters", body = ErrorBody),
+        (status = 502, description = "No device could be queried", body = ErrorBody),
+        (status = 504, description = "No device could be queried in time", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn v1_energy(
+    State(db): State<Arc<Db>>,
+    params: Result<Query<EnergyParams>, QueryRejection>,
+) -> Result<Json<Value>, ApiError> {
+    let Query(params) = params.map_err(|e| ApiError::BadRequest(e.body_text()))?;
+
+    let period = params.period.unwrap_or_default();
+    let count = params.count.unwrap_or(7);
+    if !(1..=period.max_count()).contains(&count) {
+        return Err(ApiError::BadRequest(format!(
+            "count must be between 1 and {} for this period, got {}",
+            period.max_count(),
+            count
+        )));
+    }
+
+    let utc_offset = params.utc_offset.unwrap_or(0);
+    let offset = offset_of_hours(utc_offset).ok_or_else(|| {
+        ApiError::BadRequest(format!("utc_offset out of range: {}", utc_offset))
+    })?;
+
+    let now = Utc::now().with_timezone(&offset);
+    let ranges = period.ranges(now, count);
+    let (start, stop) = (ranges[0].0, now);
+
+    let mut sources: Vec<(String, &str, &[&str])> = MPPT_DEVICES
+        .iter()
+        .map(|(name, measurement)| (format!("mppt.{}", name), *measurement, &MPPT_FIELDS[..]))
+        .collect();
+    sources.push(("inverter.primary.dc".to_string(), "dc", &DC_FIELDS));
+    sources.push(("inverter.primary.ac".to_string(), "ac", &AC_FIELDS));
+
+    let results = join_all(
+        sources
+            .iter()
+            .map(|(_, measurement, fields)| query_minute_means(&db, measurement, fields, start, stop)),
+    )
+    .await;
+
+    let mut partial = Partial::default();
+    let mut devices = BTreeMap::new();
+    for ((key, _, fields), result) in sources.into_iter().zip(results) {
+        if let Some(series) = partial.take(&key, result.map(Some)) {
+            let energy = fields
+                .iter()
+                .map(|field| {
+                    let samples = series.get(*field).map(Vec::as_slice).unwrap_or_default();
+                    (field.to_string(), FieldEnergy(integrate(samples, &ranges)))
+                })
+                .collect();
+            devices.insert(key, energy);
+        }
+    }
+
+    partial.finish(EnergyReport {
+        period,
+        utc_offset,
+        devices,
+        errors: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{integrate, offset_of_hours, Period};
+    use chrono::{DateTime, Duration, FixedOffset};
+
+    fn time(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    #[test]
+    fn ranges_end_now() {
+        let now = time("2023-08-02T15:30:00-06:00");
+
+        let days = Period::Day.ranges(now, 2);
+        assert_eq!(
+            vec![
+                (time("2023-08-01T00:00:00-06:00"), time("2023-08-02T00:00:00-06:00")),
+                (time("2023-08-02T00:00:00-06:00"), now),
+            ],
+            days
+        );
+
+        // 2023-08-02 is a Wednesday
+        let weeks = Period::Week.ranges(now, 1);
+        assert_eq!(vec![(time("2023-07-31T00:00:00-06:00"), now)], weeks);
+    }
+
+    #[test]
+    fn offset_within_a_day() {
+        assert_eq!(FixedOffset::west_opt(6 * 3600), offset_of_hours(-6));
+        assert_eq!(FixedOffset::east_opt(23 * 3600), offset_of_hours(23));
+        assert_eq!(None, offset_of_hours(24));
+        assert_eq!(None, offset_of_hours(1_000_000));
+        assert_eq!(None, offset_of_hours(i32::MIN));
+    }
+
+    #[test]
+    fn integrate_constant_power() {
+        let start = time("2023-08-01T00:00:00Z");
+        let ranges = [(start, start + Duration::hours(2))];
+        let samples: Vec<_> = (0..120)
+            .map(|m| (start + Duration::minutes(m), 60.0))
+            .collect();
+
+        let energy = integrate(&samples, &ranges);
+
+        assert_eq!(120.0, energy[0].wh);
+        assert_eq!(120, energy[0].measured_minutes);
+        assert_eq!(0, energy[0].missing_minutes);
+    }
+
+    #[test]
+    fn integrate_fills_short_gaps_only() {
+        let start = time("2023-08-01T00:00:00Z");
+        let ranges = [(start, start + Duration::hours(1))];
+        let samples = [
+            (start, 60.0),
+            // 3 minute gap is interpolated
+            (start + Duration::minutes(4), 120.0),
+            // 30 minute gap is missing
+            (start + Duration::minutes(35), 60.0),
+        ];
+
+        let energy = integrate(&samples, &ranges);
+
+        assert_eq!(3, energy[0].measured_minutes);
+        assert_eq!(3, energy[0].filled_minutes);
+        assert_eq!(54, energy[0].missing_minutes);
+        assert_eq!((60.0 + 75.0 + 90.0 + 105.0 + 120.0 + 60.0) / 60.0, energy[0].wh);
+    }
+
+    #[test]
+    fn integrate_splits_periods() {
+        let start = time("2023-08-01T23:58:00Z");
+        let midnight = time("2023-08-02T00:00:00Z");
+        let ranges = [(start, midnight), (midnight, midnight + Duration::days(1))];
+        let samples: Vec<_> = (0..4)
+            .map(|m| (start + Duration::minutes(m), 30.0))
+            .collect();
+
+        let energy = integrate(&samples, &ranges);
+
+        assert_eq!(1.0, energy[0].wh);
+        assert_eq!(1.0, energy[1].wh);
+        assert_eq!(1438, energy[1].missing_minutes);
+    }
+}
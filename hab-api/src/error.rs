@@ -0,0 +1,178 @@
+//! API error responses
+
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use utoipa::ToSchema;
+
+/// Errors surfaced to API clients as a status code and JSON body
+#[derive(Debug)]
+pub enum ApiError {
+    /// InfluxDB could not be reached or rejected the query
+    UpstreamUnavailable(String),
+
+    /// InfluxDB did not answer within the query timeout
+    Timeout,
+
+    /// Request parameters were missing or invalid
+    BadRequest(String),
+
+    /// Request did not carry a valid API token
+    Unauthorized,
+
+    /// API token is valid but lacks the scope needed for the request
+    Forbidden(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::UpstreamUnavailable(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    /// Short machine-readable error identifier
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApiError::UpstreamUnavailable(_) => "upstream_unavailable",
+            ApiError::Timeout => "timeout",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+        }
+    }
+
+    /// JSON body describing the error
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            error: self.kind().to_string(),
+            message: self.to_string(),
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::UpstreamUnavailable(msg) => write!(f, "influxdb unavailable: {}", msg),
+            ApiError::Timeout => write!(f, "influxdb query timed out"),
+            ApiError::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            ApiError::Unauthorized => write!(f, "missing or invalid api token"),
+            ApiError::Forbidden(msg) => write!(f, "forbidden: {}", msg),
+        }
+    }
+}
+
+impl From<influxdb2::RequestError> for ApiError {
+    fn from(err: influxdb2::RequestError) -> Self {
+        match err {
+            influxdb2::RequestError::ReqwestProcessing { source } if source.is_timeout() => {
+                ApiError::Timeout
+            }
+            err => ApiError::UpstreamUnavailable(err.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        log::warn!("{}", self);
+        let mut response = (self.status(), Json(self.body())).into_response();
+        if let ApiError::Unauthorized = self {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+/// JSON body of an error response
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// Machine-readable error identifier, e.g. "timeout"
+    pub error: String,
+
+    /// Human-readable description
+    pub message: String,
+}
+
+/// Errors of the devices that could not be queried, keyed by device
+#[derive(Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct DeviceErrors(pub BTreeMap<String, ErrorBody>);
+
+/// Collects the results of independent queries so that one failure doesn't discard the rest
+#[derive(Debug, Default)]
+pub struct Partial {
+    attempted: usize,
+    errors: Vec<(String, ApiError)>,
+}
+
+impl Partial {
+    /// Unwraps a query result, recording the error under `key` on failure
+    pub fn take<T>(&mut self, key: &str, result: Result<Option<T>, ApiError>) -> Option<T> {
+        self.attempted += 1;
+        result.unwrap_or_else(|err| {
+            log::warn!("{}: {}", key, err);
+            self.errors.push((key.to_string(), err));
+            None
+        })
+    }
+
+    /// Completes the response, failing outright only if every query failed
+    ///
+    /// Errors of the failed queries are reported in the `errors` field of `body`.
+    pub fn finish(self, body: impl Serialize) -> Result<Json<Value>, ApiError> {
+        if self.attempted > 0 && self.errors.len() == self.attempted {
+            return Err(self.errors.into_iter().next().unwrap().1);
+        }
+
+        let mut body = serde_json::to_value(body).unwrap_or_default();
+        if !self.errors.is_empty() {
+            let errors: Map<String, Value> = self
+                .errors
+                .iter()
+                .map(|(key, err)| (key.clone(), json!(err.body())))
+                .collect();
+            body["errors"] = Value::Object(errors);
+        }
+
+        Ok(Json(body))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ApiError, Partial};
+    use serde_json::json;
+
+    #[test]
+    fn partial_reports_failed_devices() {
+        let mut partial = Partial::default();
+        let ok = partial.take("a", Ok(Some(1)));
+        let failed: Option<i32> = partial.take("b", Err(ApiError::Timeout));
+        assert_eq!(Some(1), ok);
+        assert_eq!(None, failed);
+
+        let body = partial.finish(json!({ "a": ok, "b": failed })).unwrap().0;
+        assert_eq!("timeout", body["errors"]["b"]["error"]);
+        assert!(body["errors"].get("a").is_none());
+    }
+
+    #[test]
+    fn partial_fails_when_all_fail() {
+        let mut partial = Partial::default();
+        let _: Option<i32> = partial.take("a", Err(ApiError::Timeout));
+        let _: Option<i32> = partial.take("b", Err(ApiError::Timeout));
+
+        let err = partial.finish(json!({})).unwrap_err();
+        assert_eq!(504, err.status().as_u16());
+    }
+}
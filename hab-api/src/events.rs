@@ -0,0 +1,192 @@
+//! State changes recorded by the collectors
+//!
+//! The collectors write a point to the "event" measurement whenever a state field such as the
+//! charge state, error code or AC state changes, tagged with the measurement of the device and the
+//! field that changed.
+
+use crate::db::Db;
+use crate::error::ApiError;
+use crate::measurement::device_measurements;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Query, State};
+use axum::response::Json;
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use influxdb2::api::query::FluxRecord;
+use influxdb2_structmap::value::Value;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+
+/// Longest time range that may be requested, in days
+const MAX_RANGE_DAYS: i64 = 31;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventsParams {
+    /// Start of the range, RFC 3339, defaults to one day before `stop`
+    #[param(value_type = Option<String>)]
+    start: Option<DateTime<FixedOffset>>,
+
+    /// End of the range, RFC 3339, defaults to now
+    #[param(value_type = Option<String>)]
+    stop: Option<DateTime<FixedOffset>>,
+
+    /// Only report events of this device, e.g. "mppt.lil"
+    device: Option<String>,
+}
+
+/// A state field of a device changed value
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct Event {
+    pub time: DateTime<FixedOffset>,
+    pub device: String,
+
+    /// Field that changed, e.g. "state" or "error"
+    pub field: String,
+    pub old: String,
+    pub new: String,
+
+    /// Time spent in the old state, in seconds, counted from collector startup if the old state
+    /// was the first one seen
+    pub duration_secs: Option<f64>,
+}
+
+/// State changes over a time range, oldest first
+#[utoipa::path(
+    get,
+    path = "/v1/events",
+    tag = "v1",
+    params(EventsParams),
+    responses(
+        (status = 200, description = "Events in the range", body = [Event]),
+        (status = 400, description = "Invalid range or device", body = ErrorBody),
+        (status = 502, description = "InfluxDB could not be queried", body = ErrorBody),
+        (status = 504, description = "InfluxDB did not answer in time", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn v1_events(
+    State(db): State<Arc<Db>>,
+    params: Result<Query<EventsParams>, QueryRejection>,
+) -> Result<Json<Vec<Event>>, ApiError> {
+    let Query(params) = params.map_err(|e| ApiError::BadRequest(e.body_text()))?;
+
+    let stop = params.stop.unwrap_or_else(|| Utc::now().into());
+    let start = params.start.unwrap_or(stop - Duration::days(1));
+    if start >= stop || stop - start > Duration::days(MAX_RANGE_DAYS) {
+        return Err(ApiError::BadRequest(format!(
+            "start must be before stop and the range at most {} days",
+            MAX_RANGE_DAYS
+        )));
+    }
+
+    // events are tagged with the measurement name of the device
+    let devices: HashMap<&str, String> = device_measurements()
+        .into_iter()
+        .map(|(name, measurement)| (measurement, name))
+        .collect();
+
+    let device_filter = match &params.device {
+        None => String::new(),
+        Some(device) => {
+            let measurement = devices
+                .iter()
+                .find(|(_, name)| *name == device)
+                .map(|(measurement, _)| *measurement)
+                .ok_or_else(|| ApiError::BadRequest(format!("unknown device \"{}\"", device)))?;
+            format!(r#"|> filter(fn: (r) => r.device == "{}")"#, measurement)
+        }
+    };
+
+    let flux = format!(r#"
+        from(bucket: "hab")
+        |> range(start: {}, stop: {})
+        |> filter(fn: (r) => r._measurement == "event")
+        {}
+        |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")
+    "#, start.to_rfc3339(), stop.to_rfc3339(), device_filter);
+
+    let records = db.query_raw(flux).await?;
+
+    let mut events: Vec<Event> = records
+        .iter()
+        .filter_map(|record| {
+            let event = parse_event(record, &devices);
+            if event.is_none() {
+                log::debug!("events: skipping record {:?}", record.values);
+            }
+            event
+        })
+        .collect();
+    events.sort_by_key(|event| event.time);
+
+    Ok(Json(events))
+}
+
+/// Converts a pivoted record of the event measurement
+fn parse_event(record: &FluxRecord, devices: &HashMap<&str, String>) -> Option<Event> {
+    let string = |key: &str| match record.values.get(key) {
+        Some(Value::String(value)) => Some(value.clone()),
+        _ => None,
+    };
+
+    let time = match record.values.get("_time") {
+        Some(Value::TimeRFC(time)) => *time,
+        _ => return None,
+    };
+    let measurement = string("device")?;
+
+    Some(Event {
+        time,
+        device: devices.get(measurement.as_str()).cloned().unwrap_or(measurement),
+        field: string("field")?,
+        old: string("old")?,
+        new: string("new")?,
+        duration_secs: match record.values.get("duration_secs") {
+            Some(Value::Double(secs)) => Some(secs.into_inner()),
+            _ => None,
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_event;
+    use chrono::DateTime;
+    use influxdb2::api::query::FluxRecord;
+    use influxdb2_structmap::value::Value;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn parse_pivoted_record() {
+        let time = DateTime::parse_from_rfc3339("2023-08-01T12:00:00Z").unwrap();
+        let values: BTreeMap<String, Value> = [
+            ("_time", Value::TimeRFC(time)),
+            ("device", Value::String("mppt_lil".to_string())),
+            ("field", Value::String("state".to_string())),
+            ("old", Value::String("Bulk".to_string())),
+            ("new", Value::String("Absorption".to_string())),
+            ("duration_secs", Value::Double(5400.0.into())),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+        let record = FluxRecord {
+            table: 0,
+            values,
+        };
+        let devices = HashMap::from([("mppt_lil", "mppt.lil".to_string())]);
+
+        let event = parse_event(&record, &devices).unwrap();
+        assert_eq!("mppt.lil", event.device);
+        assert_eq!("state", event.field);
+        assert_eq!("Bulk", event.old);
+        assert_eq!("Absorption", event.new);
+        assert_eq!(Some(5400.0), event.duration_secs);
+
+        let mut incomplete = record.clone();
+        incomplete.values.remove("new");
+        assert_eq!(None, parse_event(&incomplete, &devices));
+    }
+}
@@ -0,0 +1,259 @@
+//! Live stream of device updates over server-sent events
+//!
+//! A single background task polls InfluxDB for the latest point of every device while at least
+//! one client is subscribed, and broadcasts a device's fields whenever a newer point appears.
+//! Clients therefore share one query per poll regardless of how many are connected.
+
+use crate::db::Db;
+use crate::error::ApiError;
+use crate::measurement::{device_measurements, query_latest_fields};
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Query, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+/// Interval between polls of InfluxDB while clients are subscribed
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Age, in seconds, beyond which a point is not published
+const MAX_AGE: u64 = 60;
+
+/// Interval between keep-alive comments sent to idle clients
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Updates buffered per client before a slow client starts missing updates
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Latest point of a single device
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct DeviceUpdate {
+    pub device: String,
+    pub time: DateTime<FixedOffset>,
+
+    /// Field values keyed by field name
+    #[schema(value_type = Object)]
+    pub fields: Map<String, Value>,
+}
+
+impl DeviceUpdate {
+    /// Copy of this update restricted to `fields`, if given
+    fn filtered(&self, fields: Option<&HashSet<String>>) -> DeviceUpdate {
+        match fields {
+            None => self.clone(),
+            Some(fields) => DeviceUpdate {
+                device: self.device.clone(),
+                time: self.time,
+                fields: self
+                    .fields
+                    .iter()
+                    .filter(|(name, _)| fields.contains(*name))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect(),
+            },
+        }
+    }
+}
+
+pub struct Live {
+    sender: broadcast::Sender<Arc<DeviceUpdate>>,
+    latest: Mutex<HashMap<String, Arc<DeviceUpdate>>>,
+}
+
+impl Live {
+    /// Starts polling for updates on behalf of subscribed clients
+    pub fn spawn(db: Arc<Db>) -> Arc<Live> {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let live = Arc::new(Live {
+            sender,
+            latest: Mutex::default(),
+        });
+
+        tokio::spawn(live.clone().run(db));
+
+        live
+    }
+
+    async fn run(self: Arc<Self>, db: Arc<Db>) {
+        let devices = device_measurements();
+        let measurements: Vec<&str> = devices.iter().map(|(_, measurement)| *measurement).collect();
+
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            if self.sender.receiver_count() == 0 {
+                continue;
+            }
+
+            match query_latest_fields(&db, &measurements, MAX_AGE).await {
+                Ok(latest) => {
+                    for (device, measurement) in devices.iter() {
+                        if let Some((time, fields)) = latest.get(*measurement) {
+                            self.publish(DeviceUpdate {
+                                device: device.clone(),
+                                time: *time,
+                                fields: fields.clone(),
+                            });
+                        }
+                    }
+                }
+                Err(err) => {
+                    log::warn!("live: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Broadcasts an update if it is newer than the last one seen for the device
+    fn publish(&self, update: DeviceUpdate) {
+        let mut latest = self.latest.lock().unwrap();
+        let is_newer = match latest.get(&update.device) {
+            Some(previous) => update.time > previous.time,
+            None => true,
+        };
+
+        if is_newer {
+            let update = Arc::new(update);
+            latest.insert(update.device.clone(), update.clone());
+            // only fails if every client has disconnected since the poll
+            let _ = self.sender.send(update);
+        }
+    }
+
+    /// Stream of updates, starting with the most recent update of every device
+    fn subscribe(&self) -> impl Stream<Item = Arc<DeviceUpdate>> {
+        let receiver = self.sender.subscribe();
+        let snapshot: Vec<Arc<DeviceUpdate>> = self.latest.lock().unwrap().values().cloned().collect();
+
+        tokio_stream::iter(snapshot).chain(BroadcastStream::new(receiver).filter_map(|update| {
+            update
+                .map_err(|err| log::debug!("live: client lagging: {}", err))
+                .ok()
+        }))
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LiveParams {
+    /// Comma separated device names to receive, e.g. "mppt.lil,inverter.primary.dc"
+    devices: Option<String>,
+
+    /// Comma separated field names to receive, e.g. "battery_voltage,panel_power"
+    fields: Option<String>,
+}
+
+fn split_list(list: Option<String>) -> Option<HashSet<String>> {
+    list.map(|list| {
+        list.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect()
+    })
+}
+
+/// Stream of `update` events carrying a `DeviceUpdate` whenever a device writes a new point
+///
+/// The most recent update of each device is sent on connect.
+#[utoipa::path(
+    get,
+    path = "/v1/live",
+    tag = "v1",
+    params(LiveParams),
+    responses(
+        (status = 200, description = "Server-sent event stream", body = DeviceUpdate, content_type = "text/event-stream"),
+        (status = 400, description = "Invalid parameters", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn v1_live(
+    State(live): State<Arc<Live>>,
+    params: Result<Query<LiveParams>, QueryRejection>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let Query(params) = params.map_err(|e| ApiError::BadRequest(e.body_text()))?;
+    let devices = split_list(params.devices);
+    let fields = split_list(params.fields);
+
+    if let Some(devices) = &devices {
+        let known: HashSet<String> = device_measurements().into_iter().map(|(name, _)| name).collect();
+        if let Some(unknown) = devices.iter().find(|device| !known.contains(*device)) {
+            return Err(ApiError::BadRequest(format!("unknown device: {}", unknown)));
+        }
+    }
+
+    let stream = live
+        .subscribe()
+        .filter(move |update| match &devices {
+            Some(devices) => devices.contains(&update.device),
+            None => true,
+        })
+        .map(move |update| {
+            let event = Event::default()
+                .event("update")
+                .json_data(update.filtered(fields.as_ref()))
+                .unwrap_or_else(|err| Event::default().event("error").data(err.to_string()));
+            Ok(event)
+        });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(HEARTBEAT_INTERVAL)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_list, DeviceUpdate, Live};
+    use chrono::DateTime;
+    use serde_json::{json, Map, Value};
+    use std::sync::Mutex;
+    use tokio::sync::broadcast;
+
+    fn update(time: &str, fields: Value) -> DeviceUpdate {
+        DeviceUpdate {
+            device: "mppt.lil".to_string(),
+            time: DateTime::parse_from_rfc3339(time).unwrap(),
+            fields: fields.as_object().cloned().unwrap_or_else(Map::new),
+        }
+    }
+
+    #[test]
+    fn publish_only_newer_points() {
+        let (sender, mut receiver) = broadcast::channel(4);
+        let live = Live {
+            sender,
+            latest: Mutex::default(),
+        };
+
+        live.publish(update("2023-08-01T00:00:01Z", json!({ "panel_power": 10.0 })));
+        live.publish(update("2023-08-01T00:00:01Z", json!({ "panel_power": 10.0 })));
+        live.publish(update("2023-08-01T00:00:02Z", json!({ "panel_power": 20.0 })));
+
+        assert_eq!(json!(10.0), receiver.try_recv().unwrap().fields["panel_power"]);
+        assert_eq!(json!(20.0), receiver.try_recv().unwrap().fields["panel_power"]);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn filter_fields() {
+        let update = update(
+            "2023-08-01T00:00:01Z",
+            json!({ "panel_power": 10.0, "battery_voltage": 13.2 }),
+        );
+
+        let fields = split_list(Some("battery_voltage, ,state".to_string()));
+        let filtered = update.filtered(fields.as_ref());
+
+        assert_eq!(1, filtered.fields.len());
+        assert_eq!(json!(13.2), filtered.fields["battery_voltage"]);
+        assert_eq!(2, update.filtered(None).fields.len());
+    }
+}
@@ -1,14 +1,36 @@
+mod alert;
+mod auth;
 mod config;
+mod db;
+mod energy;
+mod error;
+mod events;
+mod live;
+mod measurement;
+mod metrics;
+mod notify;
+mod openapi;
+mod soc;
+mod state;
+mod summary;
 
 use std::sync::Arc;
 
 use anyhow::Result;
-use axum::{response::Json, routing::get, Router, extract::State};
-use influxdb2::FromDataPoint;
-use influxdb2_structmap::FromMap;
+use axum::{middleware, response::Json, routing::get, Router, extract::State};
+use db::Db;
+use error::{ApiError, DeviceErrors, Partial};
+use live::Live;
+use measurement::{query_measurement, V0Ac, V0Dc, V0Mppt};
+use db::CacheStats;
 use serde::Serialize;
-use serde_json::{json, Value};
+use serde_json::Value;
+use state::AppState;
 use tokio::runtime::Runtime;
+use utoipa::ToSchema;
+
+/// Age, in seconds, beyond which a device is considered not reporting
+const MAX_AGE: u64 = 60;
 
 fn main() -> Result<()> {
     let rt = Runtime::new()?;
@@ -17,10 +39,30 @@ fn main() -> Result<()> {
 
         let config = Arc::new(config::Config::load()?);
         let bind_address = config.bind_address.to_owned();
+        if config.api_tokens.is_empty() {
+            log::warn!("no api tokens configured, all requests will be rejected");
+        }
+
+        let db = Arc::new(Db::new(&config));
+        alert::spawn(db.clone(), &config.alerts)?;
+        soc::spawn(db.clone(), &config.soc)?;
+        let state = AppState {
+            live: Live::spawn(db.clone()),
+            config,
+            db,
+        };
 
         let app = Router::new()
             .route("/", get(v0_data))
-            .with_state(config);
+            .route("/v1/summary", get(summary::v1_summary))
+            .route("/v1/energy", get(energy::v1_energy))
+            .route("/v1/events", get(events::v1_events))
+            .route("/v1/live", get(live::v1_live))
+            .route("/v1/stats", get(v1_stats))
+            .route("/metrics", get(metrics::metrics))
+            .route_layer(middleware::from_fn_with_state(state.clone(), auth::authenticate))
+            .route("/openapi.json", get(openapi::openapi_json))
+            .with_state(state);
 
         axum::Server::bind(&bind_address.parse()?)
             .serve(app.into_make_service())
@@ -31,81 +73,105 @@ fn main() -> Result<()> {
     })
 }
 
-#[derive(Clone, Default, FromDataPoint, Serialize, Debug)]
-struct V0Mppt {
-    battery_current: f64,
-    battery_voltage: f64,
-    error: String,
-    maximum_power_today: f64,
-    panel_power: f64,
-    panel_voltage: f64,
-    state: String,
-    yield_today: f64,
-    yield_total: f64,
+#[derive(Serialize, ToSchema)]
+struct V0Data {
+    mppt: V0MpptDevices,
+    inverter: V0Inverters,
+    imu: V0Imus,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<DeviceErrors>,
 }
 
-#[derive(Clone, Default, FromDataPoint, Serialize, Debug)]
-struct V0Dc {
-    charger_current: f64,
-    charger_watts: f64,
-    inverter_current: f64,
-    inverter_frequency: f64,
-    inverter_watts: f64,
-    voltage: f64,
+/// Latest point of each MPPT controller, null if it has not reported recently
+#[derive(Serialize, ToSchema)]
+struct V0MpptDevices {
+    lil: Option<V0Mppt>,
+    big: Option<V0Mppt>,
+    ext: Option<V0Mppt>,
 }
 
-#[derive(Clone, Default, FromDataPoint, Serialize, Debug)]
-struct V0Ac {
-    bf_factor: f64,
-    inverter_current: f64,
-    inverter_factor: f64,
-    inverter_voltage: f64,
-    inverter_watts: f64,
-    mains_current: f64,
-    mains_frequency: f64,
-    mains_voltage: f64,
-    mains_watts: f64,
-    state: String,
+#[derive(Serialize, ToSchema)]
+struct V0Inverters {
+    primary: V0Inverter,
 }
 
-async fn query_measurement<T: FromMap + Clone>(db: &influxdb2::Client, name: &str) -> Option<T> {
-    let q = influxdb2::models::Query::new(format!(r#"
-        from(bucket: "hab")
-        |> range(start: -60s)
-        |> filter(fn: (r) => r._measurement == "{}")
-        |> last()
-    "#, name));
+/// Latest points of a Multiplus, null if it has not reported recently
+#[derive(Serialize, ToSchema)]
+struct V0Inverter {
+    ac: Option<V0Ac>,
+    dc: Option<V0Dc>,
+}
 
-    db.query(Some(q)).await.map_or_else(|e| panic!("{}", e.to_string()), |r| r.get(0).cloned())
+/// IMUs are not yet reported and are always null
+#[derive(Serialize, ToSchema)]
+struct V0Imus {
+    #[schema(value_type = Option<Object>)]
+    primary: Option<Value>,
 }
 
+/// Latest point of every device
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "v0",
+    responses(
+        (status = 200, description = "Latest points of devices that could be queried", body = V0Data),
+        (status = 502, description = "No device could be queried", body = ErrorBody),
+        (status = 504, description = "No device could be queried in time", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
 async fn v0_data(
-    State(config): State<Arc<config::Config>>,
-) -> Json<Value> {
+    State(db): State<Arc<Db>>,
+) -> Result<Json<Value>, ApiError> {
     // All of this is hardcoded for expediency
 
-    let db = influxdb2::Client::new(&config.influxdb_url, &config.influxdb_org, &config.influxdb_token);
-
-    let mppt_lil: Option<V0Mppt> = query_measurement(&db, "mppt_lil").await;
-    let mppt_big: Option<V0Mppt> = query_measurement(&db, "mppt_big").await;
-    let mppt_ext: Option<V0Mppt> = query_measurement(&db, "mppt_ext").await;
-    let inverter_dc: Option<V0Dc> = query_measurement(&db, "dc").await;
-    let inverter_ac: Option<V0Ac> = query_measurement(&db, "ac").await;
-
-    Json(json!({
-        "mppt": {
-            "lil": mppt_lil,
-            "big": mppt_big,
-            "ext": mppt_ext,
+    let (mppt_lil, mppt_big, mppt_ext, inverter_dc, inverter_ac) = tokio::join!(
+        query_measurement::<V0Mppt>(&db, "mppt_lil", MAX_AGE),
+        query_measurement::<V0Mppt>(&db, "mppt_big", MAX_AGE),
+        query_measurement::<V0Mppt>(&db, "mppt_ext", MAX_AGE),
+        query_measurement::<V0Dc>(&db, "dc", MAX_AGE),
+        query_measurement::<V0Ac>(&db, "ac", MAX_AGE),
+    );
+
+    let mut partial = Partial::default();
+    let mppt_lil = partial.take("mppt.lil", mppt_lil);
+    let mppt_big = partial.take("mppt.big", mppt_big);
+    let mppt_ext = partial.take("mppt.ext", mppt_ext);
+    let inverter_dc = partial.take("inverter.primary.dc", inverter_dc);
+    let inverter_ac = partial.take("inverter.primary.ac", inverter_ac);
+
+    partial.finish(V0Data {
+        mppt: V0MpptDevices {
+            lil: mppt_lil,
+            big: mppt_big,
+            ext: mppt_ext,
         },
-        "inverter": {
-            "primary": {
-                "ac": inverter_ac,
-                "dc": inverter_dc,
+        inverter: V0Inverters {
+            primary: V0Inverter {
+                ac: inverter_ac,
+                dc: inverter_dc,
             },
         },
-        "imu": {
-            "primary": null,
-        }
-    }))
+        imu: V0Imus { primary: None },
+        errors: None,
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+struct Stats {
+    cache: CacheStats,
+}
+
+/// Service statistics
+#[utoipa::path(
+    get,
+    path = "/v1/stats",
+    tag = "v1",
+    responses((status = 200, description = "Query cache statistics", body = Stats)),
+    security(("bearer" = [])),
+)]
+async fn v1_stats(State(db): State<Arc<Db>>) -> Json<Stats> {
+    Json(Stats { cache: db.stats() })
 }
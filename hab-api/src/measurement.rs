@@ -0,0 +1,206 @@
+//! Measurements written by the collectors and the queries that read them back
+
+use crate::db::Db;
+use crate::error::ApiError;
+use chrono::{DateTime, FixedOffset};
+use influxdb2::FromDataPoint;
+use influxdb2_structmap::value::Value;
+use influxdb2_structmap::FromMap;
+use serde::Serialize;
+use serde_json::{Map, Number};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+/// MPPT controllers as (api name, measurement name)
+pub const MPPT_DEVICES: [(&str, &str); 3] = [
+    ("lil", "mppt_lil"),
+    ("big", "mppt_big"),
+    ("ext", "mppt_ext"),
+];
+
+/// All devices as (api name, measurement name)
+pub fn device_measurements() -> Vec<(String, &'static str)> {
+    let mut devices: Vec<(String, &'static str)> = MPPT_DEVICES
+        .iter()
+        .map(|(name, measurement)| (format!("mppt.{}", name), *measurement))
+        .collect();
+    devices.push(("inverter.primary.dc".to_string(), "dc"));
+    devices.push(("inverter.primary.ac".to_string(), "ac"));
+    devices.push(("inverter.primary.led".to_string(), "multiplus"));
+    devices.push(("battery.estimated".to_string(), "soc"));
+    devices
+}
+
+#[derive(Clone, Default, FromDataPoint, Serialize, ToSchema, Debug)]
+pub struct V0Mppt {
+    pub battery_current: f64,
+    pub battery_voltage: f64,
+    pub error: String,
+    pub maximum_power_today: f64,
+    pub panel_power: f64,
+    pub panel_voltage: f64,
+    pub state: String,
+    pub yield_today: f64,
+    pub yield_total: f64,
+}
+
+#[derive(Clone, Default, FromDataPoint, Serialize, ToSchema, Debug)]
+pub struct V0Dc {
+    pub charger_current: f64,
+    pub charger_watts: f64,
+    pub inverter_current: f64,
+    pub inverter_frequency: f64,
+    pub inverter_watts: f64,
+    pub voltage: f64,
+}
+
+#[derive(Clone, Default, FromDataPoint, Serialize, ToSchema, Debug)]
+pub struct V0Ac {
+    pub bf_factor: f64,
+    pub inverter_current: f64,
+    pub inverter_factor: f64,
+    pub inverter_voltage: f64,
+    pub inverter_watts: f64,
+    pub mains_current: f64,
+    pub mains_frequency: f64,
+    pub mains_voltage: f64,
+    pub mains_watts: f64,
+    pub state: String,
+}
+
+pub async fn query_measurement<T: FromMap + Clone + Send + Sync + 'static>(
+    db: &Db,
+    name: &str,
+    max_age: u64,
+) -> Result<Option<T>, ApiError> {
+    let flux = format!(r#"
+        from(bucket: "hab")
+        |> range(start: -{}s)
+        |> filter(fn: (r) => r._measurement == "{}")
+        |> last()
+    "#, max_age, name);
+
+    let result = db.query::<T>(flux).await?;
+
+    Ok(result.first().cloned())
+}
+
+
+/// Latest fields of a measurement and the time of the most recent of them
+pub type Latest = (DateTime<FixedOffset>, Map<String, serde_json::Value>);
+
+/// Queries the latest value of every field written within the last `max_age` seconds, keyed by
+/// measurement
+pub async fn query_latest_fields(
+    db: &Db,
+    measurements: &[&str],
+    max_age: u64,
+) -> Result<HashMap<String, Latest>, ApiError> {
+    let set = measurements
+        .iter()
+        .map(|measurement| format!(r#""{}""#, measurement))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let flux = format!(r#"
+        from(bucket: "hab")
+        |> range(start: -{}s)
+        |> filter(fn: (r) => contains(value: r._measurement, set: [{}]))
+        |> last()
+    "#, max_age, set);
+
+    let records = db.query_raw(flux).await?;
+
+    let mut latest: HashMap<String, Latest> = HashMap::new();
+    for record in records.iter() {
+        let value = match record.values.get("_value") {
+            Some(Value::Double(v)) => Number::from_f64(v.into_inner()).map(serde_json::Value::Number),
+            Some(Value::Long(v)) => Some((*v).into()),
+            Some(Value::UnsignedLong(v)) => Some((*v).into()),
+            Some(Value::Bool(v)) => Some((*v).into()),
+            Some(Value::String(v)) => Some(v.clone().into()),
+            _ => None,
+        };
+
+        match (
+            record.values.get("_measurement"),
+            record.values.get("_field"),
+            record.values.get("_time"),
+            value,
+        ) {
+            (
+                Some(Value::String(measurement)),
+                Some(Value::String(field)),
+                Some(Value::TimeRFC(time)),
+                Some(value),
+            ) => {
+                let (latest_time, fields) = latest
+                    .entry(measurement.clone())
+                    .or_insert_with(|| (*time, Map::new()));
+                *latest_time = (*latest_time).max(*time);
+                fields.insert(field.clone(), value);
+            }
+            _ => {
+                log::debug!("skipping record {:?}", record.values);
+            }
+        }
+    }
+
+    Ok(latest)
+}
+
+/// Points of a single field as (time, value), ordered by time
+pub type Series = Vec<(DateTime<FixedOffset>, f64)>;
+
+/// Queries the per-minute mean of `fields` of a measurement over a time range, keyed by field
+///
+/// Each point is timestamped at the start of its one minute window. Minutes without data are
+/// omitted rather than filled.
+pub async fn query_minute_means(
+    db: &Db,
+    name: &str,
+    fields: &[&str],
+    start: DateTime<FixedOffset>,
+    stop: DateTime<FixedOffset>,
+) -> Result<HashMap<String, Series>, ApiError> {
+    let field_filter = fields
+        .iter()
+        .map(|field| format!(r#"r._field == "{}""#, field))
+        .collect::<Vec<_>>()
+        .join(" or ");
+
+    let flux = format!(r#"
+        from(bucket: "hab")
+        |> range(start: {}, stop: {})
+        |> filter(fn: (r) => r._measurement == "{}")
+        |> filter(fn: (r) => {})
+        |> aggregateWindow(every: 1m, fn: mean, createEmpty: false, timeSrc: "_start")
+    "#, start.to_rfc3339(), stop.to_rfc3339(), name, field_filter);
+
+    let records = db.query_raw(flux).await?;
+
+    let mut series: HashMap<String, Series> = HashMap::new();
+    for record in records.iter() {
+        match (
+            record.values.get("_field"),
+            record.values.get("_time"),
+            record.values.get("_value"),
+        ) {
+            (Some(Value::String(field)), Some(Value::TimeRFC(time)), Some(Value::Double(value))) => {
+                series
+                    .entry(field.clone())
+                    .or_default()
+                    .push((*time, value.into_inner()));
+            }
+            _ => {
+                log::debug!("{}: skipping record {:?}", name, record.values);
+            }
+        }
+    }
+
+    for points in series.values_mut() {
+        points.sort_by_key(|(time, _)| *time);
+    }
+
+    Ok(series)
+}
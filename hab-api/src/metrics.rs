@@ -0,0 +1,140 @@
+//! Prometheus metrics
+//!
+//! Exposes the latest value of every numeric or boolean field as `hab_field_value{device, field}`
+//! and the age of each device's latest point as `hab_device_age_seconds{device}`, so that stale
+//! devices can be alerted on. Devices without a point in the last day are left out.
+
+use crate::db::{CacheStats, Db};
+use crate::error::ApiError;
+use crate::measurement::{device_measurements, query_latest_fields, Latest};
+use axum::extract::State;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use prometheus::{Encoder, GaugeVec, IntCounter, IntGauge, Opts, Registry, TextEncoder};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Age, in seconds, beyond which a device is no longer reported
+const MAX_AGE: u64 = 86400;
+
+/// Latest telemetry and API health in the Prometheus text format
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "v1",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+        (status = 502, description = "InfluxDB could not be queried", body = ErrorBody),
+        (status = 504, description = "InfluxDB did not answer in time", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn metrics(State(db): State<Arc<Db>>) -> Result<Response, ApiError> {
+    let devices = device_measurements();
+    let measurements: Vec<&str> = devices.iter().map(|(_, measurement)| *measurement).collect();
+    let latest = query_latest_fields(&db, &measurements, MAX_AGE).await?;
+
+    let encoder = TextEncoder::new();
+    let body = render(&encoder, &devices, &latest, Utc::now(), db.stats());
+
+    Ok(([(header::CONTENT_TYPE, encoder.format_type().to_string())], body).into_response())
+}
+
+/// Renders the metrics of the latest point of each device, as of `now`
+fn render(
+    encoder: &TextEncoder,
+    devices: &[(String, &str)],
+    latest: &HashMap<String, Latest>,
+    now: DateTime<Utc>,
+    cache: CacheStats,
+) -> Vec<u8> {
+    // names and labels are fixed, so creating and registering the metrics can't fail
+    let registry = Registry::new();
+    let field_value = GaugeVec::new(
+        Opts::new("hab_field_value", "Latest value of a device field"),
+        &["device", "field"],
+    )
+    .unwrap();
+    let device_age = GaugeVec::new(
+        Opts::new("hab_device_age_seconds", "Seconds since the latest point of a device"),
+        &["device"],
+    )
+    .unwrap();
+    let cache_hits = IntCounter::new("hab_api_cache_hits_total", "Queries answered from the cache").unwrap();
+    let cache_misses = IntCounter::new("hab_api_cache_misses_total", "Queries sent to InfluxDB").unwrap();
+    let cache_entries = IntGauge::new("hab_api_cache_entries", "Query results held in the cache").unwrap();
+
+    for (device, measurement) in devices {
+        if let Some((time, fields)) = latest.get(*measurement) {
+            let age = now.signed_duration_since(*time).num_milliseconds() as f64 / 1000.0;
+            device_age.with_label_values(&[device]).set(age);
+
+            for (field, value) in fields {
+                let value = match value {
+                    Value::Number(n) => n.as_f64(),
+                    Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+                    _ => None,
+                };
+                if let Some(value) = value {
+                    field_value.with_label_values(&[device, field]).set(value);
+                }
+            }
+        }
+    }
+
+    cache_hits.inc_by(cache.hits);
+    cache_misses.inc_by(cache.misses);
+    cache_entries.set(cache.entries as i64);
+
+    registry.register(Box::new(field_value)).unwrap();
+    registry.register(Box::new(device_age)).unwrap();
+    registry.register(Box::new(cache_hits)).unwrap();
+    registry.register(Box::new(cache_misses)).unwrap();
+    registry.register(Box::new(cache_entries)).unwrap();
+
+    let mut body = Vec::new();
+    if let Err(err) = encoder.encode(&registry.gather(), &mut body) {
+        log::error!("failed to encode metrics: {}", err);
+    }
+    body
+}
+
+#[cfg(test)]
+mod test {
+    use super::render;
+    use crate::db::CacheStats;
+    use chrono::{DateTime, Utc};
+    use prometheus::TextEncoder;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn render_latest_fields() {
+        let time = DateTime::parse_from_rfc3339("2023-08-01T12:00:00Z").unwrap();
+        let now: DateTime<Utc> = DateTime::parse_from_rfc3339("2023-08-01T12:01:30Z").unwrap().into();
+        let fields = json!({ "battery_voltage": 13.2, "load_state": true, "state": "Float" });
+        let latest = HashMap::from([(
+            "mppt_lil".to_string(),
+            (time, fields.as_object().unwrap().clone()),
+        )]);
+        let devices = [("mppt.lil".to_string(), "mppt_lil"), ("mppt.big".to_string(), "mppt_big")];
+        let cache = CacheStats {
+            hits: 3,
+            misses: 2,
+            entries: 1,
+            ttl_ms: 1000,
+        };
+
+        let body = render(&TextEncoder::new(), &devices, &latest, now, cache);
+        let body = String::from_utf8(body).unwrap();
+
+        assert!(body.contains(r#"hab_field_value{device="mppt.lil",field="battery_voltage"} 13.2"#));
+        assert!(body.contains(r#"hab_field_value{device="mppt.lil",field="load_state"} 1"#));
+        assert!(body.contains(r#"hab_device_age_seconds{device="mppt.lil"} 90"#));
+        assert!(body.contains("hab_api_cache_hits_total 3"));
+        assert!(!body.contains("field=\"state\""));
+        assert!(!body.contains("mppt.big"));
+    }
+}
@@ -0,0 +1,126 @@
+//! Delivery of alert notifications
+
+use crate::alert::{Notification, Status};
+use anyhow::Result;
+use rumqttc::{AsyncClient, ClientError, MqttOptions, QoS};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Time allowed for a webhook to answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay before reconnecting to the MQTT broker after an error
+const MQTT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Notifications queued for the MQTT broker before more are dropped
+const MQTT_QUEUE_LEN: usize = 16;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SinkConfig {
+    /// Writes notifications to the log
+    Log,
+
+    /// POSTs each notification as JSON to `url`
+    Webhook { url: String },
+
+    /// Publishes each notification as JSON to `topic`
+    Mqtt {
+        host: String,
+        #[serde(default = "default_mqtt_port")]
+        port: u16,
+        topic: String,
+        #[serde(default = "default_mqtt_client_id")]
+        client_id: String,
+    },
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "hab-api".to_string()
+}
+
+#[derive(Debug)]
+pub enum Sink {
+    Log,
+    Webhook { client: reqwest::Client, url: String },
+    Mqtt { client: AsyncClient, topic: String },
+}
+
+impl Sink {
+    /// Creates the sink, connecting to the MQTT broker in the background
+    pub fn connect(config: &SinkConfig) -> Sink {
+        match config {
+            SinkConfig::Log => Sink::Log,
+            SinkConfig::Webhook { url } => Sink::Webhook {
+                client: reqwest::Client::new(),
+                url: url.clone(),
+            },
+            SinkConfig::Mqtt {
+                host,
+                port,
+                topic,
+                client_id,
+            } => {
+                let options = MqttOptions::new(client_id, host, *port);
+                let (client, mut event_loop) = AsyncClient::new(options, MQTT_QUEUE_LEN);
+                tokio::spawn(async move {
+                    loop {
+                        if let Err(err) = event_loop.poll().await {
+                            log::warn!("mqtt: {}", err);
+                            tokio::time::sleep(MQTT_RECONNECT_DELAY).await;
+                        }
+                    }
+                });
+                Sink::Mqtt {
+                    client,
+                    topic: topic.clone(),
+                }
+            }
+        }
+    }
+
+    pub async fn send(&self, notification: &Notification) -> Result<()> {
+        match self {
+            Sink::Log => {
+                let Notification {
+                    rule,
+                    device,
+                    message,
+                    ..
+                } = notification;
+                match notification.status {
+                    Status::Firing => log::warn!("alert {} firing on {}: {}", rule, device, message),
+                    Status::Resolved => log::info!("alert {} resolved on {}", rule, device),
+                }
+            }
+            Sink::Webhook { client, url } => {
+                client
+                    .post(url)
+                    .timeout(WEBHOOK_TIMEOUT)
+                    .json(notification)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Sink::Mqtt { client, topic } => {
+                // the queue isn't drained while the broker is down, so waiting on it would hold up
+                // the other sinks and the evaluation of the rules
+                let payload = serde_json::to_vec(notification)?;
+                match client.try_publish(topic, QoS::AtLeastOnce, false, payload) {
+                    Err(ClientError::TryRequest(_)) => log::warn!(
+                        "mqtt: queue full, dropping notification of {} on {}",
+                        notification.rule,
+                        notification.device
+                    ),
+                    result => result?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! OpenAPI document describing the routes and response types
+//!
+//! The document is the contract used to generate typed dashboard clients. A copy is checked in as
+//! `openapi.json` and compared by the tests, so changes to it show up in review.
+
+use axum::response::Json;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "hab-api", description = "Access to stored HAB telemetry"),
+    paths(
+        crate::v0_data,
+        crate::v1_stats,
+        crate::summary::v1_summary,
+        crate::energy::v1_energy,
+        crate::events::v1_events,
+        crate::live::v1_live,
+        crate::metrics::metrics,
+    ),
+    components(schemas(
+        crate::V0Data,
+        crate::V0MpptDevices,
+        crate::V0Inverters,
+        crate::V0Inverter,
+        crate::V0Imus,
+        crate::Stats,
+        crate::db::CacheStats,
+        crate::error::ErrorBody,
+        crate::error::DeviceErrors,
+        crate::measurement::V0Mppt,
+        crate::measurement::V0Dc,
+        crate::measurement::V0Ac,
+        crate::summary::Summary,
+        crate::summary::Solar,
+        crate::summary::Battery,
+        crate::summary::Load,
+        crate::summary::Supply,
+        crate::energy::Period,
+        crate::energy::PeriodEnergy,
+        crate::energy::FieldEnergy,
+        crate::energy::EnergyReport,
+        crate::events::Event,
+        crate::live::DeviceUpdate,
+    )),
+    modifiers(&BearerAuth),
+)]
+pub struct ApiDoc;
+
+/// Adds the api token security scheme referenced by the routes
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
+}
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod test {
+    use super::ApiDoc;
+    use utoipa::OpenApi;
+
+    const SCHEMA_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+    /// Fails when the generated document differs from the checked in copy.
+    /// Run with `UPDATE_OPENAPI=1` to accept an intended change.
+    #[test]
+    fn schema_matches_checked_in_copy() {
+        let generated = ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+
+        if std::env::var_os("UPDATE_OPENAPI").is_some() {
+            std::fs::write(SCHEMA_PATH, &generated).unwrap();
+        }
+
+        let checked_in = std::fs::read_to_string(SCHEMA_PATH).unwrap_or_default();
+        assert!(
+            checked_in == generated,
+            "openapi.json is out of date, rerun tests with UPDATE_OPENAPI=1 if the change is intended"
+        );
+    }
+}
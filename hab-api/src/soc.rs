@@ -0,0 +1,559 @@
+//! Battery state of charge estimated by counting the current in and out of the battery
+//!
+//! Without a shunt the battery current is taken as the MPPT charge currents plus the Multiplus
+//! charger current, less the Multiplus inverter current. DC loads not supplied through the
+//! Multiplus are not seen, so the count drifts and is resynchronized from the voltage: to full
+//! when a controller is floating, and from the resting voltage after a period without current.
+//!
+//! Estimates are written to the "soc" measurement, which is also where the count is restored from
+//! after a restart.
+
+use crate::db::Db;
+use crate::measurement::{query_latest_fields, Latest, MPPT_DEVICES};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, Utc};
+use influxdb2::models::DataPoint;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Age, in seconds, beyond which a point is not used
+const MAX_AGE: u64 = 60;
+
+/// Longest time between samples that is integrated; longer gaps are skipped
+const MAX_GAP_SECS: i64 = 60;
+
+/// Age, in seconds, beyond which a persisted estimate is not restored
+const MAX_RESTORE_AGE: u64 = 86400;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SocConfig {
+    /// Capacity at the rated discharge time (Ah)
+    pub capacity_ah: f64,
+
+    /// Discharge time the capacity is rated at (h)
+    #[serde(default = "default_rated_hours")]
+    pub rated_hours: f64,
+
+    /// Peukert exponent, 1.0 disables the correction
+    #[serde(default = "default_peukert_exponent")]
+    pub peukert_exponent: f64,
+
+    /// Fraction of the charge current that is stored
+    #[serde(default = "default_charge_efficiency")]
+    pub charge_efficiency: f64,
+
+    /// Voltage at or above which a floating controller means the battery is full (V)
+    #[serde(default = "default_float_voltage")]
+    pub float_voltage: f64,
+
+    /// Current below which the battery is considered at rest (A)
+    #[serde(default = "default_rest_current")]
+    pub rest_current: f64,
+
+    /// Time at rest before the voltage is trusted, in seconds
+    #[serde(default = "default_rest_secs")]
+    pub rest_secs: u64,
+
+    /// Resting voltage to state of charge (%) as (V, %), ascending
+    #[serde(default = "default_rest_voltage_soc")]
+    pub rest_voltage_soc: Vec<(f64, f64)>,
+
+    /// Interval between estimates, in seconds
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_rated_hours() -> f64 {
+    20.0
+}
+
+fn default_peukert_exponent() -> f64 {
+    1.05
+}
+
+fn default_charge_efficiency() -> f64 {
+    0.95
+}
+
+fn default_float_voltage() -> f64 {
+    13.4
+}
+
+fn default_rest_current() -> f64 {
+    0.5
+}
+
+fn default_rest_secs() -> u64 {
+    1800
+}
+
+/// Resting voltage of a 12V LiFePO4 battery
+fn default_rest_voltage_soc() -> Vec<(f64, f64)> {
+    vec![
+        (10.0, 0.0),
+        (12.0, 9.0),
+        (12.5, 14.0),
+        (12.8, 17.0),
+        (12.9, 20.0),
+        (13.0, 30.0),
+        (13.1, 40.0),
+        (13.2, 70.0),
+        (13.3, 90.0),
+        (13.4, 99.0),
+        (13.6, 100.0),
+    ]
+}
+
+fn default_interval_secs() -> u64 {
+    10
+}
+
+impl SocConfig {
+    /// Checks the settings that would make the estimate infinite or NaN
+    fn validate(&self) -> Result<()> {
+        let positive = [
+            ("capacity_ah", self.capacity_ah),
+            ("rated_hours", self.rated_hours),
+            ("peukert_exponent", self.peukert_exponent),
+        ];
+        for (name, value) in positive {
+            if !(value.is_finite() && value > 0.0) {
+                bail!("soc: {} must be positive, but is {}", name, value);
+            }
+        }
+
+        let table = &self.rest_voltage_soc;
+        if table.iter().any(|(v, soc)| !v.is_finite() || !soc.is_finite()) {
+            bail!("soc: rest_voltage_soc must be finite");
+        }
+        if let Some(pair) = table.windows(2).find(|pair| pair[0].0 >= pair[1].0) {
+            bail!(
+                "soc: rest_voltage_soc voltages must be ascending, but {} is followed by {}",
+                pair[0].0,
+                pair[1].0
+            );
+        }
+
+        Ok(())
+    }
+
+    /// State of charge (%) of a battery resting at `voltage`, interpolated from the table
+    fn soc_at_rest(&self, voltage: f64) -> f64 {
+        let table = &self.rest_voltage_soc;
+        match table.iter().position(|(v, _)| *v >= voltage) {
+            None => table.last().map_or(100.0, |(_, soc)| *soc),
+            Some(0) => table[0].1,
+            Some(i) => {
+                let ((v0, soc0), (v1, soc1)) = (table[i - 1], table[i]);
+                soc0 + (soc1 - soc0) * (voltage - v0) / (v1 - v0)
+            }
+        }
+    }
+
+    /// Current that drains the battery as fast as discharging at `current` according to Peukert
+    fn peukert_current(&self, current: f64) -> f64 {
+        let rated_current = self.capacity_ah / self.rated_hours;
+        rated_current * (current / rated_current).powf(self.peukert_exponent)
+    }
+}
+
+/// Battery readings at one point in time
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    /// Time of the oldest point the readings are from, so it doesn't advance while a device that
+    /// stopped reporting is still included
+    pub time: DateTime<Utc>,
+
+    /// Net current into the battery, >0 charging (A)
+    pub current: f64,
+    pub voltage: Option<f64>,
+
+    /// Whether any MPPT controller is in float
+    pub float: bool,
+}
+
+impl Sample {
+    /// Combines the latest MPPT and Multiplus DC points, if there are any
+    fn from_latest(latest: &HashMap<String, Latest>) -> Option<Sample> {
+        let number = |fields: &serde_json::Map<String, Value>, field: &str| {
+            fields.get(field).and_then(Value::as_f64).unwrap_or_default()
+        };
+
+        let mppts: Vec<_> = MPPT_DEVICES
+            .iter()
+            .filter_map(|(_, measurement)| latest.get(*measurement).map(|(_, fields)| fields))
+            .collect();
+        let dc = latest.get("dc").map(|(_, fields)| fields);
+        if mppts.is_empty() && dc.is_none() {
+            return None;
+        }
+
+        let time = MPPT_DEVICES
+            .iter()
+            .map(|(_, measurement)| *measurement)
+            .chain(["dc"])
+            .filter_map(|measurement| latest.get(measurement).map(|(time, _)| *time))
+            .min()?
+            .with_timezone(&Utc);
+
+        let mut current: f64 = mppts.iter().map(|fields| number(fields, "battery_current")).sum();
+        if let Some(dc) = dc {
+            current += number(dc, "charger_current") - number(dc, "inverter_current");
+        }
+
+        let voltage = match dc.and_then(|dc| dc.get("voltage")).and_then(Value::as_f64) {
+            Some(voltage) => Some(voltage),
+            None if mppts.is_empty() => None,
+            None => {
+                let sum: f64 = mppts.iter().map(|fields| number(fields, "battery_voltage")).sum();
+                Some(sum / mppts.len() as f64)
+            }
+        };
+
+        let float = mppts
+            .iter()
+            .any(|fields| fields.get("state").and_then(Value::as_str) == Some("Float"));
+
+        Some(Sample {
+            time,
+            current,
+            voltage,
+            float,
+        })
+    }
+}
+
+/// Reason the count was reset from the voltage
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resync {
+    /// Initial estimate, nothing to restore
+    Initial,
+
+    /// Controller floating, battery full
+    Float,
+
+    /// Battery at rest, estimated from its voltage
+    Rest,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Estimate {
+    /// State of charge (%)
+    pub soc: f64,
+    pub charge_ah: f64,
+    pub current: f64,
+
+    /// Time until empty at the present current, if discharging (h)
+    pub time_to_empty_hours: Option<f64>,
+    pub resync: Option<Resync>,
+}
+
+pub struct Estimator {
+    config: SocConfig,
+
+    /// Remaining charge, if known (Ah)
+    charge_ah: Option<f64>,
+
+    /// Time of the last sample
+    last: Option<DateTime<Utc>>,
+
+    /// Time since which the current has been below the rest current
+    rest_since: Option<DateTime<Utc>>,
+}
+
+impl Estimator {
+    pub fn new(config: SocConfig, charge_ah: Option<f64>) -> Estimator {
+        Estimator {
+            charge_ah: charge_ah.map(|charge| charge.clamp(0.0, config.capacity_ah)),
+            config,
+            last: None,
+            rest_since: None,
+        }
+    }
+
+    /// Accounts for the current since the last sample and resynchronizes if possible
+    ///
+    /// The current is integrated over the time between samples, so a sample that is no newer
+    /// than the last one adds nothing.
+    pub fn update(&mut self, sample: &Sample) -> Option<Estimate> {
+        let capacity = self.config.capacity_ah;
+        let now = sample.time;
+
+        if let (Some(charge), Some(last)) = (self.charge_ah.as_mut(), self.last) {
+            let secs = now.signed_duration_since(last).num_milliseconds() as f64 / 1000.0;
+            if secs > 0.0 && secs <= MAX_GAP_SECS as f64 {
+                let hours = secs / 3600.0;
+                let delta = if sample.current >= 0.0 {
+                    sample.current * self.config.charge_efficiency * hours
+                } else {
+                    -self.config.peukert_current(-sample.current) * hours
+                };
+                *charge = (*charge + delta).clamp(0.0, capacity);
+            }
+        }
+        self.last = Some(now);
+
+        if sample.current.abs() < self.config.rest_current {
+            self.rest_since.get_or_insert(now);
+        } else {
+            self.rest_since = None;
+        }
+        let rested = match self.rest_since {
+            Some(since) => {
+                now.signed_duration_since(since) >= Duration::seconds(self.config.rest_secs as i64)
+            }
+            None => false,
+        };
+
+        let resync = match sample.voltage {
+            Some(voltage) if sample.float && voltage >= self.config.float_voltage => {
+                self.charge_ah = Some(capacity);
+                Some(Resync::Float)
+            }
+            Some(voltage) if rested || self.charge_ah.is_none() => {
+                self.charge_ah = Some(capacity * self.config.soc_at_rest(voltage) / 100.0);
+                Some(if rested { Resync::Rest } else { Resync::Initial })
+            }
+            _ => None,
+        };
+
+        let charge_ah = self.charge_ah?;
+        let time_to_empty_hours = if sample.current < 0.0 {
+            Some(charge_ah / self.config.peukert_current(-sample.current))
+        } else {
+            None
+        };
+
+        Some(Estimate {
+            soc: 100.0 * charge_ah / capacity,
+            charge_ah,
+            current: sample.current,
+            time_to_empty_hours,
+            resync,
+        })
+    }
+}
+
+/// Starts estimating the state of charge, if configured
+pub fn spawn(db: Arc<Db>, config: &Option<SocConfig>) -> Result<()> {
+    if let Some(config) = config {
+        config.validate()?;
+        log::info!("estimating state of charge of a {}Ah battery", config.capacity_ah);
+        tokio::spawn(run(db, config.clone()));
+    }
+
+    Ok(())
+}
+
+async fn run(db: Arc<Db>, config: SocConfig) {
+    let restored = match query_latest_fields(&db, &["soc"], MAX_RESTORE_AGE).await {
+        Ok(latest) => latest
+            .get("soc")
+            .and_then(|(_, fields)| fields.get("charge_ah"))
+            .and_then(Value::as_f64),
+        Err(err) => {
+            log::warn!("soc: failed to restore charge: {}", err);
+            None
+        }
+    };
+    log::info!("soc: restored charge {:?}Ah", restored);
+
+    let mut measurements: Vec<&str> = MPPT_DEVICES.iter().map(|(_, measurement)| *measurement).collect();
+    measurements.push("dc");
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(config.interval_secs.max(1)));
+    let mut estimator = Estimator::new(config, restored);
+    loop {
+        interval.tick().await;
+
+        let sample = match query_latest_fields(&db, &measurements, MAX_AGE).await {
+            Ok(latest) => Sample::from_latest(&latest),
+            Err(err) => {
+                log::warn!("soc: {}", err);
+                continue;
+            }
+        };
+
+        let estimate = match sample {
+            Some(sample) => estimator.update(&sample),
+            None => continue,
+        };
+
+        if let Some(estimate) = estimate {
+            let mut builder = DataPoint::builder("soc")
+                .field("soc", estimate.soc)
+                .field("charge_ah", estimate.charge_ah)
+                .field("current", estimate.current);
+            if let Some(hours) = estimate.time_to_empty_hours {
+                builder = builder.field("time_to_empty_hours", hours);
+            }
+            if let Some(resync) = estimate.resync {
+                log::debug!("soc: resynchronized ({:?}) to {:.1}%", resync, estimate.soc);
+                builder = builder.field("resync", format!("{:?}", resync).to_lowercase());
+            }
+
+            match builder.build() {
+                Ok(point) => {
+                    if let Err(err) = db.write(vec![point]).await {
+                        log::warn!("soc: failed to write estimate: {}", err);
+                    }
+                }
+                Err(err) => log::error!("soc: failed to build point: {:?}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Estimator, Resync, Sample, SocConfig};
+    use chrono::{DateTime, Duration, Utc};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn config() -> SocConfig {
+        toml::from_str("capacity_ah = 200.0\npeukert_exponent = 1.0\ncharge_efficiency = 1.0").unwrap()
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        let start: DateTime<Utc> = DateTime::parse_from_rfc3339("2023-08-01T12:00:00Z").unwrap().into();
+        start + Duration::seconds(secs)
+    }
+
+    fn sample(secs: i64, current: f64, voltage: f64) -> Sample {
+        Sample {
+            time: at(secs),
+            current,
+            voltage: Some(voltage),
+            float: false,
+        }
+    }
+
+    #[test]
+    fn parse_example_config() {
+        let config: SocConfig = toml::from_str(include_str!("../soc.toml.example")).unwrap();
+        assert_eq!(200.0, config.capacity_ah);
+        assert_eq!(super::default_rest_voltage_soc(), config.rest_voltage_soc);
+    }
+
+    #[test]
+    fn validate_rejects_estimates_that_cannot_be_computed() {
+        let parse = |toml: &str| toml::from_str::<SocConfig>(toml).unwrap().validate();
+
+        assert!(parse("capacity_ah = 200.0").is_ok());
+        assert!(parse("capacity_ah = 0.0").is_err());
+        assert!(parse("capacity_ah = 200.0\nrated_hours = -20.0").is_err());
+        assert!(parse("capacity_ah = 200.0\nrest_voltage_soc = [[12.0, 10.0], [12.0, 20.0]]").is_err());
+        assert!(parse("capacity_ah = 200.0\nrest_voltage_soc = [[13.0, 50.0], [12.0, 10.0]]").is_err());
+    }
+
+    #[test]
+    fn counts_charge_and_discharge() {
+        let mut estimator = Estimator::new(config(), Some(100.0));
+
+        // a minute of sampling every 10s at -60A removes 1Ah
+        for i in 0..=6 {
+            estimator.update(&sample(i * 10, -60.0, 13.1));
+        }
+        let estimate = estimator.update(&sample(70, 0.0, 13.2)).unwrap();
+        assert!((estimate.charge_ah - 99.0).abs() < 1e-9, "{}", estimate.charge_ah);
+        assert!((estimate.soc - 49.5).abs() < 1e-9);
+        assert_eq!(None, estimate.time_to_empty_hours);
+
+        let estimate = estimator.update(&sample(80, -10.0, 13.1)).unwrap();
+        assert!((estimate.time_to_empty_hours.unwrap() - 9.9).abs() < 0.01);
+    }
+
+    #[test]
+    fn peukert_and_efficiency() {
+        let config: SocConfig = toml::from_str("capacity_ah = 200.0\npeukert_exponent = 1.2\ncharge_efficiency = 0.9").unwrap();
+        assert!((config.peukert_current(10.0) - 10.0).abs() < 1e-9, "rated current is unaffected");
+        assert!(config.peukert_current(40.0) > 40.0);
+
+        let mut estimator = Estimator::new(config, Some(100.0));
+        estimator.update(&sample(0, 36.0, 13.2));
+        let estimate = estimator.update(&sample(50, 36.0, 13.2)).unwrap();
+        assert!((estimate.charge_ah - 100.45).abs() < 1e-9);
+    }
+
+    #[test]
+    fn gaps_are_not_integrated() {
+        let mut estimator = Estimator::new(config(), Some(100.0));
+        estimator.update(&sample(0, -60.0, 13.1));
+        let estimate = estimator.update(&sample(600, -60.0, 13.1)).unwrap();
+        assert_eq!(100.0, estimate.charge_ah);
+    }
+
+    #[test]
+    fn repeated_samples_are_not_integrated() {
+        let mut estimator = Estimator::new(config(), Some(100.0));
+        for _ in 0..6 {
+            estimator.update(&sample(0, -60.0, 13.1));
+        }
+        let estimate = estimator.update(&sample(10, -60.0, 13.1)).unwrap();
+        assert!((estimate.charge_ah - (100.0 - 1.0 / 6.0)).abs() < 1e-9, "{}", estimate.charge_ah);
+    }
+
+    #[test]
+    fn resync_at_float_and_rest() {
+        let mut estimator = Estimator::new(config(), Some(100.0));
+        let floating = Sample {
+            float: true,
+            ..sample(0, 2.0, 13.5)
+        };
+        let estimate = estimator.update(&floating).unwrap();
+        assert_eq!(Some(Resync::Float), estimate.resync);
+        assert_eq!(100.0, estimate.soc);
+
+        let mut estimator = Estimator::new(config(), Some(100.0));
+        assert_eq!(None, estimator.update(&sample(0, 0.1, 13.15)).unwrap().resync);
+        assert_eq!(None, estimator.update(&sample(1000, 0.1, 13.15)).unwrap().resync);
+        let estimate = estimator.update(&sample(1800, 0.1, 13.15)).unwrap();
+        assert_eq!(Some(Resync::Rest), estimate.resync);
+        assert!((estimate.soc - 55.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn initial_estimate_from_voltage() {
+        let mut estimator = Estimator::new(config(), None);
+        let estimate = estimator.update(&sample(0, -5.0, 13.0)).unwrap();
+        assert_eq!(Some(Resync::Initial), estimate.resync);
+        assert!((estimate.soc - 30.0).abs() < 1e-9);
+
+        let mut estimator = Estimator::new(config(), None);
+        let unknown = Sample {
+            voltage: None,
+            ..sample(0, -5.0, 0.0)
+        };
+        assert_eq!(None, estimator.update(&unknown));
+    }
+
+    #[test]
+    fn sample_combines_devices() {
+        let point = |secs, fields: serde_json::Value| (at(secs).into(), fields.as_object().unwrap().clone());
+        let latest = HashMap::from([
+            (
+                "mppt_lil".to_string(),
+                point(0, json!({ "battery_current": 5.0, "battery_voltage": 13.3, "state": "Float" })),
+            ),
+            (
+                "mppt_big".to_string(),
+                point(5, json!({ "battery_current": 10.0, "battery_voltage": 13.5, "state": "Bulk" })),
+            ),
+            (
+                "dc".to_string(),
+                point(2, json!({ "voltage": 13.2, "charger_current": 0.0, "inverter_current": 20.0 })),
+            ),
+        ]);
+
+        let sample = Sample::from_latest(&latest).unwrap();
+        assert_eq!(at(0), sample.time);
+        assert_eq!(-5.0, sample.current);
+        assert_eq!(Some(13.2), sample.voltage);
+        assert!(sample.float);
+
+        assert_eq!(None, Sample::from_latest(&HashMap::new()));
+    }
+}
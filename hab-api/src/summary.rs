@@ -0,0 +1,226 @@
+//! System-level energy summary combining the MPPT controllers and the Multiplus
+
+use crate::db::Db;
+use crate::error::{ApiError, DeviceErrors, Partial};
+use crate::measurement::{query_measurement, V0Ac, V0Dc, V0Mppt, MPPT_DEVICES};
+use axum::extract::State;
+use axum::response::Json;
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// Age, in seconds, beyond which a device is considered not reporting
+const MAX_AGE: u64 = 60;
+
+#[derive(Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct Summary {
+    pub solar: Solar,
+    pub battery: Battery,
+    pub load: Load,
+    pub supply: Supply,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<DeviceErrors>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct Solar {
+    /// Number of MPPT controllers with recent data
+    pub controllers: usize,
+
+    /// Total panel power across all controllers (W)
+    pub watts: Option<f64>,
+
+    /// Total power delivered to the battery by all controllers (W)
+    pub battery_watts: Option<f64>,
+
+    /// Total yield today across all controllers (Wh)
+    pub yield_today: Option<f64>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct Battery {
+    /// Battery voltage, from the Multiplus if available otherwise averaged over the MPPTs (V)
+    pub voltage: Option<f64>,
+
+    /// Net power into the battery from known sources and sinks, >0 charging (W)
+    pub net_watts: Option<f64>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct Load {
+    /// AC output of the Multiplus, whether inverting or passing through shore power (W)
+    pub ac_watts: Option<f64>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct Supply {
+    /// Power drawn from shore (W)
+    pub shore_watts: Option<f64>,
+
+    /// DC power drawn by the inverter (W)
+    pub inverter_watts: Option<f64>,
+
+    /// DC power delivered by the charger (W)
+    pub charger_watts: Option<f64>,
+
+    /// Fraction of AC supply coming from the inverter, 0..1
+    pub inverter_share: Option<f64>,
+
+    /// Fraction of AC supply coming from shore, 0..1
+    pub shore_share: Option<f64>,
+}
+
+impl Summary {
+    /// Combines the latest point of each device into a summary
+    pub fn compute(mppts: &[V0Mppt], dc: Option<&V0Dc>, ac: Option<&V0Ac>) -> Summary {
+        let sum = |f: fn(&V0Mppt) -> f64| -> Option<f64> {
+            if mppts.is_empty() {
+                None
+            } else {
+                Some(mppts.iter().map(f).sum())
+            }
+        };
+
+        let solar = Solar {
+            controllers: mppts.len(),
+            watts: sum(|m| m.panel_power),
+            battery_watts: sum(|m| m.battery_voltage * m.battery_current),
+            yield_today: sum(|m| m.yield_today),
+        };
+
+        let voltage = dc
+            .map(|dc| dc.voltage)
+            .or_else(|| sum(|m| m.battery_voltage).map(|v| v / mppts.len() as f64));
+
+        let net_watts = match (solar.battery_watts, dc) {
+            (None, None) => None,
+            (solar_watts, dc) => Some(
+                solar_watts.unwrap_or_default()
+                    + dc.map_or(0.0, |dc| dc.charger_watts - dc.inverter_watts),
+            ),
+        };
+
+        let shore_watts = ac.map(|ac| ac.mains_watts);
+        let inverter_watts = dc.map(|dc| dc.inverter_watts);
+        let (inverter_share, shore_share) = match (inverter_watts, shore_watts) {
+            (Some(inverter), Some(shore)) if inverter + shore > 0.0 => {
+                let share = inverter / (inverter + shore);
+                (Some(share), Some(1.0 - share))
+            }
+            _ => (None, None),
+        };
+
+        Summary {
+            solar,
+            battery: Battery { voltage, net_watts },
+            load: Load {
+                ac_watts: ac.map(|ac| ac.inverter_watts),
+            },
+            supply: Supply {
+                shore_watts,
+                inverter_watts,
+                charger_watts: dc.map(|dc| dc.charger_watts),
+                inverter_share,
+                shore_share,
+            },
+            errors: None,
+        }
+    }
+}
+
+/// System-level view combining all MPPT controllers and the Multiplus
+#[utoipa::path(
+    get,
+    path = "/v1/summary",
+    tag = "v1",
+    responses(
+        (status = 200, description = "Summary of devices with recent data", body = Summary),
+        (status = 502, description = "No device could be queried", body = ErrorBody),
+        (status = 504, description = "No device could be queried in time", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn v1_summary(State(db): State<Arc<Db>>) -> Result<Json<Value>, ApiError> {
+    let [(lil, lil_measurement), (big, big_measurement), (ext, ext_measurement)] = MPPT_DEVICES;
+    let (mppt_lil, mppt_big, mppt_ext, dc, ac) = tokio::join!(
+        query_measurement::<V0Mppt>(&db, lil_measurement, MAX_AGE),
+        query_measurement::<V0Mppt>(&db, big_measurement, MAX_AGE),
+        query_measurement::<V0Mppt>(&db, ext_measurement, MAX_AGE),
+        query_measurement::<V0Dc>(&db, "dc", MAX_AGE),
+        query_measurement::<V0Ac>(&db, "ac", MAX_AGE),
+    );
+
+    let mut partial = Partial::default();
+    let mppts: Vec<V0Mppt> = [(lil, mppt_lil), (big, mppt_big), (ext, mppt_ext)]
+        .into_iter()
+        .filter_map(|(name, result)| partial.take(&format!("mppt.{}", name), result))
+        .collect();
+    let dc = partial.take("inverter.primary.dc", dc);
+    let ac = partial.take("inverter.primary.ac", ac);
+
+    let summary = Summary::compute(&mppts, dc.as_ref(), ac.as_ref());
+    partial.finish(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Summary;
+    use crate::measurement::{V0Ac, V0Dc, V0Mppt};
+
+    fn mppt(panel_power: f64, battery_voltage: f64, battery_current: f64, yield_today: f64) -> V0Mppt {
+        V0Mppt {
+            panel_power,
+            battery_voltage,
+            battery_current,
+            yield_today,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn summary_combines_devices() {
+        let mppts = [mppt(200.0, 13.0, 10.0, 500.0), mppt(100.0, 13.2, 5.0, 250.0)];
+        let dc = V0Dc {
+            voltage: 13.1,
+            inverter_watts: 300.0,
+            charger_watts: 0.0,
+            ..Default::default()
+        };
+        let ac = V0Ac {
+            inverter_watts: 250.0,
+            mains_watts: 100.0,
+            ..Default::default()
+        };
+
+        let summary = Summary::compute(&mppts, Some(&dc), Some(&ac));
+
+        assert_eq!(2, summary.solar.controllers);
+        assert_eq!(Some(300.0), summary.solar.watts);
+        assert_eq!(Some(750.0), summary.solar.yield_today);
+        assert_eq!(Some(196.0), summary.solar.battery_watts);
+        assert_eq!(Some(13.1), summary.battery.voltage);
+        assert_eq!(Some(196.0 - 300.0), summary.battery.net_watts);
+        assert_eq!(Some(250.0), summary.load.ac_watts);
+        assert_eq!(Some(0.75), summary.supply.inverter_share);
+        assert_eq!(Some(0.25), summary.supply.shore_share);
+    }
+
+    #[test]
+    fn summary_without_inverter() {
+        let mppts = [mppt(100.0, 13.0, 5.0, 100.0), mppt(0.0, 13.4, 0.0, 0.0)];
+
+        let summary = Summary::compute(&mppts, None, None);
+
+        assert_eq!(Some(13.2), summary.battery.voltage);
+        assert_eq!(Some(65.0), summary.battery.net_watts);
+        assert_eq!(None, summary.load.ac_watts);
+        assert_eq!(None, summary.supply.inverter_share);
+    }
+
+    #[test]
+    fn summary_without_data() {
+        assert_eq!(Summary::default(), Summary::compute(&[], None, None));
+    }
+}
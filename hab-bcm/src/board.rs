@@ -1,20 +1,24 @@
 use embassy_stm32::{
     bind_interrupts, i2c,
     peripherals::{self, PD12},
+    usart,
 };
 
-use crate::device::{bme680::Bme680, led::Led};
+use crate::device::{bme680::Bme680, led::Led, mppt::Mppt};
 
 pub type StatusLed = Led<'static, PD12>;
 pub type OutdoorEnvSensor = Bme680<'static, peripherals::I2C1>;
+pub type SolarCharger = Mppt<'static, peripherals::USART2, peripherals::DMA1_CH5>;
 
 bind_interrupts!(struct Irq {
     I2C1_EV => i2c::InterruptHandler<peripherals::I2C1>;
+    USART2 => usart::InterruptHandler<peripherals::USART2>;
 });
 
 pub struct Board {
     pub status_led: StatusLed,
     pub outdoor_env_sensor: OutdoorEnvSensor,
+    pub solar_charger: SolarCharger,
 }
 
 impl Board {
@@ -24,6 +28,7 @@ impl Board {
         Board {
             status_led: Led::init(p.PD12),
             outdoor_env_sensor: Bme680::init(p.I2C1, p.PB6, p.PB7, Irq),
+            solar_charger: Mppt::init(p.USART2, p.PA3, p.DMA1_CH5, Irq),
         }
     }
 }
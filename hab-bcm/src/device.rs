@@ -1,2 +1,3 @@
 pub mod bme680;
 pub mod led;
+pub mod mppt;
@@ -0,0 +1,54 @@
+use embassy_stm32::{
+    interrupt::typelevel::Binding,
+    usart::{BasicInstance, Config, InterruptHandler, RxDma, RxPin, UartRx},
+    Peripheral,
+};
+use hab_victron::ve_direct::{FrameDecoder, MpptFrame};
+
+/// VE.Direct text protocol baud rate
+const BAUD_RATE: u32 = 19_200;
+
+/// Victron MPPT controller connected through a VE.Direct cable to a UART
+pub struct Mppt<'d, T: BasicInstance, D: RxDma<T>> {
+    rx: UartRx<'d, T, D>,
+    decoder: FrameDecoder,
+    buffer: [u8; 64],
+    pending: core::ops::Range<usize>,
+}
+
+impl<'d, T: BasicInstance, D: RxDma<T>> Mppt<'d, T, D> {
+    pub fn init(
+        p: impl Peripheral<P = T> + 'd,
+        rx: impl Peripheral<P = impl RxPin<T>> + 'd,
+        dma: impl Peripheral<P = D> + 'd,
+        irq: impl Binding<T::Interrupt, InterruptHandler<T>> + 'd,
+    ) -> Mppt<'d, T, D> {
+        let mut config = Config::default();
+        config.baudrate = BAUD_RATE;
+
+        Mppt {
+            rx: UartRx::new(p, irq, rx, dma, config),
+            decoder: FrameDecoder::default(),
+            buffer: [0; 64],
+            pending: 0..0,
+        }
+    }
+
+    /// Waits for the next block with a valid checksum
+    pub async fn frame(&mut self) -> MpptFrame {
+        loop {
+            // finish the bytes of the previous read before reading more
+            for index in self.pending.clone() {
+                self.pending.start = index + 1;
+                if let Some(frame) = self.decoder.decode_byte(self.buffer[index]) {
+                    return frame;
+                }
+            }
+
+            match self.rx.read_until_idle(&mut self.buffer).await {
+                Ok(count) => self.pending = 0..count,
+                Err(err) => defmt::warn!("mppt uart error: {}", err),
+            }
+        }
+    }
+}
@@ -5,11 +5,11 @@
 mod board;
 mod device;
 
-use board::{Board, OutdoorEnvSensor, StatusLed};
+use board::{Board, OutdoorEnvSensor, SolarCharger, StatusLed};
 
 use embassy_executor::Spawner;
 
-use defmt::println;
+use defmt::{println, Debug2Format};
 use embassy_time::{Duration, Timer};
 use {defmt_rtt as _, panic_probe as _};
 
@@ -21,6 +21,9 @@ async fn main(spawner: Spawner) {
     spawner
         .spawn(monitor_outdoor_env(board.outdoor_env_sensor))
         .unwrap();
+    spawner
+        .spawn(monitor_solar_charger(board.solar_charger))
+        .unwrap();
 }
 
 #[embassy_executor::task]
@@ -47,3 +50,19 @@ async fn monitor_outdoor_env(mut sensor: OutdoorEnvSensor) {
         Timer::after(Duration::from_secs(5)).await;
     }
 }
+
+#[embassy_executor::task]
+async fn monitor_solar_charger(mut mppt: SolarCharger) {
+    let mut state = None;
+    loop {
+        let frame = mppt.frame().await;
+
+        if frame.state != state {
+            println!("Charge state {}", Debug2Format(&frame.state));
+            state = frame.state;
+        }
+        if let (Some(voltage), Some(current)) = (frame.battery_voltage, frame.battery_current) {
+            println!("Battery {}V {}A", voltage, current);
+        }
+    }
+}
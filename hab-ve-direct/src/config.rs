@@ -9,6 +9,9 @@ pub struct Config {
     pub influxdb_url: String,
     pub influxdb_org: String,
     pub influxdb_token: String,
+
+    /// Address of the embedded metrics listener, e.g. "0.0.0.0:9101", disabled if not set
+    pub metrics_address: Option<String>,
 }
 
 impl Config {
@@ -45,6 +48,7 @@ impl Config {
             influxdb_url: std::env::var("INFLUXDB_URL")?,
             influxdb_org: std::env::var("INFLUXDB_ORG")?,
             influxdb_token: std::env::var("INFLUXDB_TOKEN")?,
+            metrics_address: std::env::var("METRICS_ADDRESS").ok(),
         })
     }
 }
@@ -1,5 +1,5 @@
 mod config;
-mod parser;
+mod metrics;
 mod ve_direct;
 
 use anyhow::Result;
@@ -0,0 +1,168 @@
+//! Prometheus metrics served by an optional embedded HTTP listener
+//!
+//! Every metric carries a `device` label with the configured device name, so the collectors of
+//! all MPPT controllers can be scraped by one Prometheus.
+
+use anyhow::Result;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use prometheus::{Encoder, Gauge, IntCounter, Opts, Registry, TextEncoder};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+
+    /// Frames with a valid checksum
+    pub frames: IntCounter,
+
+    /// Frames discarded because of a bad checksum
+    pub checksum_failures: IntCounter,
+
+    /// Times the parser was reset after reading malformed input
+    pub resyncs: IntCounter,
+
+    /// Batches of points that could not be written to InfluxDB
+    pub write_failures: IntCounter,
+
+    pub battery_voltage: Gauge,
+    pub battery_current: Gauge,
+    pub panel_voltage: Gauge,
+    pub panel_power: Gauge,
+    pub yield_today: Gauge,
+
+    /// Seconds since the last valid frame, or since starting if there was none
+    last_frame_age: Gauge,
+    last_frame: Mutex<Instant>,
+}
+
+impl Metrics {
+    pub fn new(device_name: &str) -> Result<Metrics> {
+        let labels = HashMap::from([("device".to_string(), device_name.to_string())]);
+        let registry = Registry::new_custom(None, Some(labels))?;
+
+        let counter = |name: &str, help: &str| -> Result<IntCounter> {
+            let counter = IntCounter::with_opts(Opts::new(name, help))?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok(counter)
+        };
+        let gauge = |name: &str, help: &str| -> Result<Gauge> {
+            let gauge = Gauge::with_opts(Opts::new(name, help))?;
+            registry.register(Box::new(gauge.clone()))?;
+            Ok(gauge)
+        };
+
+        Ok(Metrics {
+            frames: counter("hab_ve_direct_frames_total", "Frames with a valid checksum")?,
+            checksum_failures: counter(
+                "hab_ve_direct_checksum_failures_total",
+                "Frames discarded because of a bad checksum",
+            )?,
+            resyncs: counter(
+                "hab_ve_direct_resyncs_total",
+                "Times the parser was reset after reading malformed input",
+            )?,
+            write_failures: counter(
+                "hab_ve_direct_write_failures_total",
+                "Batches of points that could not be written to InfluxDB",
+            )?,
+            battery_voltage: gauge("hab_mppt_battery_voltage_volts", "Battery voltage")?,
+            battery_current: gauge("hab_mppt_battery_current_amperes", "Battery charge current")?,
+            panel_voltage: gauge("hab_mppt_panel_voltage_volts", "Panel voltage")?,
+            panel_power: gauge("hab_mppt_panel_power_watts", "Panel power")?,
+            yield_today: gauge("hab_mppt_yield_today_watt_hours", "Energy yield today")?,
+            last_frame_age: gauge(
+                "hab_ve_direct_last_frame_age_seconds",
+                "Seconds since the last valid frame",
+            )?,
+            last_frame: Mutex::new(Instant::now()),
+            registry,
+        })
+    }
+
+    /// Counts a frame with a valid checksum
+    pub fn frame_decoded(&self) {
+        self.frames.inc();
+        *self.last_frame.lock().unwrap() = Instant::now();
+    }
+
+    /// Renders all metrics in the Prometheus text format
+    fn encode(&self, encoder: &TextEncoder) -> Result<Vec<u8>> {
+        let age = self.last_frame.lock().unwrap().elapsed();
+        self.last_frame_age.set(age.as_secs_f64());
+
+        let mut buffer = Vec::new();
+        encoder.encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// Serves the metrics at `/metrics` on `address`
+pub async fn serve(address: &str, metrics: Arc<Metrics>) -> Result<()> {
+    let address: SocketAddr = address.parse()?;
+    log::info!("serving metrics on http://{}/metrics", address);
+
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(respond(&metrics, request)) }
+            }))
+        }
+    });
+
+    Server::try_bind(&address)?.serve(make_service).await?;
+    Ok(())
+}
+
+fn respond(metrics: &Metrics, request: Request<Body>) -> Response<Body> {
+    let mut response = Response::default();
+
+    if request.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+
+    let encoder = TextEncoder::new();
+    match metrics.encode(&encoder) {
+        Ok(body) => {
+            *response.body_mut() = Body::from(body);
+            if let Ok(content_type) = encoder.format_type().parse() {
+                response.headers_mut().insert(CONTENT_TYPE, content_type);
+            }
+        }
+        Err(err) => {
+            log::error!("failed to encode metrics: {:?}", err);
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use prometheus::TextEncoder;
+
+    #[test]
+    fn encode_with_device_label() {
+        let metrics = Metrics::new("mppt_lil").unwrap();
+        metrics.frame_decoded();
+        metrics.checksum_failures.inc();
+        metrics.battery_voltage.set(13.25);
+
+        let body = String::from_utf8(metrics.encode(&TextEncoder::new()).unwrap()).unwrap();
+
+        assert!(body.contains(r#"hab_ve_direct_frames_total{device="mppt_lil"} 1"#));
+        assert!(body.contains(r#"hab_ve_direct_checksum_failures_total{device="mppt_lil"} 1"#));
+        assert!(body.contains(r#"hab_mppt_battery_voltage_volts{device="mppt_lil"} 13.25"#));
+        assert!(body.contains("hab_ve_direct_last_frame_age_seconds"));
+    }
+}
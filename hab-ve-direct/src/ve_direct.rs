@@ -1,14 +1,15 @@
 //! Victron VE-Direct interface
 use crate::config::Config;
-use crate::parser::ParseEvent;
+use crate::metrics::Metrics;
+use hab_victron::transition::{Transition, Transitions};
 use anyhow::Result;
-use bitflags::bitflags;
 use futures_util::stream;
+use hab_victron::ve_direct::{FrameDecoder, MpptFrame};
+use hab_victron::CodecStats;
 use influxdb2::models::DataPoint;
-use serde::Serialize;
 use serial_io::{build, AsyncSerial};
-use std::fmt::Display;
-use std::str;
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::io::AsyncReadExt;
 
 const BUFFER_SIZE: usize = 128;
@@ -24,8 +25,21 @@ pub async fn run(config: &Config) -> Result<()> {
         &config.influxdb_token,
     );
 
-    let mut ve_direct_mppt = VeDirectMppt::new(&config.device_name);
-    let mut parser = crate::parser::Parser::default();
+    let metrics = Arc::new(Metrics::new(&config.device_name)?);
+    if let Some(address) = &config.metrics_address {
+        let (address, metrics) = (address.clone(), metrics.clone());
+        tokio::spawn(async move {
+            if let Err(err) = crate::metrics::serve(&address, metrics).await {
+                log::error!("metrics listener failed: {:?}", err);
+            }
+        });
+    }
+
+    let mut ve_direct_mppt = VeDirectMppt::new(&config.device_name, metrics.clone());
+    let mut decoder = FrameDecoder::default();
+
+    // decoder counts already added to the metrics
+    let mut reported = CodecStats::default();
 
     let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
 
@@ -33,15 +47,27 @@ pub async fn run(config: &Config) -> Result<()> {
         // read from the device
         let count = serial.read(&mut buffer[..]).await?;
 
-        // parse the read bytes
-        parser.parse(&mut ve_direct_mppt, &buffer[0..count])?;
+        // decode the read bytes into frames
+        for byte in &buffer[0..count] {
+            if let Some(frame) = decoder.decode_byte(*byte) {
+                ve_direct_mppt.frame_received(&frame);
+            }
+        }
+
+        let stats = decoder.stats();
+        metrics
+            .checksum_failures
+            .inc_by(stats.checksum_failures - reported.checksum_failures);
+        metrics.resyncs.inc_by(stats.resyncs - reported.resyncs);
+        reported = stats;
 
         // store decoded points
-        if ve_direct_mppt.points.len() > 0 {
+        if !ve_direct_mppt.points.is_empty() {
             let submission = ve_direct_mppt.points.clone();
             ve_direct_mppt.points.clear();
             if let Err(err) = db.write("hab", stream::iter(submission)).await {
                 log::debug!("failed to write to influxdb: {:?}", err);
+                metrics.write_failures.inc();
             }
         }
     }
@@ -55,150 +81,30 @@ pub struct VeDirectMppt {
     // points ready to submit to the database
     points: Vec<DataPoint>,
 
-    // current records
-    records: Vec<(String, String)>,
+    // latest telemetry and frame counts
+    metrics: Arc<Metrics>,
+
+    // current value of the state fields, to detect changes
+    transitions: Transitions,
 }
 
 impl VeDirectMppt {
-    pub fn new(device_name: &str) -> Self {
+    pub fn new(device_name: &str, metrics: Arc<Metrics>) -> Self {
         Self {
             device_name: device_name.to_string(),
+            metrics,
             points: Default::default(),
-            records: Default::default(),
+            transitions: Default::default(),
         }
     }
-}
-
-impl ParseEvent for VeDirectMppt {
-    fn record(&mut self, label: &str, value: &str) {
-        self.records.push((label.to_string(), value.to_string()));
-    }
 
-    fn checksum_valid(&mut self) {
-        log::info!("{:?}", self.records);
-
-        let mut builder = DataPoint::builder(&self.device_name);
-        for (label, value) in self.records.iter() {
-            let (label, value) = (label.as_str(), value.as_str());
-            match label {
-                "V" => {
-                    if let Ok(v) = u32::from_str_radix(value, 10) {
-                        builder = builder.field("battery_voltage", v as f64 / 1000.0);
-                    }
-                }
-                "VPV" => {
-                    if let Ok(v) = u32::from_str_radix(value, 10) {
-                        builder = builder.field("panel_voltage", v as f64 / 1000.0);
-                    }
-                }
-                "PPV" => {
-                    if let Ok(v) = u16::from_str_radix(value, 10) {
-                        builder = builder.field("panel_power", v as f64);
-                    }
-                }
-                "I" => {
-                    if let Ok(v) = i32::from_str_radix(value, 10) {
-                        builder = builder.field("battery_current", v as f64 / 1000.0);
-                    }
-                }
-                "IL" => {
-                    if let Ok(v) = i32::from_str_radix(value, 10) {
-                        builder = builder.field("load_current", v as f64 / 1000.0);
-                    }
-                }
-                "LOAD" => {
-                    if value == "ON" {
-                        builder = builder.field("load_state", true);
-                    } else if value == "OFF" {
-                        builder = builder.field("load_state", false);
-                    }
-                }
-                "RELAY" => {
-                    if value == "ON" {
-                        builder = builder.field("relay_state", true);
-                    } else if value == "OFF" {
-                        builder = builder.field("relay_state", false);
-                    }
-                }
-                "OR" => {
-                    if let Ok(v) = u32::from_str_radix(&value[2..], 16) {
-                        if let Some(or) = OffReason::from_bits(v) {
-                            builder = builder.field("off_reason", or.to_string());
-                        }
-                    }
-                }
-                "H19" => {
-                    if let Ok(v) = u32::from_str_radix(value, 10) {
-                        builder = builder.field("yield_total", v as f64 * 10.0);
-                    }
-                }
-                "H20" => {
-                    if let Ok(v) = u16::from_str_radix(value, 10) {
-                        builder = builder.field("yield_today", v as f64 * 10.0);
-                    }
-                }
-                "H21" => {
-                    if let Ok(v) = u16::from_str_radix(value, 10) {
-                        builder = builder.field("maximum_power_today", v as f64);
-                    }
-                }
-                "H22" => {
-                    if let Ok(v) = u16::from_str_radix(value, 10) {
-                        builder = builder.field("yield_yesterday", v as f64 * 10.0);
-                    }
-                }
-                "H23" => {
-                    if let Ok(v) = u16::from_str_radix(value, 10) {
-                        builder = builder.field("maximum_power_yesterday", v as f64);
-                    }
-                }
-                "ERR" => {
-                    if let Ok(v) = u32::from_str_radix(value, 10) {
-                        if let Some(err) = ErrorCode::from_u32(v) {
-                            builder = builder.field("error", err.to_string());
-                        }
-                    }
-                }
-                "CS" => {
-                    if let Ok(v) = u32::from_str_radix(value, 10) {
-                        if let Some(cs) = StateOfOperation::from_u32(v) {
-                            builder = builder.field("state", cs.to_string());
-                        }
-                    }
-                }
-                "FW" => {
-                    builder = builder.field("firmware_version", value);
-                }
-                "FWE" => {
-                    builder = builder.field("firmware_version_24", value);
-                }
-                "PID" => {
-                    if let Ok(v) = u32::from_str_radix(&value[2..], 16) {
-                        builder = builder.field("product_id", v as i64);
-                    }
-                }
-                "SER#" => {
-                    builder = builder.field("serial_number", value);
-                }
-                "HSDS" => {
-                    if let Ok(v) = u16::from_str_radix(value, 10) {
-                        builder = builder.field("day_number", v as i64);
-                    }
-                }
-                "MPPT" => {
-                    if let Ok(v) = u32::from_str_radix(value, 10) {
-                        if let Some(mppt) = Mppt::from_u32(v) {
-                            builder = builder.field("mppt_status", mppt.to_string());
-                        }
-                    }
-                }
-                unknown => {
-                    log::warn!("Skipping unknown field {}", unknown);
-                }
-            }
-        }
+    /// Queues the points of a decoded frame and any state changes it carries
+    pub fn frame_received(&mut self, frame: &MpptFrame) {
+        log::info!("{}", frame);
+        self.metrics.frame_decoded();
+        self.update_gauges(frame);
 
-        match builder.build() {
+        match data_point(&self.device_name, frame) {
             Ok(point) => {
                 self.points.push(point);
             }
@@ -207,215 +113,174 @@ impl ParseEvent for VeDirectMppt {
             }
         }
 
-        self.records.clear();
+        let now = Instant::now();
+        for (field, value) in states(frame) {
+            if let Some(transition) = self.transitions.update(field, &value, now) {
+                log::info!("{:?}", transition);
+                match event_point(&self.device_name, transition) {
+                    Ok(point) => self.points.push(point),
+                    Err(err) => log::error!("failed to build event datapoint: {:?}", err),
+                }
+            }
+        }
     }
 
-    fn checksum_invalid(&mut self) {
-        log::warn!("BAD CHECKSUM: {:?}", self.records);
-        self.records.clear();
+    fn update_gauges(&self, frame: &MpptFrame) {
+        let gauges = [
+            (&self.metrics.battery_voltage, frame.battery_voltage),
+            (&self.metrics.battery_current, frame.battery_current),
+            (&self.metrics.panel_voltage, frame.panel_voltage),
+            (&self.metrics.panel_power, frame.panel_power.map(f64::from)),
+            (&self.metrics.yield_today, frame.yield_today.map(f64::from)),
+        ];
+        for (gauge, value) in gauges {
+            if let Some(value) = value {
+                gauge.set(value);
+            }
+        }
     }
 }
 
-bitflags! {
-    pub struct OffReason: u32 {
-        const NONE = 0x0000_0000;
-        const NO_INPUT_POWER = 0x0000_0001;
-        const SWITCHED_OFF_POWER_SWITCH = 0x0000_0002;
-        const SWITCHED_OFF_REGISTER = 0x0000_0004;
-        const REMOTE_INPUT = 0x0000_0008;
-        const PROTECTION_ACTIVE = 0x0000_0010;
-        const PAYGO = 0x0000_0020;
-        const BMS = 0x0000_0040;
-        const ENGINE_SHUTDOWN_DETECTION = 0x0000_0080;
-        const ANALYSING_INPUT_VOLTAGE = 0x0000_0100;
-    }
-}
+/// Point of the device measurement with every field present in the frame
+///
+/// Field names and types match what earlier versions wrote, so existing series keep working.
+fn data_point(
+    device_name: &str,
+    frame: &MpptFrame,
+) -> Result<DataPoint, influxdb2::models::data_point::DataPointError> {
+    let mut builder = DataPoint::builder(device_name);
 
-impl Display for OffReason {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        bitflags::parser::to_writer(self, f)
+    let floats = [
+        ("battery_voltage", frame.battery_voltage),
+        ("panel_voltage", frame.panel_voltage),
+        ("panel_power", frame.panel_power.map(f64::from)),
+        ("battery_current", frame.battery_current),
+        ("load_current", frame.load_current),
+        ("yield_total", frame.yield_total.map(f64::from)),
+        ("yield_today", frame.yield_today.map(f64::from)),
+        (
+            "maximum_power_today",
+            frame.maximum_power_today.map(f64::from),
+        ),
+        ("yield_yesterday", frame.yield_yesterday.map(f64::from)),
+        (
+            "maximum_power_yesterday",
+            frame.maximum_power_yesterday.map(f64::from),
+        ),
+    ];
+    for (name, value) in floats {
+        if let Some(value) = value {
+            builder = builder.field(name, value);
+        }
     }
-}
 
-#[derive(Copy, Clone, Debug, Serialize)]
-pub enum StateOfOperation {
-    Off,
-    LowPower,
-    Fault,
-    Bulk,
-    Absorption,
-    Float,
-    Storage,
-    Equalize,
-    Inverting,
-    PowerSupply,
-    StartingUp,
-    RepeatedAbsorption,
-    AutoEqualize,
-    BatterySafe,
-    ExternalControl,
-}
-
-impl Display for StateOfOperation {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            StateOfOperation::Off => write!(f, "Off"),
-            StateOfOperation::LowPower => write!(f, "Low Power"),
-            StateOfOperation::Fault => write!(f, "Fault"),
-            StateOfOperation::Bulk => write!(f, "Bulk"),
-            StateOfOperation::Absorption => write!(f, "Absorption"),
-            StateOfOperation::Float => write!(f, "Float"),
-            StateOfOperation::Storage => write!(f, "Storage"),
-            StateOfOperation::Equalize => write!(f, "Equalize"),
-            StateOfOperation::Inverting => write!(f, "Inverting"),
-            StateOfOperation::PowerSupply => write!(f, "Power Supply"),
-            StateOfOperation::StartingUp => write!(f, "Starting Up"),
-            StateOfOperation::RepeatedAbsorption => write!(f, "Repeated Absorption"),
-            StateOfOperation::AutoEqualize => write!(f, "Auto Equalize"),
-            StateOfOperation::BatterySafe => write!(f, "Battery Safe"),
-            StateOfOperation::ExternalControl => write!(f, "External Control"),
+    let bools = [
+        ("load_state", frame.load_state),
+        ("relay_state", frame.relay_state),
+    ];
+    for (name, value) in bools {
+        if let Some(value) = value {
+            builder = builder.field(name, value);
         }
     }
-}
 
-impl StateOfOperation {
-    fn from_u32(val: u32) -> Option<Self> {
-        match val {
-            0 => Some(StateOfOperation::Off),
-            1 => Some(StateOfOperation::LowPower),
-            2 => Some(StateOfOperation::Fault),
-            3 => Some(StateOfOperation::Bulk),
-            4 => Some(StateOfOperation::Absorption),
-            5 => Some(StateOfOperation::Float),
-            6 => Some(StateOfOperation::Storage),
-            7 => Some(StateOfOperation::Equalize),
-            9 => Some(StateOfOperation::Inverting),
-            11 => Some(StateOfOperation::PowerSupply),
-            245 => Some(StateOfOperation::StartingUp),
-            246 => Some(StateOfOperation::RepeatedAbsorption),
-            247 => Some(StateOfOperation::AutoEqualize),
-            248 => Some(StateOfOperation::BatterySafe),
-            252 => Some(StateOfOperation::ExternalControl),
-            _ => None,
+    let integers = [
+        ("product_id", frame.product_id.map(i64::from)),
+        ("day_number", frame.day_number.map(i64::from)),
+    ];
+    for (name, value) in integers {
+        if let Some(value) = value {
+            builder = builder.field(name, value);
         }
     }
-}
 
-#[derive(Copy, Clone, Debug, Serialize)]
-pub enum ErrorCode {
-    NoError,
-    BatteryVoltageHigh,
-    ChargerTemperatureHigh,
-    ChargerCurrentHigh,
-    ChargerCurrentReversed,
-    BulkTimeLimit,
-    CurrentSensor,
-    TerminalTemperatureHigh,
-    Converter,
-    InputVoltageHigh,
-    InputCurrentHigh,
-    InputShutdownDueToBatteryVoltage,
-    InputShutdownDueToCurrentFlowWhileOff,
-    LostCommunication,
-    SynchronizedChargingConfiguration,
-    BmsConnectionLost,
-    NetworkMisconfigured,
-    FactoryCalibrationDataLost,
-    InvalidFirmware,
-    InvalidUserSettings,
-}
-
-impl Display for ErrorCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ErrorCode::NoError => write!(f, "No Error"),
-            ErrorCode::BatteryVoltageHigh => write!(f, "Battery Voltage High"),
-            ErrorCode::ChargerTemperatureHigh => write!(f, "Charger Temperature High"),
-            ErrorCode::ChargerCurrentHigh => write!(f, "Charger Current High"),
-            ErrorCode::ChargerCurrentReversed => write!(f, "Charger Current Reversed"),
-            ErrorCode::BulkTimeLimit => write!(f, "Bulk Time Limit"),
-            ErrorCode::CurrentSensor => write!(f, "Current Sensor"),
-            ErrorCode::TerminalTemperatureHigh => write!(f, "Terminal Temperature High"),
-            ErrorCode::Converter => write!(f, "Converter"),
-            ErrorCode::InputVoltageHigh => write!(f, "Input Voltage High"),
-            ErrorCode::InputCurrentHigh => write!(f, "Input Current High"),
-            ErrorCode::InputShutdownDueToBatteryVoltage => {
-                write!(f, "Input Shutdown Due To Battery Voltage")
-            }
-            ErrorCode::InputShutdownDueToCurrentFlowWhileOff => {
-                write!(f, "Input Shutdown Due To Current Flow While Off")
-            }
-            ErrorCode::LostCommunication => write!(f, "Lost Communication"),
-            ErrorCode::SynchronizedChargingConfiguration => {
-                write!(f, "Synchronized Charging Configuration")
-            }
-            ErrorCode::BmsConnectionLost => write!(f, "Bms Connection Lost"),
-            ErrorCode::NetworkMisconfigured => write!(f, "Network Misconfigured"),
-            ErrorCode::FactoryCalibrationDataLost => write!(f, "Factory Calibration Data Lost"),
-            ErrorCode::InvalidFirmware => write!(f, "Invalid Firmware"),
-            ErrorCode::InvalidUserSettings => write!(f, "Invalid User Settings"),
+    let strings = [
+        ("firmware_version", frame.firmware_version.as_deref()),
+        ("firmware_version_24", frame.firmware_version_24.as_deref()),
+        ("serial_number", frame.serial_number.as_deref()),
+    ];
+    for (name, value) in strings {
+        if let Some(value) = value {
+            builder = builder.field(name, value);
         }
     }
-}
 
-impl ErrorCode {
-    fn from_u32(val: u32) -> Option<ErrorCode> {
-        match val {
-            0 => Some(ErrorCode::NoError),
-            2 => Some(ErrorCode::BatteryVoltageHigh),
-            17 => Some(ErrorCode::ChargerTemperatureHigh),
-            18 => Some(ErrorCode::ChargerCurrentHigh),
-            19 => Some(ErrorCode::ChargerCurrentReversed),
-            20 => Some(ErrorCode::BulkTimeLimit),
-            21 => Some(ErrorCode::CurrentSensor),
-            26 => Some(ErrorCode::TerminalTemperatureHigh),
-            28 => Some(ErrorCode::Converter),
-            33 => Some(ErrorCode::InputVoltageHigh),
-            34 => Some(ErrorCode::InputCurrentHigh),
-            38 => Some(ErrorCode::InputShutdownDueToBatteryVoltage),
-            39 => Some(ErrorCode::InputShutdownDueToCurrentFlowWhileOff),
-            65 => Some(ErrorCode::LostCommunication),
-            66 => Some(ErrorCode::SynchronizedChargingConfiguration),
-            67 => Some(ErrorCode::BmsConnectionLost),
-            68 => Some(ErrorCode::NetworkMisconfigured),
-            116 => Some(ErrorCode::FactoryCalibrationDataLost),
-            117 => Some(ErrorCode::InvalidFirmware),
-            119 => Some(ErrorCode::InvalidUserSettings),
-            _ => None,
-        }
+    for (name, value) in states(frame) {
+        builder = builder.field(name, value);
     }
+
+    builder.build()
 }
 
-#[derive(Copy, Clone, Debug, Serialize)]
-pub enum Mppt {
-    Off = 0,
-    VoltageOrCurrentLimited = 1,
-    MpptTrackerActive = 2,
+/// State fields of the frame, which are also recorded as events when they change
+fn states(frame: &MpptFrame) -> Vec<(&'static str, String)> {
+    [
+        ("off_reason", frame.off_reason.map(|v| v.to_string())),
+        ("error", frame.error.map(|v| v.to_string())),
+        ("state", frame.state.map(|v| v.to_string())),
+        ("mppt_status", frame.mppt_status.map(|v| v.to_string())),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|value| (name, value)))
+    .collect()
 }
 
-impl Mppt {
-    fn from_u32(val: u32) -> Option<Mppt> {
-        match val {
-            0 => Some(Mppt::Off),
-            1 => Some(Mppt::VoltageOrCurrentLimited),
-            2 => Some(Mppt::MpptTrackerActive),
-            _ => None,
-        }
-    }
+/// Point of the "event" measurement recording a state change of a device
+fn event_point(
+    device_name: &str,
+    transition: Transition,
+) -> Result<DataPoint, influxdb2::models::data_point::DataPointError> {
+    DataPoint::builder("event")
+        .tag("device", device_name)
+        .tag("field", transition.field)
+        .field("old", transition.old)
+        .field("new", transition.new)
+        .field("duration_secs", transition.duration.as_secs_f64())
+        .build()
 }
 
-impl Display for Mppt {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Mppt::Off => {
-                write!(f, "Off")
-            }
-            Mppt::VoltageOrCurrentLimited => {
-                write!(f, "Voltage Or Current Limited")
-            }
-            Mppt::MpptTrackerActive => {
-                write!(f, "Mppt Tracker Active")
-            }
+#[cfg(test)]
+mod test {
+    use super::{data_point, states};
+    use hab_victron::ve_direct::MpptFrame;
+    use influxdb2::models::WriteDataPoint;
+
+    #[test]
+    fn frame_to_point() {
+        let mut frame = MpptFrame::default();
+        for (label, value) in [
+            ("V", "13250"),
+            ("I", "-1500"),
+            ("PPV", "0"),
+            ("LOAD", "ON"),
+            ("H20", "12"),
+            ("CS", "0"),
+            ("OR", "0X00000001"),
+            ("PID", "0XA053"),
+            ("SER#", "HQ1901YTGE6"),
+        ] {
+            frame.set(label, value).unwrap();
         }
+
+        let mut line = Vec::new();
+        data_point("mppt_lil", &frame)
+            .unwrap()
+            .write_data_point_to(&mut line)
+            .unwrap();
+        assert_eq!(
+            "mppt_lil battery_current=-1.5,battery_voltage=13.25,load_state=t,\
+             off_reason=\"NO_INPUT_POWER\",panel_power=0,product_id=41043i,\
+             serial_number=\"HQ1901YTGE6\",state=\"Off\",yield_today=120\n",
+            String::from_utf8(line).unwrap()
+        );
+
+        assert_eq!(
+            vec![
+                ("off_reason", "NO_INPUT_POWER".to_string()),
+                ("state", "Off".to_string())
+            ],
+            states(&frame)
+        );
+        assert!(data_point("mppt_lil", &MpptFrame::default()).is_err());
     }
 }
@@ -8,6 +8,9 @@ pub struct Config {
     pub influxdb_url: String,
     pub influxdb_org: String,
     pub influxdb_token: String,
+
+    /// Address of the embedded metrics listener, e.g. "0.0.0.0:9102", disabled if not set
+    pub metrics_address: Option<String>,
 }
 
 impl Config {
@@ -41,6 +44,7 @@ impl Config {
             influxdb_url: std::env::var("INFLUXDB_URL")?,
             influxdb_org: std::env::var("INFLUXDB_ORG")?,
             influxdb_token: std::env::var("INFLUXDB_TOKEN")?,
+            metrics_address: std::env::var("METRICS_ADDRESS").ok(),
         })
     }
 }
\ No newline at end of file
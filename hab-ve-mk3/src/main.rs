@@ -1,5 +1,5 @@
 mod config;
-mod decoder;
+mod metrics;
 mod mk3;
 
 use anyhow::Result;
@@ -0,0 +1,28 @@
+//! Victron Energy protocol support shared by the HAB services
+//!
+//! Decodes the VE.Direct text protocol spoken by the MPPT controllers and the MK3 protocol spoken
+//! by the Multiplus into typed frames. Storage and transport are left to the binaries.
+//!
+//! Without the default `std` feature only the VE.Direct parser and frame model are built, which
+//! need neither `std` nor an allocator, for use on the body control module firmware.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+pub mod mk3;
+#[cfg(feature = "std")]
+pub mod transition;
+pub mod ve_direct;
+
+/// Counts kept by a codec, for health reporting
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CodecStats {
+    /// Frames decoded with a valid checksum
+    pub frames: u64,
+
+    /// Frames discarded because of a bad checksum
+    pub checksum_failures: u64,
+
+    /// Times the codec lost track of the frame boundaries and had to search for the next frame
+    pub resyncs: u64,
+}
@@ -0,0 +1,489 @@
+//! Victron MK3 protocol, as spoken by the Multiplus through the MK3-USB interface
+//!
+//! Frames are `<length> <command> <data>... <checksum>`. The codec waits for a version frame, which
+//! the interface sends unprompted, to find the frame boundaries and then decodes frames by length.
+
+use crate::CodecStats;
+use bytes::{Buf, BytesMut};
+use core::fmt::Display;
+use core::num::Wrapping;
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Length of a version frame, which is used to synchronize
+const VERSION_FRAME_LEN: usize = 9;
+
+#[derive(Debug, Default)]
+pub struct VeMk3Codec {
+    synchronized: bool,
+    stats: CodecStats,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Frame {
+    Unknown,
+    Version,
+    LedStatus { led_status: LedStatus },
+    Ac { ac: AcMeasurement },
+    Dc { dc: DcMeasurement },
+}
+
+/// LEDs on the front panel, true if on or blinking
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedStatus {
+    pub mains: bool,
+    pub absorption: bool,
+    pub bulk: bool,
+    pub float: bool,
+    pub inverter: bool,
+    pub overload: bool,
+    pub low_battery: bool,
+    pub temperature: bool,
+}
+
+/// Battery side measurements
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DcMeasurement {
+    pub voltage: f32,
+    pub inverter_current: f32,
+    pub inverter_watts: f32,
+    pub charger_current: f32,
+    pub charger_watts: f32,
+    pub inverter_frequency: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AcState {
+    Down,
+    Startup,
+    Off,
+    Slave,
+    InvertFull,
+    InvertHalf,
+    InvertAes,
+    PowerAssist,
+    Bypass,
+    Charge,
+    Unknown,
+}
+
+impl AcState {
+    /// Name of the state as stored by the collector, e.g. "invert-full"
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AcState::Down => "down",
+            AcState::Startup => "startup",
+            AcState::Off => "off",
+            AcState::Slave => "slave",
+            AcState::InvertFull => "invert-full",
+            AcState::InvertHalf => "invert-half",
+            AcState::InvertAes => "invert-aes",
+            AcState::PowerAssist => "power-assist",
+            AcState::Bypass => "bypass",
+            AcState::Charge => "charge",
+            AcState::Unknown => "unknown",
+        }
+    }
+}
+
+impl Display for AcState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Mains and inverter side measurements of phase L1
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AcMeasurement {
+    pub bf_factor: u8,
+    pub inverter_factor: u8,
+    pub state: AcState,
+    pub mains_voltage: f32,
+    pub mains_current: f32,
+    pub mains_watts: f32,
+    pub inverter_voltage: f32,
+    pub inverter_current: f32,
+    pub inverter_watts: f32,
+    pub mains_frequency: f32,
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "unknown"),
+            Self::Version => write!(f, "version"),
+            Self::LedStatus { led_status } => write!(f, "led: {:?}", led_status),
+            Self::Dc { dc } => write!(f, "dc: {:?}", dc),
+            Self::Ac { ac } => write!(f, "ac: {:?}", ac),
+        }
+    }
+}
+
+impl VeMk3Codec {
+    /// Counts of frames decoded and discarded so far
+    pub fn stats(&self) -> CodecStats {
+        self.stats
+    }
+
+    fn decode_synchronized(&mut self, src: &mut BytesMut) -> Option<Frame> {
+        log::trace!("decode sync buffer: {:?}", &src[..]);
+
+        if src.is_empty() {
+            log::trace!("decode sync, waiting for length byte");
+            return None;
+        }
+
+        let expected_len: usize = usize::from(src[0]) + 2;
+
+        if src.len() < expected_len {
+            log::trace!("decode sync, waiting for expected length");
+            None
+        } else if !checksum_ok(&src[0..expected_len]) {
+            // lost track of the frame boundaries, search for the next version frame
+            log::debug!("decode sync, bad checksum, resynchronizing");
+            self.stats.checksum_failures += 1;
+            self.stats.resyncs += 1;
+            self.synchronized = false;
+            self.decode_unsynchronized(src)
+        } else {
+            let frame = if src[1] == 0xff && src[2] == 0x56 {
+                // version frame
+                Some(Frame::Version)
+            } else if src[1] == 0xff && src[2] == 0x4c && expected_len >= 6 {
+                // led frame
+                let active = src[3] | src[4]; // either on, or blinking
+
+                Some(Frame::LedStatus {
+                    led_status: LedStatus {
+                        mains: active & 0x01 != 0,
+                        absorption: active & 0x02 != 0,
+                        bulk: active & 0x04 != 0,
+                        float: active & 0x08 != 0,
+                        inverter: active & 0x10 != 0,
+                        overload: active & 0x20 != 0,
+                        low_battery: active & 0x40 != 0,
+                        temperature: active & 0x80 != 0,
+                    },
+                })
+            } else if src[1] == 0x20 && expected_len >= 16 {
+                Some(decode_info_frame(&src[2..16]))
+            } else {
+                None
+            };
+            src.advance(expected_len);
+            frame
+        }
+    }
+
+    fn decode_unsynchronized(&mut self, src: &mut BytesMut) -> Option<Frame> {
+        // wait for a version frame
+        // NOTE: future versions could be longer which will break this logic
+        log::trace!("decode unsync buffer: {:?}", &src[..]);
+
+        if src.len() < VERSION_FRAME_LEN {
+            log::trace!("decode unsync, waiting for enough bytes");
+            None
+        } else if src[0] == 0x07
+            && src[1] == 0xff
+            && src[2] == 0x56
+            && checksum_ok(&src[0..VERSION_FRAME_LEN])
+        {
+            log::trace!("decode unsync version frame");
+            // received a version frame, now synced
+            self.synchronized = true;
+            src.advance(VERSION_FRAME_LEN);
+            Some(Frame::Version)
+        } else {
+            // not a version frame, consume everything up to the next 0x07 (or the end),
+            // skipping the one at index 0 as it didn't match
+            match src.iter().skip(1).position(|v| *v == 0x07) {
+                Some(index) => {
+                    log::trace!("decode unsync discarded {} to next potential", index + 1);
+                    src.advance(index + 1);
+                }
+                None => {
+                    log::trace!("decode unsync discarded entire buffer");
+                    src.advance(src.len());
+                }
+            }
+
+            None
+        }
+    }
+}
+
+fn decode_info_frame(d: &[u8]) -> Frame {
+    let phase_info = d[4];
+    if phase_info == 0x0c {
+        // DC
+        let voltage = ((d[6] as f32) * 256.0 + (d[5] as f32)) / 100.0;
+        let inverter_current =
+            ((d[9] as f32) * 65536.0 + (d[8] as f32) * 256.0 + (d[7] as f32)) / 10.0;
+        let charger_current =
+            ((d[12] as f32) * 65536.0 + (d[11] as f32) * 256.0 + (d[10] as f32)) / 10.0;
+
+        Frame::Dc {
+            dc: DcMeasurement {
+                voltage,
+                inverter_current,
+                inverter_watts: voltage * inverter_current,
+                charger_current,
+                charger_watts: voltage * charger_current,
+                inverter_frequency: 10000.0 / (d[13] as f32),
+            },
+        }
+    } else if (0x05..=0x0b).contains(&phase_info) {
+        // AC
+        let state = match d[3] {
+            0x00 => AcState::Down,
+            0x01 => AcState::Startup,
+            0x02 => AcState::Off,
+            0x03 => AcState::Slave,
+            0x04 => AcState::InvertFull,
+            0x05 => AcState::InvertHalf,
+            0x06 => AcState::InvertAes,
+            0x07 => AcState::PowerAssist,
+            0x08 => AcState::Bypass,
+            0x09 => AcState::Charge,
+            _ => AcState::Unknown,
+        };
+
+        let mains_voltage = ((d[6] as f32) * 256.0 + (d[5] as f32)) / 100.0;
+        let mains_current = ((d[8] as f32) * 256.0 + (d[7] as f32)) / 100.0;
+        let inverter_voltage = ((d[10] as f32) * 256.0 + (d[9] as f32)) / 100.0;
+        let inverter_current = ((d[12] as f32) * 256.0 + (d[11] as f32)) / 100.0;
+
+        Frame::Ac {
+            ac: AcMeasurement {
+                bf_factor: d[0],
+                inverter_factor: d[1],
+                state,
+                mains_voltage,
+                mains_current,
+                mains_watts: mains_voltage * mains_current,
+                inverter_voltage,
+                inverter_current,
+                inverter_watts: inverter_voltage * inverter_current,
+                mains_frequency: 10000.0 / (d[13] as f32),
+            },
+        }
+    } else {
+        Frame::Unknown
+    }
+}
+
+impl Decoder for VeMk3Codec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        log::trace!("decode buffer is {} bytes", src.len());
+
+        // frames of unknown commands are skipped without ending the decode
+        while !src.is_empty() {
+            let len = src.len();
+            let frame = match self.synchronized {
+                true => self.decode_synchronized(src),
+                false => self.decode_unsynchronized(src),
+            };
+
+            if let Some(frame) = frame {
+                self.stats.frames += 1;
+                return Ok(Some(frame));
+            }
+            if src.len() == len {
+                // waiting for more input
+                break;
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn checksum_ok(src: &[u8]) -> bool {
+    let mut checksum: Wrapping<u8> = Wrapping(0);
+
+    for v in src.iter() {
+        checksum += Wrapping(*v);
+    }
+
+    checksum == Wrapping(0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestFrame {
+    Version,
+    LedStatus,
+    DcStatus,
+    AcL1Status,
+}
+
+impl Encoder<RequestFrame> for VeMk3Codec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: RequestFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let request: &[u8] = match item {
+            RequestFrame::Version => &[0x02, 0xff, 0x56, 0xa9],
+            RequestFrame::LedStatus => &[0x02, 0xff, 0x4c, 0xb3],
+            RequestFrame::DcStatus => &[0x03, 0xff, 0x46, 0x00, 0xb8],
+            RequestFrame::AcL1Status => &[0x03, 0xff, 0x46, 0x01, 0xb7],
+        };
+        dst.extend_from_slice(request);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{checksum_ok, AcState, Frame, LedStatus, RequestFrame, VeMk3Codec};
+    use crate::CodecStats;
+    use bytes::BytesMut;
+    use futures::TryStreamExt;
+    use std::io::Cursor;
+    use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+    /// Appends the checksum to a frame
+    fn with_checksum(frame: &[u8]) -> Vec<u8> {
+        let sum = frame.iter().fold(0u8, |sum, v| sum.wrapping_add(*v));
+        let mut frame = frame.to_vec();
+        frame.push(sum.wrapping_neg());
+        frame
+    }
+
+    #[test]
+    fn decode_after_sync() {
+        let version = with_checksum(&[0x07, 0xff, 0x56, 0x24, 0xdb, 0x11, 0x00, 0x42]);
+        let dc = with_checksum(&[
+            0x0f, 0x20, 0x01, 0x01, 0x01, 0x00, 0x0c, 0x50, 0x05, 0x00, 0x00, 0x00, 0x96, 0x00,
+            0x00, 0xc8,
+        ]);
+        let ac = with_checksum(&[
+            0x0f, 0x20, 0x01, 0x01, 0x00, 0x09, 0x08, 0x18, 0x2e, 0x00, 0x00, 0x18, 0x2e, 0x64,
+            0x00, 0xc8,
+        ]);
+
+        let mut src = BytesMut::from(&[0x42, 0x00][..]);
+        src.extend_from_slice(&version);
+        src.extend_from_slice(&dc);
+        src.extend_from_slice(&ac[..4]);
+
+        let mut codec = VeMk3Codec::default();
+        assert_eq!(Some(Frame::Version), codec.decode(&mut src).unwrap());
+
+        match codec.decode(&mut src).unwrap() {
+            Some(Frame::Dc { dc }) => {
+                assert_eq!(13.6, dc.voltage);
+                assert_eq!(15.0, dc.charger_current);
+            }
+            frame => panic!("expected dc frame, got {:?}", frame),
+        }
+
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        src.extend_from_slice(&ac[4..]);
+        match codec.decode(&mut src).unwrap() {
+            Some(Frame::Ac { ac }) => {
+                assert_eq!(AcState::Charge, ac.state);
+                assert_eq!("charge", ac.state.to_string());
+                assert_eq!(118.0, ac.mains_voltage);
+                assert_eq!(1.0, ac.inverter_current);
+            }
+            frame => panic!("expected ac frame, got {:?}", frame),
+        }
+
+        // a corrupted frame drops synchronization until the next version frame
+        let mut corrupted = dc.clone();
+        corrupted[8] ^= 0x10;
+        src.extend_from_slice(&corrupted);
+        src.extend_from_slice(&version);
+        assert_eq!(Some(Frame::Version), codec.decode(&mut src).unwrap());
+        assert!(src.is_empty());
+        assert_eq!(
+            CodecStats {
+                frames: 4,
+                checksum_failures: 1,
+                resyncs: 1
+            },
+            codec.stats()
+        );
+    }
+
+    #[test]
+    fn decode_led_status() {
+        let version = with_checksum(&[0x07, 0xff, 0x56, 0x24, 0xdb, 0x11, 0x00, 0x42]);
+        // mains and float on, absorption blinking
+        let led = with_checksum(&[0x04, 0xff, 0x4c, 0x09, 0x02]);
+        // led frame without its on and blink bytes
+        let truncated = with_checksum(&[0x02, 0xff, 0x4c]);
+
+        let mut src = BytesMut::from(&version[..]);
+        src.extend_from_slice(&led);
+        src.extend_from_slice(&truncated);
+        src.extend_from_slice(&led);
+        src.extend_from_slice(&truncated);
+
+        let mut codec = VeMk3Codec::default();
+        assert_eq!(Some(Frame::Version), codec.decode(&mut src).unwrap());
+
+        let expected = Frame::LedStatus {
+            led_status: LedStatus {
+                mains: true,
+                absorption: true,
+                bulk: false,
+                float: true,
+                inverter: false,
+                overload: false,
+                low_battery: false,
+                temperature: false,
+            },
+        };
+        assert_eq!(Some(expected.clone()), codec.decode(&mut src).unwrap());
+
+        // a truncated frame is skipped without taking the next frame's bytes, or reading past
+        // the end of the buffer
+        assert_eq!(Some(expected), codec.decode(&mut src).unwrap());
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        assert!(src.is_empty());
+        assert_eq!(3, codec.stats().frames);
+    }
+
+    #[tokio::test]
+    async fn decode_captured() {
+        let input = std::include_bytes!("../test/multiplus-capture.bin");
+
+        let mut reader = FramedRead::new(Cursor::new(input), VeMk3Codec::default());
+        let mut frames: Vec<Frame> = Vec::new();
+        while let Some(frame) = reader.try_next().await.unwrap() {
+            frames.push(frame);
+        }
+
+        // the interface announces itself with version frames until requests are sent
+        assert_eq!(280, frames.len());
+        assert!(frames.iter().all(|frame| *frame == Frame::Version));
+        assert_eq!(
+            CodecStats {
+                frames: 280,
+                checksum_failures: 0,
+                resyncs: 0
+            },
+            reader.decoder().stats()
+        );
+    }
+
+    #[test]
+    fn encode_requests() {
+        let mut codec = VeMk3Codec::default();
+        for request in [
+            RequestFrame::Version,
+            RequestFrame::LedStatus,
+            RequestFrame::DcStatus,
+            RequestFrame::AcL1Status,
+        ] {
+            let mut dst = BytesMut::new();
+            codec.encode(request, &mut dst).unwrap();
+            assert!(checksum_ok(&dst[..]), "{:?}", request);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Detection of changes in state fields such as the charge state or error code
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// A state field changed value
+#[derive(Debug, PartialEq)]
+pub struct Transition {
+    /// Field that changed, e.g. "state"
+    pub field: String,
+    pub old: String,
+    pub new: String,
+
+    /// Time spent in the old state, counted from startup if it was the first state seen
+    pub duration: Duration,
+}
+
+/// Latest value of each state field and when it was entered
+#[derive(Debug, Default)]
+pub struct Transitions {
+    states: HashMap<String, (String, Instant)>,
+}
+
+impl Transitions {
+    /// Records the current value of `field`, returning the transition if it changed
+    ///
+    /// The first value seen for a field is not a transition, as the previous state is unknown.
+    pub fn update(&mut self, field: &str, value: &str, now: Instant) -> Option<Transition> {
+        match self.states.get_mut(field) {
+            None => {
+                self.states
+                    .insert(field.to_string(), (value.to_string(), now));
+                None
+            }
+            Some((old, _)) if old == value => None,
+            Some((old, since)) => {
+                let transition = Transition {
+                    field: field.to_string(),
+                    old: std::mem::replace(old, value.to_string()),
+                    new: value.to_string(),
+                    duration: now.duration_since(*since),
+                };
+                *since = now;
+                Some(transition)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Transitions;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn reports_changes_only() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut transitions = Transitions::default();
+
+        assert_eq!(None, transitions.update("state", "Bulk", at(0)));
+        assert_eq!(None, transitions.update("state", "Bulk", at(10)));
+        assert_eq!(None, transitions.update("error", "No Error", at(10)));
+
+        let transition = transitions.update("state", "Absorption", at(30)).unwrap();
+        assert_eq!("Bulk", transition.old);
+        assert_eq!("Absorption", transition.new);
+        assert_eq!(Duration::from_secs(30), transition.duration);
+
+        let transition = transitions.update("state", "Float", at(90)).unwrap();
+        assert_eq!("Absorption", transition.old);
+        assert_eq!(Duration::from_secs(60), transition.duration);
+    }
+}
@@ -0,0 +1,19 @@
+//! Victron VE.Direct text protocol
+//!
+//! The MPPT controllers send a block of `<label>\t<value>\r\n` records about once a second, ending
+//! with a checksum record. HEX protocol messages starting with ':' may be interleaved and are
+//! skipped.
+
+#[cfg(feature = "std")]
+mod codec;
+mod decoder;
+mod frame;
+mod parser;
+
+#[cfg(feature = "std")]
+pub use codec::VeDirectCodec;
+pub use decoder::FrameDecoder;
+pub use frame::{
+    ErrorCode, FrameError, Label, Mppt, MpptFrame, OffReason, StateOfOperation, Value,
+};
+pub use parser::{ParseError, ParseEvent, ParseState, Parser};
@@ -0,0 +1,217 @@
+//! Decoder of VE.Direct text blocks into frames
+
+use super::decoder::FrameDecoder;
+use super::frame::MpptFrame;
+use crate::CodecStats;
+use bytes::{Buf, BytesMut};
+use std::io;
+use tokio_util::codec::Decoder;
+
+/// Decodes the byte stream of an MPPT controller into one frame per valid block
+///
+/// Wraps [`FrameDecoder`] for use with tokio's `FramedRead`.
+#[derive(Debug, Default)]
+pub struct VeDirectCodec {
+    decoder: FrameDecoder,
+}
+
+impl VeDirectCodec {
+    /// Counts of frames decoded and discarded so far
+    pub fn stats(&self) -> CodecStats {
+        self.decoder.stats()
+    }
+}
+
+impl Decoder for VeDirectCodec {
+    type Item = MpptFrame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while src.has_remaining() {
+            if let Some(frame) = self.decoder.decode_byte(src.get_u8()) {
+                return Ok(Some(frame));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // a partial block at the end of the stream is dropped
+        self.decode(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::VeDirectCodec;
+    use crate::ve_direct::{MpptFrame, StateOfOperation};
+    use crate::CodecStats;
+    use bytes::BytesMut;
+    use futures::TryStreamExt;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use std::io::Cursor;
+    use tokio_util::codec::{Decoder, FramedRead};
+
+    const CAPTURE: &[u8] =
+        std::include_bytes!("../../test/usb-VictronEnergy_BV_VE_Direct_cable_VE46V0KW-if00-port0");
+
+    /// Decodes a capture, returning the frames and the final counts
+    async fn decode_all(input: &[u8]) -> (Vec<MpptFrame>, CodecStats) {
+        let mut reader = FramedRead::new(Cursor::new(input), VeDirectCodec::default());
+        let mut frames: Vec<MpptFrame> = Vec::new();
+        while let Some(frame) = reader.try_next().await.unwrap() {
+            frames.push(frame);
+        }
+
+        (frames, reader.decoder().stats())
+    }
+
+    #[tokio::test]
+    async fn decode_captured() {
+        // each capture starts part way through a block, which fails its checksum
+        let captures: [(&[u8], u64); 2] = [
+            (CAPTURE, 298),
+            (
+                std::include_bytes!(
+                    "../../test/usb-VictronEnergy_BV_VE_Direct_cable_VE47E73U-if00-port0"
+                ),
+                304,
+            ),
+        ];
+
+        for (input, expected) in captures {
+            let (frames, stats) = decode_all(input).await;
+            assert_eq!(expected, frames.len() as u64);
+            assert!(frames.iter().all(|frame| frame.battery_voltage.is_some()));
+            assert_eq!(
+                CodecStats {
+                    frames: expected,
+                    checksum_failures: 1,
+                    resyncs: 0
+                },
+                stats
+            );
+        }
+    }
+
+    #[test]
+    fn decode_split_input() {
+        let block = b"\r\nV\t13250\r\nCS\t5\r\nBMV\t712\r\nChecksum\t";
+        let checksum = block
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b))
+            .wrapping_neg();
+
+        let mut codec = VeDirectCodec::default();
+        let mut src = BytesMut::from(&block[..20]);
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+
+        // the checksum is not followed by CRLF when a HEX message comes next
+        src.extend_from_slice(&block[20..]);
+        src.extend_from_slice(&[checksum, b':']);
+        let frame = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(Some(13.25), frame.battery_voltage);
+        assert_eq!(Some(StateOfOperation::Float), frame.state);
+        assert_eq!(&b":"[..], &src[..]);
+
+        // a corrupted copy of the block is dropped
+        let mut src = BytesMut::from(&block[..]);
+        src.extend_from_slice(&[checksum.wrapping_add(1)]);
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        assert_eq!(
+            CodecStats {
+                frames: 1,
+                checksum_failures: 1,
+                resyncs: 0
+            },
+            codec.stats()
+        );
+    }
+
+    /// Feeds the input in chunks of the given sizes, as reads from the serial port would arrive
+    fn decode_chunked(input: &[u8], chunks: &[usize]) -> (Vec<MpptFrame>, CodecStats) {
+        let mut codec = VeDirectCodec::default();
+        let mut src = BytesMut::new();
+        let mut frames = Vec::new();
+
+        let mut rest = input;
+        for size in chunks.iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (chunk, tail) = rest.split_at((*size).min(rest.len()));
+            rest = tail;
+
+            src.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut src).unwrap() {
+                frames.push(frame);
+            }
+        }
+
+        (frames, codec.stats())
+    }
+
+    /// Inserts HEX messages at the given positions, except in place of a checksum byte or inside
+    /// a HEX message already in the input
+    fn interleave(input: &[u8], messages: &[(Index, String)]) -> Vec<u8> {
+        let checksum_bytes: Vec<usize> = input
+            .windows(9)
+            .enumerate()
+            .filter(|(_, label)| label.eq_ignore_ascii_case(b"Checksum\t"))
+            .map(|(index, _)| index + 9)
+            .collect();
+
+        let mut hex_messages = Vec::new();
+        let mut index = 0;
+        while index < input.len() {
+            if input[index] == b':' && !checksum_bytes.contains(&index) {
+                let end = input[index..]
+                    .iter()
+                    .position(|b| *b == b'\n')
+                    .map_or(input.len(), |len| index + len);
+                hex_messages.push(index + 1..=end);
+                index = end;
+            }
+            index += 1;
+        }
+
+        let mut positions: Vec<(usize, &str)> = messages
+            .iter()
+            .map(|(index, message)| (index.index(input.len()), message.as_str()))
+            .filter(|(position, _)| {
+                !checksum_bytes.contains(position)
+                    && !hex_messages.iter().any(|span| span.contains(position))
+            })
+            .collect();
+        positions.sort();
+
+        let mut output = Vec::new();
+        let mut last = 0;
+        for (position, message) in positions {
+            output.extend_from_slice(&input[last..position]);
+            output.push(b':');
+            output.extend_from_slice(message.as_bytes());
+            output.push(b'\n');
+            last = position;
+        }
+        output.extend_from_slice(&input[last..]);
+        output
+    }
+
+    proptest! {
+        #[test]
+        fn decode_any_chunks_and_interleaving(
+            chunks in vec(1..200usize, 1..16),
+            messages in vec((any::<Index>(), "[0-9A-F]{1,64}"), 0..32),
+        ) {
+            let (expected, expected_stats) = decode_chunked(CAPTURE, &[CAPTURE.len()]);
+            let (frames, stats) = decode_chunked(&interleave(CAPTURE, &messages), &chunks);
+
+            prop_assert_eq!(expected, frames);
+            prop_assert_eq!(expected_stats, stats);
+        }
+    }
+}
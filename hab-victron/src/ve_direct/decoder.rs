@@ -0,0 +1,109 @@
+//! Push decoder of VE.Direct text blocks into frames, without `std` or an allocator
+
+use super::frame::MpptFrame;
+use super::parser::{ParseEvent, Parser};
+use crate::CodecStats;
+
+/// Decodes the byte stream of an MPPT controller into one frame per valid block
+///
+/// Blocks with a bad checksum are dropped, records with unknown labels or unparseable values are
+/// skipped, and malformed input restarts the parser at the next record.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    parser: Parser,
+    collector: Collector,
+    stats: CodecStats,
+}
+
+impl FrameDecoder {
+    /// Feeds one received byte, returning the frame if it completed a valid block
+    pub fn decode_byte(&mut self, byte: u8) -> Option<MpptFrame> {
+        if let Err(err) = self.parser.parse_input_byte(&mut self.collector, byte) {
+            log::debug!("resynchronizing after parse error: {}", err);
+            self.stats.resyncs += 1;
+            self.parser = Parser::default();
+            self.collector.frame = MpptFrame::default();
+        }
+
+        if self.collector.checksum_failed {
+            self.collector.checksum_failed = false;
+            self.stats.checksum_failures += 1;
+        }
+
+        let frame = self.collector.complete.take();
+        if frame.is_some() {
+            self.stats.frames += 1;
+        }
+        frame
+    }
+
+    /// Counts of frames decoded and discarded so far
+    pub fn stats(&self) -> CodecStats {
+        self.stats
+    }
+}
+
+/// Builds a frame from the records of the current block
+#[derive(Debug, Default)]
+struct Collector {
+    frame: MpptFrame,
+    complete: Option<MpptFrame>,
+    checksum_failed: bool,
+}
+
+impl ParseEvent for Collector {
+    fn record(&mut self, label: &str, value: &str) {
+        if let Err(err) = self.frame.set(label, value) {
+            log::debug!("skipping record: {}", err);
+        }
+    }
+
+    fn checksum_valid(&mut self) {
+        self.complete = Some(core::mem::take(&mut self.frame));
+    }
+
+    fn checksum_invalid(&mut self) {
+        self.frame = MpptFrame::default();
+        self.checksum_failed = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FrameDecoder;
+    use crate::CodecStats;
+
+    #[test]
+    fn resync_after_overlong_value() {
+        let block = b"\r\nV\t13250\r\nChecksum\t";
+        let checksum = block
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b))
+            .wrapping_neg();
+
+        let mut decoder = FrameDecoder::default();
+        let garbage = [b'\n', b'V', b'\t'].into_iter().chain([b'1'; 40]);
+        assert!(garbage
+            .filter_map(|b| decoder.decode_byte(b))
+            .next()
+            .is_none());
+
+        // the rest of the garbled block fails its checksum, the next one decodes
+        let frames: Vec<_> = [block.as_slice(), &[checksum]]
+            .concat()
+            .repeat(2)
+            .into_iter()
+            .filter_map(|b| decoder.decode_byte(b))
+            .collect();
+        assert_eq!(1, frames.len());
+        assert_eq!(Some(13.25), frames[0].battery_voltage);
+        assert_eq!(
+            CodecStats {
+                frames: 1,
+                checksum_failures: 1,
+                resyncs: 1
+            },
+            decoder.stats()
+        );
+    }
+}
@@ -0,0 +1,487 @@
+//! Typed model of the VE.Direct block sent by the MPPT controllers
+
+use super::parser::{LABEL_LEN, VALUE_LEN};
+use arrayvec::ArrayString;
+use bitflags::bitflags;
+use core::fmt::Display;
+use serde::{Deserialize, Serialize};
+
+/// Record label, sized to the longest label the protocol allows
+pub type Label = ArrayString<LABEL_LEN>;
+
+/// Record value, sized to the longest value the protocol allows
+pub type Value = ArrayString<VALUE_LEN>;
+
+/// Latest values of one block of records, in SI units
+///
+/// Fields are `None` until a record for them is received; not every model sends every record.
+/// Text fields are fixed capacity so frames can be decoded without an allocator.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MpptFrame {
+    /// V: Battery voltage (V)
+    pub battery_voltage: Option<f64>,
+
+    /// VPV: Panel voltage (V)
+    pub panel_voltage: Option<f64>,
+
+    /// PPV: Panel power (W)
+    pub panel_power: Option<u16>,
+
+    /// I: Battery current (A): >0 charging, <0 discharging
+    pub battery_current: Option<f64>,
+
+    /// IL: Load current (A)
+    pub load_current: Option<f64>,
+
+    /// LOAD: Load output state
+    pub load_state: Option<bool>,
+
+    /// RELAY: Relay state
+    pub relay_state: Option<bool>,
+
+    /// OR: Off reason
+    pub off_reason: Option<OffReason>,
+
+    /// H19: Yield total (Wh)
+    pub yield_total: Option<u32>,
+
+    /// H20: Yield today (Wh)
+    pub yield_today: Option<u32>,
+
+    /// H21: Maximum power today (W)
+    pub maximum_power_today: Option<u16>,
+
+    /// H22: Yield yesterday (Wh)
+    pub yield_yesterday: Option<u32>,
+
+    /// H23: Maximum power yesterday (W)
+    pub maximum_power_yesterday: Option<u16>,
+
+    /// ERR: Error code
+    pub error: Option<ErrorCode>,
+
+    /// CS: State of operation
+    pub state: Option<StateOfOperation>,
+
+    /// FW: Firmware version. Whole number, potentially prefixed by a letter
+    pub firmware_version: Option<Value>,
+
+    /// FWE: Firmware version, 24 bit
+    pub firmware_version_24: Option<Value>,
+
+    /// PID: Product Id
+    pub product_id: Option<u32>,
+
+    /// SER#: Serial number
+    /// LLYYMMSSSSS - LL location, YYWW production data, SSSSS unique id
+    pub serial_number: Option<Value>,
+
+    /// HSDS: Historical day sequence number 0..364
+    pub day_number: Option<u16>,
+
+    /// MPPT: Tracker operation mode
+    pub mppt_status: Option<Mppt>,
+}
+
+/// A record that could not be applied to a frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// Label of a record this model doesn't know about
+    UnknownLabel(Label),
+
+    /// Value that couldn't be parsed for its label
+    InvalidValue { label: Label, value: Value },
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FrameError::UnknownLabel(label) => write!(f, "unknown label {}", label),
+            FrameError::InvalidValue { label, value } => {
+                write!(f, "invalid value {:?} for {}", value, label)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FrameError {}
+
+impl MpptFrame {
+    /// Sets the field of a record, as received from the parser with an upper case label
+    pub fn set(&mut self, label: &str, value: &str) -> Result<(), FrameError> {
+        let invalid = || FrameError::InvalidValue {
+            label: truncated(label),
+            value: truncated(value),
+        };
+        let text = |value: &str| Value::from(value).map_err(|_| invalid());
+        let on_off = |value: &str| match value {
+            "ON" => Ok(true),
+            "OFF" => Ok(false),
+            _ => Err(invalid()),
+        };
+        let hex = |value: &str| {
+            value
+                .get(2..)
+                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                .ok_or_else(invalid)
+        };
+
+        match label {
+            "V" => {
+                let v: u32 = value.parse().map_err(|_| invalid())?;
+                self.battery_voltage = Some(v as f64 / 1000.0);
+            }
+            "VPV" => {
+                let v: u32 = value.parse().map_err(|_| invalid())?;
+                self.panel_voltage = Some(v as f64 / 1000.0);
+            }
+            "PPV" => self.panel_power = Some(value.parse().map_err(|_| invalid())?),
+            "I" => {
+                let v: i32 = value.parse().map_err(|_| invalid())?;
+                self.battery_current = Some(v as f64 / 1000.0);
+            }
+            "IL" => {
+                let v: i32 = value.parse().map_err(|_| invalid())?;
+                self.load_current = Some(v as f64 / 1000.0);
+            }
+            "LOAD" => self.load_state = Some(on_off(value)?),
+            "RELAY" => self.relay_state = Some(on_off(value)?),
+            "OR" => self.off_reason = Some(OffReason::from_bits(hex(value)?).ok_or_else(invalid)?),
+            "H19" => {
+                let v: u32 = value.parse().map_err(|_| invalid())?;
+                self.yield_total = Some(v.checked_mul(10).ok_or_else(invalid)?);
+            }
+            "H20" => {
+                let v: u32 = value.parse().map_err(|_| invalid())?;
+                self.yield_today = Some(v.checked_mul(10).ok_or_else(invalid)?);
+            }
+            "H21" => self.maximum_power_today = Some(value.parse().map_err(|_| invalid())?),
+            "H22" => {
+                let v: u32 = value.parse().map_err(|_| invalid())?;
+                self.yield_yesterday = Some(v.checked_mul(10).ok_or_else(invalid)?);
+            }
+            "H23" => self.maximum_power_yesterday = Some(value.parse().map_err(|_| invalid())?),
+            "ERR" => {
+                let v: u32 = value.parse().map_err(|_| invalid())?;
+                self.error = Some(ErrorCode::from_u32(v).ok_or_else(invalid)?);
+            }
+            "CS" => {
+                let v: u32 = value.parse().map_err(|_| invalid())?;
+                self.state = Some(StateOfOperation::from_u32(v).ok_or_else(invalid)?);
+            }
+            "FW" => self.firmware_version = Some(text(value)?),
+            "FWE" => self.firmware_version_24 = Some(text(value)?),
+            "PID" => self.product_id = Some(hex(value)?),
+            "SER#" => self.serial_number = Some(text(value)?),
+            "HSDS" => self.day_number = Some(value.parse().map_err(|_| invalid())?),
+            "MPPT" => {
+                let v: u32 = value.parse().map_err(|_| invalid())?;
+                self.mppt_status = Some(Mppt::from_u32(v).ok_or_else(invalid)?);
+            }
+            unknown => return Err(FrameError::UnknownLabel(truncated(unknown))),
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies as much of `s` as fits, for error reporting
+fn truncated<const N: usize>(s: &str) -> ArrayString<N> {
+    let mut out = ArrayString::new();
+    for c in s.chars() {
+        if out.try_push(c).is_err() {
+            break;
+        }
+    }
+    out
+}
+
+impl Display for MpptFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "VPV {:?} PPV {:?} V {:?} I {:?} H20 {:?} H21 {:?} CS {:?} MPPT {:?}",
+            self.panel_voltage,
+            self.panel_power,
+            self.battery_voltage,
+            self.battery_current,
+            self.yield_today,
+            self.maximum_power_today,
+            self.state,
+            self.mppt_status,
+        )
+    }
+}
+
+bitflags! {
+    /// OR: Reasons the charger is off
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct OffReason: u32 {
+        const NONE = 0x0000_0000;
+        const NO_INPUT_POWER = 0x0000_0001;
+        const SWITCHED_OFF_POWER_SWITCH = 0x0000_0002;
+        const SWITCHED_OFF_REGISTER = 0x0000_0004;
+        const REMOTE_INPUT = 0x0000_0008;
+        const PROTECTION_ACTIVE = 0x0000_0010;
+        const PAYGO = 0x0000_0020;
+        const BMS = 0x0000_0040;
+        const ENGINE_SHUTDOWN_DETECTION = 0x0000_0080;
+        const ANALYSING_INPUT_VOLTAGE = 0x0000_0100;
+    }
+}
+
+impl Display for OffReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        bitflags::parser::to_writer(self, f)
+    }
+}
+
+/// CS: State of operation
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StateOfOperation {
+    Off,
+    LowPower,
+    Fault,
+    Bulk,
+    Absorption,
+    Float,
+    Storage,
+    Equalize,
+    Inverting,
+    PowerSupply,
+    StartingUp,
+    RepeatedAbsorption,
+    AutoEqualize,
+    BatterySafe,
+    ExternalControl,
+}
+
+impl Display for StateOfOperation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StateOfOperation::Off => write!(f, "Off"),
+            StateOfOperation::LowPower => write!(f, "Low Power"),
+            StateOfOperation::Fault => write!(f, "Fault"),
+            StateOfOperation::Bulk => write!(f, "Bulk"),
+            StateOfOperation::Absorption => write!(f, "Absorption"),
+            StateOfOperation::Float => write!(f, "Float"),
+            StateOfOperation::Storage => write!(f, "Storage"),
+            StateOfOperation::Equalize => write!(f, "Equalize"),
+            StateOfOperation::Inverting => write!(f, "Inverting"),
+            StateOfOperation::PowerSupply => write!(f, "Power Supply"),
+            StateOfOperation::StartingUp => write!(f, "Starting Up"),
+            StateOfOperation::RepeatedAbsorption => write!(f, "Repeated Absorption"),
+            StateOfOperation::AutoEqualize => write!(f, "Auto Equalize"),
+            StateOfOperation::BatterySafe => write!(f, "Battery Safe"),
+            StateOfOperation::ExternalControl => write!(f, "External Control"),
+        }
+    }
+}
+
+impl StateOfOperation {
+    pub fn from_u32(val: u32) -> Option<Self> {
+        match val {
+            0 => Some(StateOfOperation::Off),
+            1 => Some(StateOfOperation::LowPower),
+            2 => Some(StateOfOperation::Fault),
+            3 => Some(StateOfOperation::Bulk),
+            4 => Some(StateOfOperation::Absorption),
+            5 => Some(StateOfOperation::Float),
+            6 => Some(StateOfOperation::Storage),
+            7 => Some(StateOfOperation::Equalize),
+            9 => Some(StateOfOperation::Inverting),
+            11 => Some(StateOfOperation::PowerSupply),
+            245 => Some(StateOfOperation::StartingUp),
+            246 => Some(StateOfOperation::RepeatedAbsorption),
+            247 => Some(StateOfOperation::AutoEqualize),
+            248 => Some(StateOfOperation::BatterySafe),
+            252 => Some(StateOfOperation::ExternalControl),
+            _ => None,
+        }
+    }
+}
+
+/// ERR: Error code
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    NoError,
+    BatteryVoltageHigh,
+    ChargerTemperatureHigh,
+    ChargerCurrentHigh,
+    ChargerCurrentReversed,
+    BulkTimeLimit,
+    CurrentSensor,
+    TerminalTemperatureHigh,
+    Converter,
+    InputVoltageHigh,
+    InputCurrentHigh,
+    InputShutdownDueToBatteryVoltage,
+    InputShutdownDueToCurrentFlowWhileOff,
+    LostCommunication,
+    SynchronizedChargingConfiguration,
+    BmsConnectionLost,
+    NetworkMisconfigured,
+    FactoryCalibrationDataLost,
+    InvalidFirmware,
+    InvalidUserSettings,
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ErrorCode::NoError => write!(f, "No Error"),
+            ErrorCode::BatteryVoltageHigh => write!(f, "Battery Voltage High"),
+            ErrorCode::ChargerTemperatureHigh => write!(f, "Charger Temperature High"),
+            ErrorCode::ChargerCurrentHigh => write!(f, "Charger Current High"),
+            ErrorCode::ChargerCurrentReversed => write!(f, "Charger Current Reversed"),
+            ErrorCode::BulkTimeLimit => write!(f, "Bulk Time Limit"),
+            ErrorCode::CurrentSensor => write!(f, "Current Sensor"),
+            ErrorCode::TerminalTemperatureHigh => write!(f, "Terminal Temperature High"),
+            ErrorCode::Converter => write!(f, "Converter"),
+            ErrorCode::InputVoltageHigh => write!(f, "Input Voltage High"),
+            ErrorCode::InputCurrentHigh => write!(f, "Input Current High"),
+            ErrorCode::InputShutdownDueToBatteryVoltage => {
+                write!(f, "Input Shutdown Due To Battery Voltage")
+            }
+            ErrorCode::InputShutdownDueToCurrentFlowWhileOff => {
+                write!(f, "Input Shutdown Due To Current Flow While Off")
+            }
+            ErrorCode::LostCommunication => write!(f, "Lost Communication"),
+            ErrorCode::SynchronizedChargingConfiguration => {
+                write!(f, "Synchronized Charging Configuration")
+            }
+            ErrorCode::BmsConnectionLost => write!(f, "Bms Connection Lost"),
+            ErrorCode::NetworkMisconfigured => write!(f, "Network Misconfigured"),
+            ErrorCode::FactoryCalibrationDataLost => write!(f, "Factory Calibration Data Lost"),
+            ErrorCode::InvalidFirmware => write!(f, "Invalid Firmware"),
+            ErrorCode::InvalidUserSettings => write!(f, "Invalid User Settings"),
+        }
+    }
+}
+
+impl ErrorCode {
+    pub fn from_u32(val: u32) -> Option<ErrorCode> {
+        match val {
+            0 => Some(ErrorCode::NoError),
+            2 => Some(ErrorCode::BatteryVoltageHigh),
+            17 => Some(ErrorCode::ChargerTemperatureHigh),
+            18 => Some(ErrorCode::ChargerCurrentHigh),
+            19 => Some(ErrorCode::ChargerCurrentReversed),
+            20 => Some(ErrorCode::BulkTimeLimit),
+            21 => Some(ErrorCode::CurrentSensor),
+            26 => Some(ErrorCode::TerminalTemperatureHigh),
+            28 => Some(ErrorCode::Converter),
+            33 => Some(ErrorCode::InputVoltageHigh),
+            34 => Some(ErrorCode::InputCurrentHigh),
+            38 => Some(ErrorCode::InputShutdownDueToBatteryVoltage),
+            39 => Some(ErrorCode::InputShutdownDueToCurrentFlowWhileOff),
+            65 => Some(ErrorCode::LostCommunication),
+            66 => Some(ErrorCode::SynchronizedChargingConfiguration),
+            67 => Some(ErrorCode::BmsConnectionLost),
+            68 => Some(ErrorCode::NetworkMisconfigured),
+            116 => Some(ErrorCode::FactoryCalibrationDataLost),
+            117 => Some(ErrorCode::InvalidFirmware),
+            119 => Some(ErrorCode::InvalidUserSettings),
+            _ => None,
+        }
+    }
+}
+
+/// MPPT: Tracker operation mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
+pub enum Mppt {
+    Off = 0,
+    VoltageOrCurrentLimited = 1,
+    MpptTrackerActive = 2,
+}
+
+impl Mppt {
+    pub fn from_u32(val: u32) -> Option<Mppt> {
+        match val {
+            0 => Some(Mppt::Off),
+            1 => Some(Mppt::VoltageOrCurrentLimited),
+            2 => Some(Mppt::MpptTrackerActive),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Mppt {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Mppt::Off => {
+                write!(f, "Off")
+            }
+            Mppt::VoltageOrCurrentLimited => {
+                write!(f, "Voltage Or Current Limited")
+            }
+            Mppt::MpptTrackerActive => {
+                write!(f, "Mppt Tracker Active")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ErrorCode, FrameError, Label, MpptFrame, OffReason, StateOfOperation};
+
+    #[test]
+    fn set_records() {
+        let mut frame = MpptFrame::default();
+        let records = [
+            ("V", "13250"),
+            ("I", "-1500"),
+            ("PPV", "120"),
+            ("LOAD", "ON"),
+            ("OR", "0X00000001"),
+            ("H20", "725"),
+            ("ERR", "67"),
+            ("CS", "5"),
+            ("PID", "0XA053"),
+        ];
+        for (label, value) in records {
+            frame.set(label, value).unwrap();
+        }
+
+        assert_eq!(Some(13.25), frame.battery_voltage);
+        assert_eq!(Some(-1.5), frame.battery_current);
+        assert_eq!(Some(120), frame.panel_power);
+        assert_eq!(Some(true), frame.load_state);
+        assert_eq!(Some(OffReason::NO_INPUT_POWER), frame.off_reason);
+        assert_eq!(Some(7250), frame.yield_today);
+        assert_eq!(Some(ErrorCode::BmsConnectionLost), frame.error);
+        assert_eq!(Some(StateOfOperation::Float), frame.state);
+        assert_eq!(Some(0xa053), frame.product_id);
+    }
+
+    #[test]
+    fn reject_unknown_and_invalid() {
+        let mut frame = MpptFrame::default();
+        assert_eq!(
+            Err(FrameError::UnknownLabel(Label::from("BMV").unwrap())),
+            frame.set("BMV", "712")
+        );
+        assert!(frame.set("SER#", &"X".repeat(40)).is_err());
+        assert!(frame.set("V", "abc").is_err());
+        assert!(frame.set("OR", "0").is_err());
+        assert!(frame.set("CS", "8").is_err());
+        assert!(frame.set("H19", "429496730").is_err());
+        assert_eq!(MpptFrame::default(), frame);
+    }
+
+    #[test]
+    fn display_names() {
+        assert_eq!(
+            "Bms Connection Lost",
+            ErrorCode::BmsConnectionLost.to_string()
+        );
+        assert_eq!("Float", StateOfOperation::Float.to_string());
+        assert_eq!(
+            "NO_INPUT_POWER | BMS",
+            (OffReason::NO_INPUT_POWER | OffReason::BMS).to_string()
+        );
+    }
+}
@@ -0,0 +1,336 @@
+//! Byte-at-a-time parser of the VE.Direct text protocol
+//!
+//! Adapted from the reference implementation in the VE.Direct protocol FAQ. The parser keeps no
+//! state beyond the record being read, so it can be fed however many bytes are available.
+
+use arrayvec::ArrayVec;
+use core::fmt::Display;
+use core::num::Wrapping;
+use core::str::from_utf8;
+#[cfg(test)]
+use mockall::{automock, predicate::*};
+
+// Constants defined in VE.Direct Protocol doc in "Implementation Guidelines" section.
+pub(crate) const LABEL_LEN: usize = 9;
+pub(crate) const VALUE_LEN: usize = 33;
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Record {
+    label: ArrayVec<u8, LABEL_LEN>,
+    value: ArrayVec<u8, VALUE_LEN>,
+}
+
+impl Record {
+    pub fn clear(&mut self) {
+        self.label.clear();
+        self.value.clear();
+    }
+}
+
+/// Input the parser can't make sense of; the parser should be reset before continuing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    LabelTooLong,
+    ValueTooLong,
+    InvalidUtf8,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseError::LabelTooLong => write!(f, "record label longer than {} bytes", LABEL_LEN),
+            ParseError::ValueTooLong => write!(f, "record value longer than {} bytes", VALUE_LEN),
+            ParseError::InvalidUtf8 => write!(f, "record is not valid utf-8"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
+/// Receives the records of a block as they are parsed
+#[cfg_attr(test, automock)]
+pub trait ParseEvent {
+    fn record(&mut self, label: &str, value: &str);
+    fn checksum_valid(&mut self);
+    fn checksum_invalid(&mut self);
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParseState {
+    #[default]
+    Idle,
+    RecordLabel,
+    RecordValue,
+    Checksum,
+    RecordHex,
+}
+
+/// HEX message interleaved with the text block
+///
+/// A ':' may also be part of a text value, so the bytes following it are held until they are known
+/// to be a HEX message (hex digits up to a bare '\n') or text (anything else), in which case they
+/// are replayed through the text state machine.
+#[derive(Debug, Default)]
+pub struct HexMessage {
+    /// State of the text block to resume when the message ends
+    resume: ParseState,
+
+    /// Bytes after the ':' while it could still be text
+    pending: ArrayVec<u8, VALUE_LEN>,
+
+    /// Too long to be text, skip to the end of the message
+    confirmed: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Parser {
+    pub state: ParseState,
+    pub record: Record,
+    pub checksum: Wrapping<u8>,
+    pub hex: HexMessage,
+}
+
+const COLON: u8 = 0x3a; // ':'
+const NL: u8 = 0x0a; // '\n'
+const CR: u8 = 0x0d; // '\r'
+const TAB: u8 = 0x09; // '\t'
+const CHECKSUM_LABEL: &[u8] = "CHECKSUM".as_bytes();
+
+impl Parser {
+    pub fn parse<T: ParseEvent>(
+        &mut self,
+        parse_event: &mut T,
+        inp: &[u8],
+    ) -> Result<(), ParseError> {
+        inp.iter()
+            .try_for_each(|b| self.parse_input_byte(parse_event, *b))
+    }
+
+    pub fn parse_input_byte<T: ParseEvent>(
+        &mut self,
+        parse_event: &mut T,
+        inp: u8,
+    ) -> Result<(), ParseError> {
+        // adapted from reference implementation at
+        // https://www.victronenergy.com/live/vedirect_protocol:faq
+        // HEX messages may start anywhere in a text block except in the checksum byte, which can
+        // itself be a ':', and the text block resumes where it was interrupted.
+        match self.state {
+            ParseState::RecordHex => self.parse_hex_byte(parse_event, inp),
+            ParseState::Checksum => self.parse_text_byte(parse_event, inp),
+            state if inp == COLON => {
+                self.hex = HexMessage {
+                    resume: state,
+                    ..Default::default()
+                };
+                self.state = ParseState::RecordHex;
+                Ok(())
+            }
+            _ => self.parse_text_byte(parse_event, inp),
+        }
+    }
+
+    fn parse_hex_byte<T: ParseEvent>(
+        &mut self,
+        parse_event: &mut T,
+        inp: u8,
+    ) -> Result<(), ParseError> {
+        if inp == NL {
+            // end of the message, which doesn't count towards the text checksum
+            self.state = self.hex.resume;
+        } else if self.hex.confirmed {
+            // ignore hex message data
+        } else if inp.is_ascii_hexdigit() {
+            if self.hex.pending.try_push(inp).is_err() {
+                self.hex.confirmed = true;
+                self.hex.pending.clear();
+            }
+        } else {
+            // not a HEX message after all, the ':' was text
+            self.state = self.hex.resume;
+            let pending = core::mem::take(&mut self.hex.pending);
+            self.parse_text_byte(parse_event, COLON)?;
+            for b in pending {
+                self.parse_text_byte(parse_event, b)?;
+            }
+            self.parse_input_byte(parse_event, inp)?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_text_byte<T: ParseEvent>(
+        &mut self,
+        parse_event: &mut T,
+        inp: u8,
+    ) -> Result<(), ParseError> {
+        self.checksum += inp;
+
+        let inp = to_upper(inp);
+
+        match self.state {
+            ParseState::Idle => {
+                if inp == NL {
+                    self.record.clear();
+                    self.state = ParseState::RecordLabel;
+                }
+            }
+            ParseState::RecordLabel => {
+                if inp == TAB {
+                    if self.record.label.as_slice() == CHECKSUM_LABEL {
+                        self.state = ParseState::Checksum
+                    } else {
+                        self.state = ParseState::RecordValue
+                    }
+                } else {
+                    self.record
+                        .label
+                        .try_push(inp)
+                        .map_err(|_| ParseError::LabelTooLong)?;
+                }
+            }
+            ParseState::RecordValue => {
+                match inp {
+                    NL => {
+                        let label = from_utf8(self.record.label.as_slice())
+                            .map_err(|_| ParseError::InvalidUtf8)?;
+                        let value = from_utf8(self.record.value.as_slice())
+                            .map_err(|_| ParseError::InvalidUtf8)?;
+                        parse_event.record(label, value);
+                        self.record.clear();
+                        self.state = ParseState::RecordLabel;
+                    }
+                    CR => {
+                        // skip
+                    }
+                    _ => {
+                        self.record
+                            .value
+                            .try_push(inp)
+                            .map_err(|_| ParseError::ValueTooLong)?;
+                    }
+                }
+            }
+            ParseState::Checksum => {
+                self.state = ParseState::Idle;
+                if self.checksum == Wrapping::default() {
+                    parse_event.checksum_valid();
+                } else {
+                    parse_event.checksum_invalid();
+                }
+                self.checksum = Wrapping::default();
+            }
+            ParseState::RecordHex => {
+                // parsed by parse_hex_byte
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn to_upper(b: u8) -> u8 {
+    if (0x61..=0x7a).contains(&b) {
+        b - 0x20
+    } else {
+        b
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MockParseEvent, ParseState, Parser};
+    use mockall::predicate::*;
+
+    #[derive(Default)]
+    struct Mock(MockParseEvent);
+
+    impl Mock {
+        fn expect_record(&mut self, label: &'static str, value: &'static str) {
+            self.0
+                .expect_record()
+                .with(eq(label), eq(value))
+                .returning(|_, _| {});
+        }
+
+        fn expect_checksum_valid(&mut self) {
+            self.0.expect_checksum_valid().returning(|| {});
+        }
+
+        // fn expect_checksum_invalid(&mut self) {
+        //     self.0
+        //         .expect_checksum_invalid()
+        //         .returning(|| {});
+        // }
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        let data = b"\r\nERR\t0\r\nLOAD\tON\r\nRelay\tOFF\r\nH19\t29051\r\nH20\t725\r\nH21\t1376\r\nH22\t917\r\nH23\t1419\r\nHSDS\t191\r\nChecksum\t\xd2";
+
+        let mut mock = Mock::default();
+        mock.expect_record("ERR", "0");
+        mock.expect_record("LOAD", "ON");
+        mock.expect_record("RELAY", "OFF");
+        mock.expect_record("H19", "29051");
+        mock.expect_record("H20", "725");
+        mock.expect_record("H21", "1376");
+        mock.expect_record("H22", "917");
+        mock.expect_record("H23", "1419");
+        mock.expect_record("HSDS", "191");
+        mock.expect_checksum_valid();
+
+        let mut parser = Parser::default();
+        parser.parse(&mut mock.0, data).unwrap();
+        assert_eq!(ParseState::Idle, parser.state);
+    }
+
+    #[test]
+    fn test_parse_frame_with_hex() {
+        let data = b":A200100ADB50200C6\n\r\nERR\t0\r\nLOAD\tON\r\nRelay\tOFF\r\nH19\t29067\r\nH20\t741\r\nH21\t1376\r\nH22\t917\r\nH23\t1419\r\nHSDS\t191\r\nChecksum\t\xcd";
+
+        let mut mock = Mock::default();
+        mock.expect_record("ERR", "0");
+        mock.expect_record("LOAD", "ON");
+        mock.expect_record("RELAY", "OFF");
+        mock.expect_record("H19", "29067");
+        mock.expect_record("H20", "741");
+        mock.expect_record("H21", "1376");
+        mock.expect_record("H22", "917");
+        mock.expect_record("H23", "1419");
+        mock.expect_record("HSDS", "191");
+        mock.expect_checksum_valid();
+
+        let mut parser = Parser::default();
+        parser.parse(&mut mock.0, data).unwrap();
+        assert_eq!(ParseState::Idle, parser.state);
+    }
+
+    #[test]
+    fn test_parse_hex_mid_record() {
+        // text checksum excludes the interleaved HEX message
+        let data = b"\r\nV\t132:A200100ADB50200C6\n50\r\n:154\nChecksum\t\x3c";
+
+        let mut mock = Mock::default();
+        mock.expect_record("V", "13250");
+        mock.expect_checksum_valid();
+
+        let mut parser = Parser::default();
+        parser.parse(&mut mock.0, data).unwrap();
+        assert_eq!(ParseState::Idle, parser.state);
+    }
+
+    #[test]
+    fn test_parse_colon_in_value() {
+        let data = b"\r\nFW\t1:5A\r\nChecksum\t\x0f";
+
+        let mut mock = Mock::default();
+        mock.expect_record("FW", "1:5A");
+        mock.expect_checksum_valid();
+
+        let mut parser = Parser::default();
+        parser.parse(&mut mock.0, data).unwrap();
+        assert_eq!(ParseState::Idle, parser.state);
+    }
+}
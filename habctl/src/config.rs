@@ -12,6 +12,7 @@ pub struct Config {
     #[serde(skip, default)]
     pub build: Build,
     pub hardware: crate::hardware::config::Hardware,
+    pub leveling: Option<crate::leveling::config::Leveling>,
     pub web: crate::web::config::Web,
 }
 
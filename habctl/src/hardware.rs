@@ -9,20 +9,36 @@ use anyhow::Result;
 use device::Device;
 use futures::future::try_join_all;
 use imu::Icm20948;
-use serde::Serialize;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::SystemTime;
 use victron::ve_direct::VeDirectMppt;
 
-#[derive(Serialize)]
 pub struct Hardware {
     imu: Vec<Arc<Icm20948>>,
     mppt: Vec<Arc<VeDirectMppt>>,
 }
 
 impl Hardware {
+    /// IMU with the given name in the config
+    pub fn imu(&self, name: &str) -> Option<Arc<Icm20948>> {
+        self.imu.iter().find(|imu| imu.name() == name).cloned()
+    }
+
+    /// MPPT controller with the given name in the config
+    pub fn mppt(&self, name: &str) -> Option<Arc<VeDirectMppt>> {
+        self.mppt.iter().find(|mppt| mppt.name() == name).cloned()
+    }
+
+    pub fn imus(&self) -> &[Arc<Icm20948>] {
+        &self.imu
+    }
+
+    pub fn mppts(&self) -> &[Arc<VeDirectMppt>] {
+        &self.mppt
+    }
+
     pub async fn run(&self) -> Result<Vec<Vec<()>>> {
         let mut imu_runners = Vec::new();
         for i in 0..self.imu.len() {
@@ -51,18 +67,18 @@ impl Default for Hardware {
                 .hardware
                 .imu
                 .iter()
-                .map(|(name, config)| match &config.port {
-                    Some(port) => Icm20948::device(name, &port),
-                    None => Icm20948::loopback(name),
+                .map(|(name, config)| match (&config.port, config.loopback) {
+                    (Some(port), None | Some(false)) => Icm20948::device(name, port),
+                    _ => Icm20948::loopback(name),
                 })
                 .collect(),
             mppt: config
                 .hardware
                 .mppt
                 .iter()
-                .map(|(name, config)| match &config.port {
-                    Some(port) => VeDirectMppt::device(name, &port),
-                    None => VeDirectMppt::loopback(name),
+                .map(|(name, config)| match (&config.port, config.loopback) {
+                    (Some(port), None | Some(false)) => VeDirectMppt::device(name, port),
+                    _ => VeDirectMppt::loopback(name),
                 })
                 .collect(),
         };
@@ -1,3 +1,4 @@
+use crate::hardware::imu::{AccelRange, GyroRange};
 use serde::Deserialize;
 use std::collections::HashMap;
 
@@ -11,10 +12,213 @@ pub struct Hardware {
 pub struct Mppt {
     pub port: Option<String>,
     pub loopback: Option<bool>,
+
+    /// Captured VE.Direct stream replayed in loopback, instead of synthetic frames
+    pub replay: Option<String>,
+
+    /// Synthetic controller used in loopback
+    #[serde(default)]
+    pub synthetic: Synthetic,
+}
+
+/// Synthetic MPPT controller charging from panels in clear sky
+#[derive(Deserialize, Debug, Clone)]
+pub struct Synthetic {
+    /// Panel power at noon, in W
+    #[serde(default = "default_peak_power")]
+    pub peak_power: f64,
+
+    /// Local time the panels start producing, in hours
+    #[serde(default = "default_sunrise")]
+    pub sunrise: f64,
+
+    /// Local time the panels stop producing, in hours
+    #[serde(default = "default_sunset")]
+    pub sunset: f64,
+
+    /// Panel voltage at noon, in V
+    #[serde(default = "default_panel_voltage")]
+    pub panel_voltage: f64,
+}
+
+impl Default for Synthetic {
+    fn default() -> Self {
+        Synthetic {
+            peak_power: default_peak_power(),
+            sunrise: default_sunrise(),
+            sunset: default_sunset(),
+            panel_voltage: default_panel_voltage(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Imu {
     pub port: Option<String>,
     pub loopback: Option<bool>,
+
+    /// I2C address, 0x68 with AD0 low or 0x69 with AD0 high
+    #[serde(default = "default_imu_address")]
+    pub address: u16,
+
+    #[serde(default)]
+    pub accel_range: AccelRange,
+
+    #[serde(default)]
+    pub gyro_range: GyroRange,
+
+    /// Accelerometer low pass filter setting (0-7), bypassed if not set
+    pub accel_dlpf: Option<u8>,
+
+    /// Gyrometer low pass filter setting (0-7), bypassed if not set
+    pub gyro_dlpf: Option<u8>,
+
+    /// File storing the magnetometer calibration, `<name>-magnetometer.toml` if not set
+    pub magnetometer_calibration_path: Option<String>,
+
+    /// Magnetic declination added to the heading to give true north, in degrees east
+    #[serde(default)]
+    pub declination: f32,
+
+    /// Samples per second fused into the orientation
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f32,
+
+    /// Proportional gain of the orientation correction from the accelerometer and magnetometer
+    #[serde(default = "default_fusion_kp")]
+    pub fusion_kp: f32,
+
+    /// Integral gain of the orientation correction, estimating the gyrometer bias
+    #[serde(default = "default_fusion_ki")]
+    pub fusion_ki: f32,
+
+    /// Deviation from the resting acceleration that counts as the camper moving, in g
+    #[serde(default = "default_motion_threshold")]
+    pub motion_threshold: f32,
+
+    /// Change of tilt that counts as the camper moving, in degrees
+    #[serde(default = "default_motion_tilt_threshold")]
+    pub motion_tilt_threshold: f32,
+
+    /// Simulated device used in loopback
+    #[serde(default)]
+    pub simulation: Simulation,
+}
+
+/// Simulated ICM-20948, with the camper at rest apart from the motion script
+#[derive(Deserialize, Debug, Clone)]
+pub struct Simulation {
+    /// Left side up, in degrees
+    #[serde(default)]
+    pub roll: f32,
+
+    /// Front up, in degrees
+    #[serde(default)]
+    pub pitch: f32,
+
+    /// Front clockwise from magnetic north, in degrees
+    #[serde(default)]
+    pub heading: f32,
+
+    /// Largest accelerometer noise, in g
+    #[serde(default = "default_accel_noise")]
+    pub accel_noise: f32,
+
+    /// Largest gyrometer noise, in degrees per second
+    #[serde(default = "default_gyro_noise")]
+    pub gyro_noise: f32,
+
+    #[serde(default)]
+    pub motion: Vec<Motion>,
+
+    /// Period after which the motion script starts over, in seconds
+    pub repeat: Option<f32>,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Simulation {
+            roll: 0.0,
+            pitch: 0.0,
+            heading: 0.0,
+            accel_noise: default_accel_noise(),
+            gyro_noise: default_gyro_noise(),
+            motion: Vec::new(),
+            repeat: None,
+        }
+    }
+}
+
+/// Step of a simulated motion script
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Motion {
+    /// Seconds from the start of the script
+    pub start: f32,
+
+    /// Seconds the step lasts
+    pub duration: f32,
+
+    /// Acceleration on the IMU axes while the step lasts, in g
+    #[serde(default)]
+    pub acceleration: [f32; 3],
+
+    /// Change of roll over the step, in degrees
+    #[serde(default)]
+    pub roll: f32,
+
+    /// Change of pitch over the step, in degrees
+    #[serde(default)]
+    pub pitch: f32,
+
+    /// Change of heading over the step, in degrees
+    #[serde(default)]
+    pub heading: f32,
+}
+
+fn default_peak_power() -> f64 {
+    400.0
+}
+
+fn default_sunrise() -> f64 {
+    6.0
+}
+
+fn default_sunset() -> f64 {
+    20.0
+}
+
+fn default_panel_voltage() -> f64 {
+    36.0
+}
+
+fn default_imu_address() -> u16 {
+    0x69
+}
+
+fn default_sample_rate() -> f32 {
+    50.0
+}
+
+fn default_fusion_kp() -> f32 {
+    1.0
+}
+
+fn default_fusion_ki() -> f32 {
+    0.01
+}
+
+fn default_motion_threshold() -> f32 {
+    0.05
+}
+
+fn default_motion_tilt_threshold() -> f32 {
+    0.5
+}
+
+fn default_accel_noise() -> f32 {
+    0.002
+}
+
+fn default_gyro_noise() -> f32 {
+    0.1
 }
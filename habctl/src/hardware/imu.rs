@@ -1,17 +1,67 @@
+mod ak09916;
+mod bus;
+pub mod compass;
+mod driver;
+pub mod fusion;
+pub mod motion;
+mod simulation;
+
+pub use driver::{AccelRange, GyroRange};
+
+use crate::hardware::config;
 use crate::hardware::device::Device;
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use bus::{I2cBus, LinuxI2c};
+use compass::{Calibration, Calibrator};
+use driver::Driver;
+use fusion::{EulerAngles, Fusion};
+use motion::{MotionDetector, MotionEvent};
 use nalgebra as na;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use simulation::SimulatedIcm20948;
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 use tokio::task;
-use tokio::time::{sleep, Duration};
+use tokio::time::{self, sleep, Duration, Instant, MissedTickBehavior};
+
+/// Interval between frames written to the log
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of motion events kept
+const MAX_EVENTS: usize = 32;
 
-#[derive(Serialize)]
 pub struct Icm20948 {
     loopback: bool,
     name: String,
     port: String,
     pub telemetry: Mutex<ImuFrame>,
+
+    /// When the telemetry was last updated
+    received: Mutex<Option<Instant>>,
+
+    /// Most recent motion events, oldest first
+    pub events: Mutex<VecDeque<MotionEvent>>,
+
+    compass: Mutex<CompassState>,
+}
+
+/// State kept between samples
+struct Sampler {
+    fusion: Fusion,
+    motion: MotionDetector,
+
+    /// When the last sample was read
+    sampled: Option<Instant>,
+
+    /// When a frame was last written to the log
+    logged: Option<Instant>,
+}
+
+/// Magnetometer calibration in use and any calibration in progress
+#[derive(Default)]
+struct CompassState {
+    calibration: Option<Calibration>,
+    calibrator: Option<Calibrator>,
 }
 
 impl Device for Icm20948 {
@@ -21,6 +71,9 @@ impl Device for Icm20948 {
             name: name.to_owned(),
             port: path.to_owned(),
             telemetry: Mutex::default(),
+            received: Mutex::default(),
+            events: Mutex::default(),
+            compass: Mutex::default(),
         })
     }
 
@@ -30,106 +83,244 @@ impl Device for Icm20948 {
             name: name.to_owned(),
             port: String::new(),
             telemetry: Mutex::default(),
+            received: Mutex::default(),
+            events: Mutex::default(),
+            compass: Mutex::default(),
         })
     }
 }
 
 impl Icm20948 {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Time since the telemetry was last updated
+    pub fn age(&self) -> Option<Duration> {
+        self.received
+            .lock()
+            .unwrap()
+            .map(|received| received.elapsed())
+    }
+
     pub async fn run(&self) -> Result<()> {
         if self.loopback {
             log::debug!("Icm20948 {} is in loopback mode.", self.name);
-            sleep(Duration::from_secs(600)).await;
         } else {
             log::debug!("Icm20948 {} at {}", self.name, self.port);
+        }
+
+        let config = self.config()?;
+        self.load_calibration(config)?;
+
+        // reopened and initialized again after any error
+        let mut driver = None;
+        let mut sampler = Sampler {
+            fusion: Fusion::new(config.fusion_kp, config.fusion_ki),
+            motion: MotionDetector::new(config.motion_threshold, config.motion_tilt_threshold),
+            sampled: None,
+            logged: None,
+        };
+
+        let mut interval = time::interval(Duration::from_secs_f32(1.0 / config.sample_rate));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
 
-            loop {
-                task::block_in_place(|| {
-                    self.read_imu_data();
-                });
+            task::block_in_place(|| {
+                if let Err(e) = self.read_imu_data(&mut driver, &mut sampler, config) {
+                    log::error!("IMU {}: {:#}", self.name, e);
+                    driver = None;
+                    sampler.sampled = None;
+                }
+            });
 
-                tokio::time::sleep(Duration::from_secs(1)).await;
+            if driver.is_none() {
+                // back off before reopening the device
+                sleep(Duration::from_secs(1)).await;
             }
         }
+    }
 
-        Ok(())
+    /// Bus with the device, which is simulated in loopback
+    fn bus(&self, config: &config::Imu) -> Result<Box<dyn I2cBus>> {
+        if self.loopback {
+            Ok(Box::new(SimulatedIcm20948::new(
+                config.simulation.clone(),
+                config.address,
+            )))
+        } else {
+            let bus = LinuxI2c::open(&self.port).with_context(|| format!("open {}", self.port))?;
+            Ok(Box::new(bus))
+        }
     }
 
-    fn read_imu_data(&self) {
-        let mut frame = ImuFrame::default();
-
-        match i2c_linux::I2c::from_path(self.port.to_owned()) {
-            Ok(i2c) => {
-                let mut i2c = i2c;
-                i2c.smbus_set_slave_address(0x69, false)
-                    .expect("set i2c address");
-
-                // power on, best available clock
-                i2c.smbus_write_byte_data(0x06, 0x01).expect("power up");
-
-                let accel_xout_h = i2c.smbus_read_byte_data(0x2d).unwrap() as f32;
-                let accel_xout_l = i2c.smbus_read_byte_data(0x2e).unwrap() as f32;
-                let accel_yout_h = i2c.smbus_read_byte_data(0x2f).unwrap() as f32;
-                let accel_yout_l = i2c.smbus_read_byte_data(0x30).unwrap() as f32;
-                let accel_zout_h = i2c.smbus_read_byte_data(0x31).unwrap() as f32;
-                let accel_zout_l = i2c.smbus_read_byte_data(0x32).unwrap() as f32;
-                let gyro_xout_h = i2c.smbus_read_byte_data(0x33).unwrap() as f32;
-                let gyro_xout_l = i2c.smbus_read_byte_data(0x34).unwrap() as f32;
-                let gyro_yout_h = i2c.smbus_read_byte_data(0x35).unwrap() as f32;
-                let gyro_yout_l = i2c.smbus_read_byte_data(0x36).unwrap() as f32;
-                let gyro_zout_h = i2c.smbus_read_byte_data(0x37).unwrap() as f32;
-                let gyro_zout_l = i2c.smbus_read_byte_data(0x38).unwrap() as f32;
-                let temp_out_h = i2c.smbus_read_byte_data(0x39).unwrap() as f32;
-                let temp_out_l = i2c.smbus_read_byte_data(0x3a).unwrap() as f32;
-
-                fn scale(hi: f32, lo: f32, s: f32) -> f32 {
-                    (hi * 256.0 + lo) * s
-                }
+    fn read_imu_data(
+        &self,
+        driver: &mut Option<Driver>,
+        sampler: &mut Sampler,
+        config: &config::Imu,
+    ) -> Result<()> {
+        let driver = match driver {
+            Some(driver) => driver,
+            None => driver.insert(Driver::open(self.bus(config)?, config)?),
+        };
 
-                const ACCEL_SCALE: f32 = 4.0 / 65535.0;
-                let accel_x = scale(accel_xout_h, accel_xout_l, ACCEL_SCALE);
-                let accel_y = scale(accel_yout_h, accel_yout_l, ACCEL_SCALE);
-                let accel_z = scale(accel_zout_h, accel_zout_l, ACCEL_SCALE);
+        let sample = driver.read_sample()?;
+        let now = Instant::now();
+        let dt = sampler
+            .sampled
+            .replace(now)
+            .map_or(0.0, |sampled| now.duration_since(sampled).as_secs_f32());
 
-                frame.accelerometer = Some(na::Vector3::new(accel_x, accel_y, accel_z));
+        let mut state = self.compass.lock().unwrap();
+        if let (Some(calibrator), Some(field)) = (&mut state.calibrator, &sample.magnetometer) {
+            calibrator.add(field);
+        }
 
-                const GYRO_SCALE: f32 = 250.0 / 65535.0;
-                let gyro_x = scale(gyro_xout_h, gyro_xout_l, GYRO_SCALE);
-                let gyro_y = scale(gyro_yout_h, gyro_yout_l, GYRO_SCALE);
-                let gyro_z = scale(gyro_zout_h, gyro_zout_l, GYRO_SCALE);
+        let (magnetometer, heading) = match (&state.calibration, sample.magnetometer) {
+            (Some(calibration), Some(field)) => {
+                let field = calibration.apply(&field);
+                let heading = compass::heading(&sample.accelerometer, &field)
+                    .map(|heading| (heading + config.declination).rem_euclid(360.0));
+                (Some(field), heading)
+            }
+            (_, field) => (field, None),
+        };
 
-                frame.gyrometer = Some(na::Vector3::new(gyro_x, gyro_y, gyro_z));
+        // an uncalibrated magnetometer would pull the orientation off north
+        let orientation = sampler.fusion.update(
+            &sample.gyrometer,
+            &sample.accelerometer,
+            heading.and(magnetometer.as_ref()),
+            dt,
+        );
 
-                const TEMP_SCALE: f32 = 1.0 / 333.87;
-                let temp = scale(temp_out_h, temp_out_l, TEMP_SCALE) + 21.0;
+        if let Some(event) = sampler
+            .motion
+            .update(chrono::Utc::now(), &sample.accelerometer, dt)
+        {
+            log::warn!("{}: motion {:?}", self.name, event);
+            let mut events = self.events.lock().unwrap();
+            if events.len() == MAX_EVENTS {
+                events.pop_front();
+            }
+            events.push_back(event);
+        }
 
-                frame.temperature = Some(temp);
-                frame.timestamp = Some(crate::hardware::timestamp());
+        let frame = ImuFrame {
+            timestamp: Some(crate::hardware::timestamp()),
+            gyrometer: Some(sample.gyrometer),
+            accelerometer: Some(sample.accelerometer),
+            magnetometer,
+            heading,
+            orientation,
+            euler: orientation.as_ref().map(EulerAngles::from),
+            motion: sampler.motion.current().cloned(),
+            temperature: Some(sample.temperature),
+        };
 
-                log::info!("{}: {}", self.name, frame);
-                *self.telemetry.lock().unwrap() = frame;
-            }
-            Err(e) => {
-                log::error!("IMU {}: {}", self.name, e);
-            }
+        if !matches!(sampler.logged, Some(logged) if now.duration_since(logged) < LOG_INTERVAL) {
+            sampler.logged = Some(now);
+            log::info!("{}: {}", self.name, frame);
         }
+        *self.telemetry.lock().unwrap() = frame;
+        *self.received.lock().unwrap() = Some(now);
+
+        Ok(())
+    }
+
+    fn config(&self) -> Result<&'static config::Imu> {
+        let config = crate::Config::get()
+            .hardware
+            .imu
+            .get(&self.name)
+            .ok_or_else(|| anyhow!("IMU {} is not configured", self.name))?;
+        validate(config).with_context(|| format!("IMU {}", self.name))?;
+
+        Ok(config)
+    }
+
+    fn calibration_path(&self, config: &config::Imu) -> String {
+        config
+            .magnetometer_calibration_path
+            .clone()
+            .unwrap_or_else(|| format!("{}-magnetometer.toml", self.name))
+    }
+
+    fn load_calibration(&self, config: &config::Imu) -> Result<()> {
+        let calibration = match std::fs::read_to_string(self.calibration_path(config)) {
+            Ok(contents) => Some(toml::from_str(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::warn!("IMU {}: magnetometer is not calibrated", self.name);
+                None
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        self.compass.lock().unwrap().calibration = calibration;
+        Ok(())
+    }
+
+    /// Starts collecting magnetometer readings, while the level camper is turned through a full
+    /// circle
+    pub fn start_magnetometer_calibration(&self) {
+        log::info!("IMU {}: magnetometer calibration started", self.name);
+        self.compass.lock().unwrap().calibrator = Some(Calibrator::default());
+    }
+
+    /// Computes the calibration from the readings collected and stores it for future runs
+    pub fn finish_magnetometer_calibration(&self) -> Result<Calibration> {
+        let config = self.config()?;
+        let mut state = self.compass.lock().unwrap();
+        let calibration = state
+            .calibrator
+            .take()
+            .ok_or_else(|| anyhow!("magnetometer calibration was not started"))?
+            .finish()?;
+
+        std::fs::write(
+            self.calibration_path(config),
+            toml::to_string(&calibration)?,
+        )?;
+        log::info!(
+            "IMU {}: magnetometer calibrated: {:?}",
+            self.name,
+            calibration
+        );
+        state.calibration = Some(calibration.clone());
+
+        Ok(calibration)
     }
 }
 
-#[derive(Default, Clone, Debug, Serialize)]
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ImuFrame {
-    timestamp: Option<f32>,
+    pub timestamp: Option<f32>,
+
+    /// Rotation rate in degress per second (up to the configured `gyro_range`)
+    pub gyrometer: Option<na::Vector3<f32>>,
+
+    /// Accelerameter 3-vector in g (up to the configured `accel_range`)
+    pub accelerometer: Option<na::Vector3<f32>>,
+
+    /// Magnetometer 3-vector in Tesla (max 4900 microTesla), calibrated if a calibration is stored
+    pub magnetometer: Option<na::Vector3<f32>>,
 
-    /// Rotation rate in degress per second (max 2000 dps)
-    gyrometer: Option<na::Vector3<f32>>,
+    /// Tilt-compensated heading of the front in degrees clockwise from north, once calibrated
+    pub heading: Option<f32>,
 
-    /// Accelerameter 3-vector in g (max 2g)
-    accelerometer: Option<na::Vector3<f32>>,
+    /// Rotation from the IMU axes to north, west, up fused from all sensors
+    pub orientation: Option<na::UnitQuaternion<f32>>,
 
-    /// Magnetometer 3-vector in Tesla (max 4900 microTesla)
-    magnetometer: Option<na::Vector3<f32>>,
+    /// Fused orientation as angles
+    pub euler: Option<EulerAngles>,
+
+    /// Motion of the camper in progress
+    pub motion: Option<MotionEvent>,
 
     /// IMU temperature in deg C
-    temperature: Option<f32>,
+    pub temperature: Option<f32>,
 }
 
 impl std::fmt::Display for ImuFrame {
@@ -137,31 +328,84 @@ impl std::fmt::Display for ImuFrame {
         let accel = if let Some(a) = self.accelerometer {
             format!("{:?}", (a.x, a.y, a.z))
         } else {
-            format!("none")
+            "none".to_string()
         };
 
         let gyro = if let Some(g) = self.gyrometer {
             format!("{:?}", (g.x, g.y, g.z))
         } else {
-            format!("none")
+            "none".to_string()
         };
 
         let mag = if let Some(m) = self.magnetometer {
             format!("{:?}", (m.x, m.y, m.z))
         } else {
-            format!("none")
+            "none".to_string()
+        };
+
+        let heading = if let Some(h) = self.heading {
+            format!("{}", h)
+        } else {
+            "none".to_string()
+        };
+
+        let euler = if let Some(e) = self.euler {
+            format!("{:?}", (e.roll, e.pitch, e.yaw))
+        } else {
+            "none".to_string()
         };
 
         let temp = if let Some(t) = self.temperature {
             format!("{}", t)
         } else {
-            format!("none")
+            "none".to_string()
         };
 
         write!(
             f,
-            "ACCEL {:?} GYRO {:?} MAG {:?} TEMP {:?}",
-            accel, gyro, mag, temp
+            "ACCEL {:?} GYRO {:?} MAG {:?} HEADING {:?} EULER {:?} TEMP {:?}",
+            accel, gyro, mag, heading, euler, temp
         )
     }
 }
+
+/// Checks the settings that can't be used as configured
+fn validate(config: &config::Imu) -> Result<()> {
+    let positive = [
+        ("sample_rate", config.sample_rate),
+        ("fusion_kp", config.fusion_kp),
+    ];
+    for (name, value) in positive {
+        if !(value.is_finite() && value > 0.0) {
+            bail!("{} must be positive, but is {}", name, value);
+        }
+    }
+
+    // zero leaves the gyro bias uncorrected, using the proportional gain only
+    if !(config.fusion_ki.is_finite() && config.fusion_ki >= 0.0) {
+        bail!(
+            "fusion_ki must not be negative, but is {}",
+            config.fusion_ki
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::validate;
+    use crate::hardware::config;
+
+    #[test]
+    fn validate_rejects_negative_gains_and_rates_that_are_not_positive() {
+        let parse = |toml: &str| toml::from_str::<config::Imu>(toml).unwrap();
+
+        assert!(validate(&parse("")).is_ok());
+        assert!(validate(&parse("sample_rate = 0.0")).is_err());
+        assert!(validate(&parse("sample_rate = -50.0")).is_err());
+        assert!(validate(&parse("fusion_kp = 0.0")).is_err());
+        assert!(validate(&parse("fusion_ki = 0.0")).is_ok());
+        assert!(validate(&parse("fusion_ki = -0.01")).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! AK09916 magnetometer inside the ICM-20948
+//!
+//! Reached directly on the host bus once the ICM-20948 is in I2C bypass mode.
+
+use super::bus::I2cBus;
+use anyhow::{bail, Context, Result};
+use nalgebra as na;
+use std::thread;
+use std::time::Duration;
+
+/// Fixed I2C address of the magnetometer
+pub(super) const ADDRESS: u16 = 0x0c;
+
+/// Expected contents of `WIA2`
+pub(super) const DEVICE_ID: u8 = 0x09;
+
+pub(super) const WIA2: u8 = 0x01;
+pub(super) const ST1: u8 = 0x10;
+pub(super) const CNTL2: u8 = 0x31;
+pub(super) const CNTL3: u8 = 0x32;
+
+// ST1 bits
+pub(super) const DRDY: u8 = 0x01;
+
+// ST2 bits
+const HOFL: u8 = 0x08;
+
+// CNTL2 modes
+pub(super) const POWER_DOWN: u8 = 0x00;
+const CONTINUOUS_100HZ: u8 = 0x08;
+
+// CNTL3 bits
+pub(super) const SRST: u8 = 0x01;
+
+/// ST1, the measurement and ST2, which must be read to release the data registers
+pub(super) const MEASUREMENT_LEN: usize = 9;
+
+/// Sensitivity in Tesla per LSB
+pub(super) const SENSITIVITY: f32 = 0.15e-6;
+
+/// Checks the magnetometer is on the bus and starts continuous measurement
+pub fn init(bus: &mut dyn I2cBus) -> Result<()> {
+    let id = bus.read_byte(ADDRESS, WIA2).context("read WIA2")?;
+    if id != DEVICE_ID {
+        bail!(
+            "unexpected magnetometer WIA2 {:#04x}, expected {:#04x}",
+            id,
+            DEVICE_ID
+        );
+    }
+
+    bus.write_byte(ADDRESS, CNTL3, SRST)
+        .context("reset magnetometer")?;
+    thread::sleep(Duration::from_millis(10));
+    bus.write_byte(ADDRESS, CNTL2, CONTINUOUS_100HZ)
+        .context("start magnetometer")?;
+
+    Ok(())
+}
+
+/// Latest measurement in Tesla on the accelerometer axes, if one is ready and not saturated
+pub fn read(bus: &mut dyn I2cBus) -> Result<Option<na::Vector3<f32>>> {
+    let mut raw = [0u8; MEASUREMENT_LEN];
+    bus.read_block(ADDRESS, ST1, &mut raw)
+        .context("read magnetometer")?;
+
+    Ok(decode(&raw))
+}
+
+/// Scales the little-endian measurement registers
+///
+/// The magnetometer y and z axes point opposite to the accelerometer's, so they are negated.
+fn decode(raw: &[u8; MEASUREMENT_LEN]) -> Option<na::Vector3<f32>> {
+    let (st1, st2) = (raw[0], raw[8]);
+    if st1 & DRDY == 0 || st2 & HOFL != 0 {
+        return None;
+    }
+
+    let word = |index: usize| i16::from_le_bytes([raw[1 + index * 2], raw[2 + index * 2]]) as f32;
+    Some(na::Vector3::new(word(0), -word(1), -word(2)) * SENSITIVITY)
+}
+
+#[cfg(test)]
+mod test {
+    use super::decode;
+
+    #[test]
+    fn decode_measurement() {
+        // x: 100, y: -100, z: 1000
+        let raw = [0x01, 0x64, 0x00, 0x9c, 0xff, 0xe8, 0x03, 0x00, 0x10];
+        let field = decode(&raw).unwrap();
+        assert!((field.x - 15e-6).abs() < 1e-9);
+        assert!((field.y - 15e-6).abs() < 1e-9);
+        assert!((field.z + 150e-6).abs() < 1e-9);
+
+        // not ready
+        let mut stale = raw;
+        stale[0] = 0x00;
+        assert_eq!(None, decode(&stale));
+
+        // saturated
+        let mut overflow = raw;
+        overflow[8] = 0x18;
+        assert_eq!(None, decode(&overflow));
+    }
+}
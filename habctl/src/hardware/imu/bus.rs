@@ -0,0 +1,66 @@
+//! I2C bus shared by the ICM-20948 and its magnetometer
+
+use i2c_linux::I2c;
+use std::fs::File;
+use std::io;
+
+/// Register access to devices on an I2C bus
+pub trait I2cBus: Send {
+    fn read_byte(&mut self, address: u16, register: u8) -> io::Result<u8>;
+
+    fn write_byte(&mut self, address: u16, register: u8, value: u8) -> io::Result<()>;
+
+    /// Reads consecutive registers starting at `register` in one transaction
+    fn read_block(&mut self, address: u16, register: u8, buffer: &mut [u8]) -> io::Result<()>;
+}
+
+/// Linux i2c-dev bus
+pub struct LinuxI2c {
+    i2c: I2c<File>,
+
+    /// Device address currently selected on the file
+    address: Option<u16>,
+}
+
+impl LinuxI2c {
+    pub fn open(path: &str) -> io::Result<LinuxI2c> {
+        Ok(LinuxI2c {
+            i2c: I2c::from_path(path)?,
+            address: None,
+        })
+    }
+
+    fn select(&mut self, address: u16) -> io::Result<()> {
+        if self.address != Some(address) {
+            self.i2c.smbus_set_slave_address(address, false)?;
+            self.address = Some(address);
+        }
+
+        Ok(())
+    }
+}
+
+impl I2cBus for LinuxI2c {
+    fn read_byte(&mut self, address: u16, register: u8) -> io::Result<u8> {
+        self.select(address)?;
+        self.i2c.smbus_read_byte_data(register)
+    }
+
+    fn write_byte(&mut self, address: u16, register: u8, value: u8) -> io::Result<()> {
+        self.select(address)?;
+        self.i2c.smbus_write_byte_data(register, value)
+    }
+
+    fn read_block(&mut self, address: u16, register: u8, buffer: &mut [u8]) -> io::Result<()> {
+        self.select(address)?;
+        let len = self.i2c.i2c_read_block_data(register, buffer)?;
+        if len != buffer.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("read {} of {} bytes", len, buffer.len()),
+            ));
+        }
+
+        Ok(())
+    }
+}
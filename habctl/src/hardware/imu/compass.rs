@@ -0,0 +1,164 @@
+//! Magnetometer calibration and tilt-compensated heading
+//!
+//! Headings assume the IMU is mounted with x towards the front of the camper, y towards the left
+//! side and z up.
+
+use anyhow::{bail, Result};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+/// Smallest spread of field on the horizontal axes to accept a calibration, in Tesla
+const MIN_RADIUS: f32 = 5e-6;
+
+/// Hard and soft iron correction of the magnetometer
+///
+/// Calibrated field is `soft_iron * (measured - hard_iron)`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Offset from magnetized parts of the camper, in Tesla
+    pub hard_iron: na::Vector3<f32>,
+
+    /// Correction of the distortion from nearby steel into a sphere
+    pub soft_iron: na::Matrix3<f32>,
+}
+
+impl Calibration {
+    pub fn apply(&self, field: &na::Vector3<f32>) -> na::Vector3<f32> {
+        self.soft_iron * (field - self.hard_iron)
+    }
+}
+
+/// Collects the extremes of the field while the level camper is turned through a full circle
+///
+/// Fits an ellipse aligned with the horizontal axes, which covers the offset and the axis scaling
+/// that dominate once the IMU is mounted. A level turn leaves the vertical field almost constant,
+/// so the z axis is left uncorrected; it only matters for the heading while the camper is tilted.
+#[derive(Clone, Debug, Default)]
+pub struct Calibrator {
+    min: Option<na::Vector3<f32>>,
+    max: Option<na::Vector3<f32>>,
+}
+
+impl Calibrator {
+    pub fn add(&mut self, field: &na::Vector3<f32>) {
+        self.min = Some(self.min.map_or(*field, |min| min.inf(field)));
+        self.max = Some(self.max.map_or(*field, |max| max.sup(field)));
+    }
+
+    pub fn finish(&self) -> Result<Calibration> {
+        let (min, max) = match (self.min, self.max) {
+            (Some(min), Some(max)) => (min, max),
+            _ => bail!("no magnetometer readings collected"),
+        };
+
+        let radii = (max - min).xy() / 2.0;
+        if radii.min() < MIN_RADIUS {
+            bail!(
+                "magnetometer readings cover too little of a circle: {:?}",
+                radii
+            );
+        }
+
+        let center = (max + min).xy() / 2.0;
+        let radius = radii.mean();
+        Ok(Calibration {
+            hard_iron: na::Vector3::new(center.x, center.y, 0.0),
+            soft_iron: na::Matrix3::from_diagonal(&na::Vector3::new(
+                radius / radii.x,
+                radius / radii.y,
+                1.0,
+            )),
+        })
+    }
+}
+
+/// Heading of the front of the camper in degrees clockwise from magnetic north
+///
+/// The accelerometer gives the up direction, so the heading does not depend on tilt.
+pub fn heading(accelerometer: &na::Vector3<f32>, field: &na::Vector3<f32>) -> Option<f32> {
+    let up = accelerometer.try_normalize(f32::EPSILON)?;
+    let east = field.cross(&up).try_normalize(f32::EPSILON)?;
+    let north = up.cross(&east);
+
+    // the front is the x axis
+    let heading = east.x.atan2(north.x).to_degrees();
+    Some(heading.rem_euclid(360.0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{heading, Calibration, Calibrator};
+    use nalgebra as na;
+
+    fn assert_near(expected: f32, actual: f32) {
+        assert!(
+            (expected - actual).abs() < 0.01,
+            "expected {} but got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn heading_compensates_tilt() {
+        let up = na::Vector3::new(0.0, 0.0, 1.0);
+
+        // field points north and down into the ground
+        let north = na::Vector3::new(20e-6, 0.0, -40e-6);
+        assert_near(0.0, heading(&up, &north).unwrap());
+
+        // facing east, north is to the left
+        let east = na::Vector3::new(0.0, 20e-6, -40e-6);
+        assert_near(90.0, heading(&up, &east).unwrap());
+
+        // facing south west
+        let rotation = na::Rotation3::from_axis_angle(&na::Vector3::z_axis(), 225f32.to_radians());
+        assert_near(225.0, heading(&up, &(rotation * north)).unwrap());
+
+        // pitched 10 degrees and rolled 5 degrees, still facing east
+        let tilt = na::Rotation3::from_axis_angle(&na::Vector3::x_axis(), 5f32.to_radians())
+            * na::Rotation3::from_axis_angle(&na::Vector3::y_axis(), 10f32.to_radians());
+        assert_near(90.0, heading(&(tilt * up), &(tilt * east)).unwrap());
+    }
+
+    #[test]
+    fn level_turn_calibrates_heading() {
+        let hard_iron = na::Vector3::new(10e-6, -30e-6, 5e-6);
+        let scale = na::Vector3::new(1.2, 0.8, 1.0);
+        let up = na::Vector3::z();
+
+        // earth field measured by the IMU with the front facing the heading
+        let measure = |heading: f32| {
+            let rotation =
+                na::Rotation3::from_axis_angle(&na::Vector3::z_axis(), heading.to_radians());
+            let field = rotation * na::Vector3::new(20e-6, 0.0, -40e-6);
+            field.component_mul(&scale) + hard_iron
+        };
+
+        let mut calibrator = Calibrator::default();
+        assert!(calibrator.finish().is_err());
+
+        // a small part of a turn isn't enough
+        for heading in 0..=20 {
+            calibrator.add(&measure(heading as f32));
+        }
+        assert!(calibrator.finish().is_err());
+
+        for heading in 0..360 {
+            calibrator.add(&measure(heading as f32));
+        }
+        let calibration = calibrator.finish().unwrap();
+
+        for heading in [0.0, 45.0, 90.0, 200.0, 315.0] {
+            let field = calibration.apply(&measure(heading));
+            assert_near(heading, super::heading(&up, &field).unwrap());
+
+            // the vertical field is kept
+            assert!((field.z - measure(heading).z).abs() < 1e-9, "{:?}", field);
+        }
+
+        // stored calibrations are read back unchanged
+        let stored = toml::to_string(&calibration).unwrap();
+        assert_eq!(calibration, toml::from_str::<Calibration>(&stored).unwrap());
+    }
+}
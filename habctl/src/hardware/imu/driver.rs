@@ -0,0 +1,399 @@
+//! ICM-20948 register level driver
+//!
+//! Registers are split over four user banks selected through `REG_BANK_SEL`, which is available
+//! in every bank. Sensor data is in bank 0 and sensor configuration in bank 2.
+
+use super::ak09916;
+use super::bus::I2cBus;
+use crate::hardware::config;
+use anyhow::{bail, Context, Result};
+use nalgebra as na;
+use serde::Deserialize;
+use std::thread;
+use std::time::Duration;
+
+/// Expected contents of `WHO_AM_I`
+pub(super) const DEVICE_ID: u8 = 0xea;
+
+/// Register bank select, present in every bank
+pub(super) const REG_BANK_SEL: u8 = 0x7f;
+
+// bank 0
+pub(super) const WHO_AM_I: u8 = 0x00;
+pub(super) const USER_CTRL: u8 = 0x03;
+pub(super) const PWR_MGMT_1: u8 = 0x06;
+pub(super) const PWR_MGMT_2: u8 = 0x07;
+pub(super) const INT_PIN_CFG: u8 = 0x0f;
+pub(super) const ACCEL_XOUT_H: u8 = 0x2d;
+
+// bank 2
+pub(super) const GYRO_CONFIG_1: u8 = 0x01;
+pub(super) const ACCEL_CONFIG: u8 = 0x14;
+
+// PWR_MGMT_1 bits
+pub(super) const DEVICE_RESET: u8 = 0x80;
+const CLKSEL_AUTO: u8 = 0x01;
+
+// INT_PIN_CFG bits
+pub(super) const BYPASS_EN: u8 = 0x02;
+
+/// Accelerometer, gyrometer and temperature registers, read in one burst
+pub(super) const SENSOR_DATA_LEN: usize = 14;
+
+/// Temperature sensitivity in LSB per deg C
+pub(super) const TEMP_SENSITIVITY: f32 = 333.87;
+
+/// Temperature reading at 0 LSB, in deg C
+pub(super) const TEMP_OFFSET: f32 = 21.0;
+
+/// Accelerometer full-scale range
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum AccelRange {
+    #[default]
+    #[serde(rename = "2g")]
+    G2,
+    #[serde(rename = "4g")]
+    G4,
+    #[serde(rename = "8g")]
+    G8,
+    #[serde(rename = "16g")]
+    G16,
+}
+
+impl AccelRange {
+    /// Value of `ACCEL_FS_SEL`
+    fn fs_sel(self) -> u8 {
+        self as u8
+    }
+
+    pub(super) fn from_fs_sel(fs_sel: u8) -> AccelRange {
+        match fs_sel & 0x03 {
+            0 => AccelRange::G2,
+            1 => AccelRange::G4,
+            2 => AccelRange::G8,
+            _ => AccelRange::G16,
+        }
+    }
+
+    /// Sensitivity in LSB per g
+    pub(super) fn sensitivity(self) -> f32 {
+        16384.0 / (1 << self.fs_sel()) as f32
+    }
+}
+
+/// Gyrometer full-scale range
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum GyroRange {
+    #[default]
+    #[serde(rename = "250dps")]
+    Dps250,
+    #[serde(rename = "500dps")]
+    Dps500,
+    #[serde(rename = "1000dps")]
+    Dps1000,
+    #[serde(rename = "2000dps")]
+    Dps2000,
+}
+
+impl GyroRange {
+    /// Value of `GYRO_FS_SEL`
+    fn fs_sel(self) -> u8 {
+        self as u8
+    }
+
+    pub(super) fn from_fs_sel(fs_sel: u8) -> GyroRange {
+        match fs_sel & 0x03 {
+            0 => GyroRange::Dps250,
+            1 => GyroRange::Dps500,
+            2 => GyroRange::Dps1000,
+            _ => GyroRange::Dps2000,
+        }
+    }
+
+    /// Sensitivity in LSB per degree per second
+    pub(super) fn sensitivity(self) -> f32 {
+        match self {
+            GyroRange::Dps250 => 131.0,
+            GyroRange::Dps500 => 65.5,
+            GyroRange::Dps1000 => 32.8,
+            GyroRange::Dps2000 => 16.4,
+        }
+    }
+}
+
+/// Scaled readings from one burst read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// Acceleration in g
+    pub accelerometer: na::Vector3<f32>,
+
+    /// Rotation rate in degrees per second
+    pub gyrometer: na::Vector3<f32>,
+
+    /// Die temperature in deg C
+    pub temperature: f32,
+
+    /// Magnetic field in Tesla, if a new measurement was ready
+    pub magnetometer: Option<na::Vector3<f32>>,
+}
+
+/// Initialized ICM-20948 on an I2C bus
+pub struct Driver {
+    bus: Box<dyn I2cBus>,
+    address: u16,
+
+    /// Currently selected user bank
+    bank: u8,
+
+    accel_range: AccelRange,
+    gyro_range: GyroRange,
+}
+
+impl Driver {
+    /// Checks the device identity and configures the sensors and magnetometer
+    pub fn open(bus: Box<dyn I2cBus>, config: &config::Imu) -> Result<Driver> {
+        let mut driver = Driver {
+            bus,
+            address: config.address,
+            bank: 0,
+            accel_range: config.accel_range,
+            gyro_range: config.gyro_range,
+        };
+
+        // the selected bank is unknown until written
+        driver
+            .bus
+            .write_byte(driver.address, REG_BANK_SEL, 0)
+            .with_context(|| format!("select bank 0 at address {:#x}", driver.address))?;
+
+        driver.init(config)?;
+
+        // the magnetometer is only reachable once bypass is enabled
+        ak09916::init(driver.bus.as_mut())?;
+
+        Ok(driver)
+    }
+
+    fn init(&mut self, config: &config::Imu) -> Result<()> {
+        let id = self.read(0, WHO_AM_I)?;
+        if id != DEVICE_ID {
+            bail!(
+                "unexpected WHO_AM_I {:#04x}, expected {:#04x}",
+                id,
+                DEVICE_ID
+            );
+        }
+
+        // reset returns every register, including REG_BANK_SEL, to its default
+        self.write(0, PWR_MGMT_1, DEVICE_RESET)?;
+        thread::sleep(Duration::from_millis(100));
+        self.bank = 0;
+
+        // wake from sleep with the best available clock and enable all axes
+        self.write(0, PWR_MGMT_1, CLKSEL_AUTO)?;
+        self.write(0, PWR_MGMT_2, 0x00)?;
+        thread::sleep(Duration::from_millis(20));
+
+        // disable the internal I2C master and connect its bus to the host
+        self.write(0, USER_CTRL, 0x00)?;
+        self.write(0, INT_PIN_CFG, BYPASS_EN)?;
+
+        self.write(
+            2,
+            GYRO_CONFIG_1,
+            sensor_config(self.gyro_range.fs_sel(), config.gyro_dlpf)?,
+        )?;
+        self.write(
+            2,
+            ACCEL_CONFIG,
+            sensor_config(self.accel_range.fs_sel(), config.accel_dlpf)?,
+        )?;
+
+        // leave bank 0 selected for reading samples
+        self.select_bank(0)
+    }
+
+    /// Reads all accelerometer, gyrometer and temperature registers in one transaction, and the
+    /// magnetometer
+    pub fn read_sample(&mut self) -> Result<Sample> {
+        self.select_bank(0)?;
+
+        let mut raw = [0u8; SENSOR_DATA_LEN];
+        self.bus
+            .read_block(self.address, ACCEL_XOUT_H, &mut raw)
+            .context("read sensor data")?;
+
+        let mut sample = decode(&raw, self.accel_range, self.gyro_range);
+        sample.magnetometer = ak09916::read(self.bus.as_mut())?;
+
+        Ok(sample)
+    }
+
+    fn select_bank(&mut self, bank: u8) -> Result<()> {
+        if self.bank != bank {
+            self.bus
+                .write_byte(self.address, REG_BANK_SEL, bank << 4)
+                .with_context(|| format!("select bank {}", bank))?;
+            self.bank = bank;
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, bank: u8, register: u8) -> Result<u8> {
+        self.select_bank(bank)?;
+        self.bus
+            .read_byte(self.address, register)
+            .with_context(|| format!("read bank {} register {:#04x}", bank, register))
+    }
+
+    fn write(&mut self, bank: u8, register: u8, value: u8) -> Result<()> {
+        self.select_bank(bank)?;
+        self.bus
+            .write_byte(self.address, register, value)
+            .with_context(|| format!("write bank {} register {:#04x}", bank, register))
+    }
+}
+
+/// Value of `GYRO_CONFIG_1` or `ACCEL_CONFIG`, with the low pass filter bypassed if not given
+fn sensor_config(fs_sel: u8, dlpf: Option<u8>) -> Result<u8> {
+    match dlpf {
+        Some(dlpf) if dlpf > 7 => bail!("low pass filter setting {} is not 0-7", dlpf),
+        Some(dlpf) => Ok(dlpf << 3 | fs_sel << 1 | 1),
+        None => Ok(fs_sel << 1),
+    }
+}
+
+/// Scales the big-endian two's complement sensor registers
+fn decode(raw: &[u8; SENSOR_DATA_LEN], accel_range: AccelRange, gyro_range: GyroRange) -> Sample {
+    let word = |index: usize| i16::from_be_bytes([raw[index * 2], raw[index * 2 + 1]]) as f32;
+    let vector = |index: usize, sensitivity: f32| {
+        na::Vector3::new(word(index), word(index + 1), word(index + 2)) / sensitivity
+    };
+
+    Sample {
+        accelerometer: vector(0, accel_range.sensitivity()),
+        gyrometer: vector(3, gyro_range.sensitivity()),
+        temperature: word(6) / TEMP_SENSITIVITY + TEMP_OFFSET,
+        magnetometer: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, sensor_config, AccelRange, Driver, GyroRange};
+    use crate::hardware::config;
+    use crate::hardware::imu::compass;
+    use crate::hardware::imu::simulation::SimulatedIcm20948;
+    use nalgebra as na;
+
+    fn open(simulation: config::Simulation, address: u16, config: &str) -> anyhow::Result<Driver> {
+        let config: config::Imu = toml::from_str(config).unwrap();
+        Driver::open(
+            Box::new(SimulatedIcm20948::new(simulation, address)),
+            &config,
+        )
+    }
+
+    fn assert_near(expected: f32, actual: f32, tolerance: f32) {
+        assert!(
+            (expected - actual).abs() < tolerance,
+            "expected {} but got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn decode_signed_scaled() {
+        let raw = [
+            0x40, 0x00, // accel x: 16384
+            0xc0, 0x00, // accel y: -16384
+            0xff, 0xff, // accel z: -1
+            0x00, 0x83, // gyro x: 131
+            0xff, 0x7d, // gyro y: -131
+            0x80, 0x00, // gyro z: -32768
+            0x00, 0x00, // temp: 0
+        ];
+
+        let sample = decode(&raw, AccelRange::G2, GyroRange::Dps250);
+        assert_eq!(
+            na::Vector3::new(1.0, -1.0, -1.0 / 16384.0),
+            sample.accelerometer
+        );
+        assert_eq!(
+            na::Vector3::new(1.0, -1.0, -32768.0 / 131.0),
+            sample.gyrometer
+        );
+        assert_eq!(21.0, sample.temperature);
+
+        let sample = decode(&raw, AccelRange::G16, GyroRange::Dps2000);
+        assert_eq!(8.0, sample.accelerometer.x);
+        assert_eq!(131.0 / 16.4, sample.gyrometer.x);
+    }
+
+    #[test]
+    fn sensor_config_bits() {
+        assert_eq!(0x00, sensor_config(0, None).unwrap());
+        assert_eq!(0x06, sensor_config(3, None).unwrap());
+        assert_eq!(0x2b, sensor_config(1, Some(5)).unwrap());
+        assert!(sensor_config(0, Some(8)).is_err());
+    }
+
+    #[test]
+    fn read_level_device() {
+        let mut driver = open(config::Simulation::default(), 0x69, "").unwrap();
+        let sample = driver.read_sample().unwrap();
+
+        assert!((sample.accelerometer - na::Vector3::z()).norm() < 0.01);
+        assert!(sample.gyrometer.norm() < 0.2);
+        assert_near(25.0, sample.temperature, 0.01);
+
+        let field = sample.magnetometer.unwrap();
+        assert_near(22e-6, field.x, 0.2e-6);
+        assert_near(0.0, field.y, 0.2e-6);
+        assert_near(-42e-6, field.z, 0.2e-6);
+    }
+
+    #[test]
+    fn read_tilted_device_at_configured_range() {
+        let simulation = config::Simulation {
+            roll: 10.0,
+            pitch: 5.0,
+            heading: 90.0,
+            accel_noise: 0.0,
+            gyro_noise: 0.0,
+            ..Default::default()
+        };
+        let mut driver = open(
+            simulation,
+            0x68,
+            "address = 0x68\naccel_range = \"16g\"\ngyro_range = \"2000dps\"\naccel_dlpf = 3",
+        )
+        .unwrap();
+        let sample = driver.read_sample().unwrap();
+
+        let a = sample.accelerometer;
+        assert_near(1.0, a.norm(), 0.001);
+        assert_near(
+            5.0,
+            a.x.atan2((a.y * a.y + a.z * a.z).sqrt()).to_degrees(),
+            0.1,
+        );
+        assert_near(10.0, a.y.atan2(a.z).to_degrees(), 0.1);
+
+        let heading = compass::heading(&a, &sample.magnetometer.unwrap()).unwrap();
+        assert_near(90.0, heading, 0.2);
+    }
+
+    #[test]
+    fn open_fails_without_device() {
+        let error = open(config::Simulation::default(), 0x68, "").err().unwrap();
+        assert!(format!("{:#}", error).contains("no device at address 0x69"));
+
+        let error = open(config::Simulation::default(), 0x69, "gyro_dlpf = 9")
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", error).contains("not 0-7"));
+    }
+}
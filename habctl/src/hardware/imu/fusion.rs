@@ -0,0 +1,205 @@
+//! Orientation filter fusing gyrometer, accelerometer and magnetometer samples
+//!
+//! A Mahony complementary filter: the gyrometer is integrated for orientation, and drift is
+//! corrected towards the up direction from the accelerometer and, when available, north from the
+//! magnetometer. Body axes are x towards the front of the camper, y towards the left side and z up;
+//! the earth frame is north, west, up.
+
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+/// Orientation as angles, in degrees
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EulerAngles {
+    /// Positive with the left side up
+    pub roll: f32,
+
+    /// Positive with the front up
+    pub pitch: f32,
+
+    /// Heading of the front clockwise from magnetic north, only meaningful with a magnetometer
+    pub yaw: f32,
+}
+
+impl From<&na::UnitQuaternion<f32>> for EulerAngles {
+    fn from(orientation: &na::UnitQuaternion<f32>) -> Self {
+        let (roll, pitch, yaw) = orientation.euler_angles();
+
+        // rotations about the left axis lower the front, and about the up axis turn left
+        let yaw = (-yaw.to_degrees()).rem_euclid(360.0);
+        EulerAngles {
+            roll: roll.to_degrees(),
+            pitch: -pitch.to_degrees(),
+            // a tiny negative angle rounds up to a full turn
+            yaw: if yaw < 360.0 { yaw } else { 0.0 },
+        }
+    }
+}
+
+pub struct Fusion {
+    /// Proportional gain of the correction towards the measured directions
+    kp: f32,
+
+    /// Integral gain, which estimates the gyrometer bias
+    ki: f32,
+
+    /// Rotation from body to earth, once initialized from the first sample
+    orientation: Option<na::UnitQuaternion<f32>>,
+
+    /// Integrated correction, in radians per second
+    bias: na::Vector3<f32>,
+}
+
+impl Fusion {
+    pub fn new(kp: f32, ki: f32) -> Fusion {
+        Fusion {
+            kp,
+            ki,
+            orientation: None,
+            bias: na::Vector3::zeros(),
+        }
+    }
+
+    /// Updates with a sample taken `dt` seconds after the previous one, returning the rotation from
+    /// body to earth
+    ///
+    /// The gyrometer is in degrees per second, the accelerometer in g and the magnetometer
+    /// calibrated, in any unit.
+    pub fn update(
+        &mut self,
+        gyrometer: &na::Vector3<f32>,
+        accelerometer: &na::Vector3<f32>,
+        magnetometer: Option<&na::Vector3<f32>>,
+        dt: f32,
+    ) -> Option<na::UnitQuaternion<f32>> {
+        let up = accelerometer.try_normalize(f32::EPSILON)?;
+        let field = magnetometer.and_then(|field| field.try_normalize(f32::EPSILON));
+
+        let orientation = match self.orientation {
+            Some(orientation) => orientation,
+            None => {
+                // start from the measured directions rather than converging from level
+                let orientation = initial_orientation(&up, field.as_ref())?;
+                self.orientation = Some(orientation);
+                return self.orientation;
+            }
+        };
+
+        // error between measured and estimated directions, as a rotation vector in the body
+        let mut error = up.cross(&orientation.inverse_transform_vector(&na::Vector3::z()));
+        if let Some(field) = field {
+            // the field in the earth frame with its horizontal part turned to point north
+            let earth = orientation.transform_vector(&field);
+            let north = na::Vector3::new(earth.xy().norm(), 0.0, earth.z);
+            error += field.cross(&orientation.inverse_transform_vector(&north));
+        }
+
+        self.bias += error * self.ki * dt;
+        let rate = gyrometer.map(f32::to_radians) + error * self.kp + self.bias;
+
+        let orientation = orientation * na::UnitQuaternion::from_scaled_axis(rate * dt);
+        self.orientation = Some(orientation);
+        self.orientation
+    }
+}
+
+/// Orientation from the up direction and magnetic field in the body
+///
+/// Without a field, the front is taken as north.
+fn initial_orientation(
+    up: &na::Vector3<f32>,
+    field: Option<&na::Vector3<f32>>,
+) -> Option<na::UnitQuaternion<f32>> {
+    let west = match field {
+        Some(field) => up.cross(field),
+        None => up.cross(&na::Vector3::x()),
+    }
+    .try_normalize(f32::EPSILON)?;
+    let north = west.cross(up);
+
+    // rows are the earth axes in the body
+    let matrix = na::Matrix3::from_rows(&[north.transpose(), west.transpose(), up.transpose()]);
+    Some(na::UnitQuaternion::from_rotation_matrix(
+        &na::Rotation3::from_matrix_unchecked(matrix),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EulerAngles, Fusion};
+    use nalgebra as na;
+
+    fn assert_near(expected: f32, actual: f32, tolerance: f32) {
+        assert!(
+            (expected - actual).abs() < tolerance,
+            "expected {} but got {}",
+            expected,
+            actual
+        );
+    }
+
+    /// Earth field pointing north and down, in the body of a camper with the given orientation
+    fn measure(orientation: &na::UnitQuaternion<f32>) -> (na::Vector3<f32>, na::Vector3<f32>) {
+        let up = orientation.inverse_transform_vector(&na::Vector3::z());
+        let field = orientation.inverse_transform_vector(&na::Vector3::new(0.4, 0.0, -0.9));
+        (up, field)
+    }
+
+    #[test]
+    fn initial_orientation_from_directions() {
+        // front up 10 degrees, left side up 5 degrees, facing east
+        let actual = na::UnitQuaternion::from_euler_angles(
+            5f32.to_radians(),
+            -10f32.to_radians(),
+            -90f32.to_radians(),
+        );
+        let (up, field) = measure(&actual);
+
+        let mut fusion = Fusion::new(1.0, 0.0);
+        let angles = EulerAngles::from(
+            &fusion
+                .update(&na::Vector3::zeros(), &up, Some(&field), 0.02)
+                .unwrap(),
+        );
+
+        assert_near(5.0, angles.roll, 0.01);
+        assert_near(10.0, angles.pitch, 0.01);
+        assert_near(90.0, angles.yaw, 0.01);
+    }
+
+    #[test]
+    fn gyrometer_integrates_turn() {
+        let mut fusion = Fusion::new(1.0, 0.0);
+        let up = na::Vector3::z();
+        fusion.update(&na::Vector3::zeros(), &up, None, 0.02);
+
+        // turning right at 45 degrees per second for 2 seconds
+        let mut orientation = None;
+        for _ in 0..100 {
+            orientation = fusion.update(&na::Vector3::new(0.0, 0.0, -45.0), &up, None, 0.02);
+        }
+
+        let angles = EulerAngles::from(&orientation.unwrap());
+        assert_near(0.0, angles.roll, 0.01);
+        assert_near(0.0, angles.pitch, 0.01);
+        assert_near(90.0, angles.yaw, 0.1);
+    }
+
+    #[test]
+    fn corrects_gyrometer_bias() {
+        let actual = na::UnitQuaternion::from_euler_angles(0.0, 0.0, -30f32.to_radians());
+        let (up, field) = measure(&actual);
+        let bias = na::Vector3::new(0.5, -0.5, 1.0);
+
+        let mut fusion = Fusion::new(1.0, 0.1);
+        let mut orientation = None;
+        for _ in 0..10000 {
+            orientation = fusion.update(&bias, &up, Some(&field), 0.02);
+        }
+
+        let angles = EulerAngles::from(&orientation.unwrap());
+        assert_near(0.0, angles.roll, 0.05);
+        assert_near(0.0, angles.pitch, 0.05);
+        assert_near(30.0, angles.yaw, 0.05);
+    }
+}
@@ -0,0 +1,213 @@
+//! Detection of the parked camper being moved, bumped or jacked
+//!
+//! Acceleration is compared against a slowly following baseline, so bumps show up as a deviation
+//! while changes of tilt, such as from a jack, show up as the baseline moving away from where it
+//! last settled.
+
+use chrono::{DateTime, Utc};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+/// Time constant of the baseline low pass filter, in seconds
+const BASELINE_SMOOTHING: f32 = 10.0;
+
+/// Time without motion that ends an event, in seconds
+const HOLD: f32 = 2.0;
+
+/// Motion of the camper, from the first to the last reading over a threshold
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MotionEvent {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+
+    /// Largest deviation from the resting acceleration, in g
+    pub peak_g: f32,
+
+    /// Change of tilt from before the event, in degrees
+    pub tilt: f32,
+}
+
+pub struct MotionDetector {
+    /// Deviation in g that counts as motion
+    threshold: f32,
+
+    /// Change of tilt in degrees that counts as motion
+    tilt_threshold: f32,
+
+    /// Low pass filtered acceleration
+    baseline: Option<na::Vector3<f32>>,
+
+    /// Time since the first reading, in seconds
+    elapsed: f32,
+
+    /// Baseline when the camper was last at rest
+    settled: Option<na::Vector3<f32>>,
+
+    current: Option<Current>,
+}
+
+/// Event in progress
+struct Current {
+    event: MotionEvent,
+
+    /// Elapsed time when motion was last seen
+    last_motion: f32,
+
+    /// Tilt when motion was last seen
+    last_tilt: f32,
+}
+
+impl MotionDetector {
+    pub fn new(threshold: f32, tilt_threshold: f32) -> MotionDetector {
+        MotionDetector {
+            threshold,
+            tilt_threshold,
+            baseline: None,
+            elapsed: 0.0,
+            settled: None,
+            current: None,
+        }
+    }
+
+    /// Event in progress
+    pub fn current(&self) -> Option<&MotionEvent> {
+        self.current.as_ref().map(|current| &current.event)
+    }
+
+    /// Updates with a reading taken `dt` seconds after the previous one, returning an event once
+    /// it has ended
+    pub fn update(
+        &mut self,
+        now: DateTime<Utc>,
+        accelerometer: &na::Vector3<f32>,
+        dt: f32,
+    ) -> Option<MotionEvent> {
+        let baseline = match self.baseline {
+            Some(baseline) => {
+                baseline + (accelerometer - baseline) * (dt / (BASELINE_SMOOTHING + dt))
+            }
+            None => *accelerometer,
+        };
+        self.baseline = Some(baseline);
+        let deviation = (accelerometer - baseline).norm();
+
+        // tilt is measured once the baseline has settled from the first readings
+        self.elapsed += dt;
+        if self.elapsed < BASELINE_SMOOTHING {
+            self.settled = Some(baseline);
+        }
+        let tilt = self
+            .settled
+            .map_or(0.0, |settled| settled.angle(&baseline).to_degrees());
+
+        // once started, an event goes on while the tilt keeps changing
+        let moving = deviation > self.threshold
+            || match &self.current {
+                Some(current) => (tilt - current.last_tilt).abs() > self.tilt_threshold / 5.0,
+                None => tilt > self.tilt_threshold,
+            };
+
+        if moving {
+            let elapsed = self.elapsed;
+            let current = self.current.get_or_insert(Current {
+                event: MotionEvent {
+                    start: now,
+                    end: now,
+                    peak_g: 0.0,
+                    tilt: 0.0,
+                },
+                last_motion: elapsed,
+                last_tilt: tilt,
+            });
+            current.event.end = now;
+            current.event.peak_g = current.event.peak_g.max(deviation);
+            current.last_motion = elapsed;
+            current.last_tilt = tilt;
+        }
+
+        match &self.current {
+            Some(current) if self.elapsed - current.last_motion >= HOLD => {
+                let mut event = self.current.take()?.event;
+                event.tilt = tilt;
+                self.settled = Some(baseline);
+                Some(event)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MotionDetector;
+    use chrono::{Duration, TimeZone, Utc};
+    use nalgebra as na;
+
+    const DT: f32 = 0.02;
+
+    /// Runs the detector over readings at 50 Hz, returning the events
+    fn detect(
+        detector: &mut MotionDetector,
+        seconds: f32,
+        reading: impl Fn(f32) -> na::Vector3<f32>,
+    ) -> Vec<super::MotionEvent> {
+        (0..(seconds / DT) as usize)
+            .filter_map(|i| {
+                let t = i as f32 * DT;
+                let now = Utc.timestamp(1_600_000_000, 0) + Duration::milliseconds(i as i64 * 20);
+                detector.update(now, &reading(t), DT)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quiet_camper_has_no_events() {
+        let mut detector = MotionDetector::new(0.05, 0.5);
+        let events = detect(&mut detector, 60.0, |t| {
+            na::Vector3::new(0.01 * (t * 7.0).sin(), 0.01 * (t * 3.0).cos(), 1.0)
+        });
+
+        assert!(events.is_empty());
+        assert!(detector.current().is_none());
+    }
+
+    #[test]
+    fn bump_reports_peak() {
+        let mut detector = MotionDetector::new(0.05, 0.5);
+        let events = detect(&mut detector, 30.0, |t| {
+            if (10.0..10.1).contains(&t) {
+                na::Vector3::new(0.3, 0.0, 1.0)
+            } else {
+                na::Vector3::z()
+            }
+        });
+
+        assert_eq!(1, events.len());
+        let event = &events[0];
+        let start = Utc.timestamp(1_600_000_010, 0);
+        assert!(
+            (event.start - start).num_milliseconds().abs() < 50,
+            "{:?}",
+            event
+        );
+        assert!((event.end - start).num_milliseconds() < 200, "{:?}", event);
+        assert!((event.peak_g - 0.3).abs() < 0.01, "{:?}", event);
+        assert!(event.tilt < 0.5, "{:?}", event);
+    }
+
+    #[test]
+    fn jacking_reports_tilt() {
+        let mut detector = MotionDetector::new(0.05, 0.5);
+
+        // left side raised 3 degrees over 30 seconds, then left to settle
+        let events = detect(&mut detector, 120.0, |t| {
+            let angle = (3.0 * (t - 10.0).clamp(0.0, 30.0) / 30.0).to_radians();
+            na::Vector3::new(0.0, angle.sin(), angle.cos())
+        });
+
+        let tilt: f32 = events.iter().map(|event| event.tilt).sum();
+        assert!(!events.is_empty());
+        assert!((tilt - 3.0).abs() < 0.5, "{:?}", events);
+        assert!(detector.current().is_none());
+    }
+}
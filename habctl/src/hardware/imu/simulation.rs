@@ -0,0 +1,274 @@
+//! Simulated ICM-20948 register map
+//!
+//! Answers on the I2C bus like the device, with readings generated from the configured orientation,
+//! noise and motion script, so loopback IMUs produce frames and the driver can be tested.
+
+use super::ak09916;
+use super::bus::I2cBus;
+use super::driver::{
+    AccelRange, GyroRange, ACCEL_CONFIG, ACCEL_XOUT_H, BYPASS_EN, DEVICE_ID, DEVICE_RESET,
+    GYRO_CONFIG_1, INT_PIN_CFG, PWR_MGMT_1, REG_BANK_SEL, TEMP_OFFSET, TEMP_SENSITIVITY, WHO_AM_I,
+};
+use crate::hardware::config;
+use nalgebra as na;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::io;
+use std::time::Instant;
+
+/// Registers in each user bank
+const BANK_LEN: usize = 0x80;
+
+/// Magnetometer registers
+const MAGNETOMETER_LEN: usize = 0x33;
+
+// PWR_MGMT_1 bits
+const SLEEP: u8 = 0x40;
+
+/// Horizontal earth field towards north, in Tesla
+const EARTH_FIELD_NORTH: f32 = 22e-6;
+
+/// Vertical earth field, in Tesla
+const EARTH_FIELD_UP: f32 = -42e-6;
+
+/// Die temperature, in deg C
+const TEMPERATURE: f32 = 25.0;
+
+pub struct SimulatedIcm20948 {
+    config: config::Simulation,
+    address: u16,
+
+    banks: [[u8; BANK_LEN]; 4],
+    bank: usize,
+    magnetometer: [u8; MAGNETOMETER_LEN],
+
+    started: Instant,
+    rng: StdRng,
+}
+
+/// Simulated camper at one point in the motion script
+struct State {
+    /// Rotation from the IMU axes to north, west, up
+    orientation: na::UnitQuaternion<f32>,
+
+    /// Acceleration besides gravity on the IMU axes, in g
+    acceleration: na::Vector3<f32>,
+
+    /// Rotation rate on the IMU axes, in degrees per second
+    rate: na::Vector3<f32>,
+}
+
+impl SimulatedIcm20948 {
+    pub fn new(config: config::Simulation, address: u16) -> SimulatedIcm20948 {
+        let mut device = SimulatedIcm20948 {
+            config,
+            address,
+            banks: [[0; BANK_LEN]; 4],
+            bank: 0,
+            magnetometer: [0; MAGNETOMETER_LEN],
+            started: Instant::now(),
+            rng: StdRng::seed_from_u64(0),
+        };
+        device.reset();
+        device.reset_magnetometer();
+
+        device
+    }
+
+    fn reset(&mut self) {
+        self.banks = [[0; BANK_LEN]; 4];
+        self.bank = 0;
+        self.banks[0][WHO_AM_I as usize] = DEVICE_ID;
+        self.banks[0][PWR_MGMT_1 as usize] = SLEEP | 0x01;
+        self.banks[2][GYRO_CONFIG_1 as usize] = 0x01;
+        self.banks[2][ACCEL_CONFIG as usize] = 0x01;
+    }
+
+    fn reset_magnetometer(&mut self) {
+        self.magnetometer = [0; MAGNETOMETER_LEN];
+        self.magnetometer[ak09916::WIA2 as usize] = ak09916::DEVICE_ID;
+    }
+
+    /// Whether the magnetometer is connected to the host bus
+    fn bypass(&self) -> bool {
+        self.banks[0][INT_PIN_CFG as usize] & BYPASS_EN != 0
+    }
+
+    /// Seconds into the motion script
+    fn script_time(&self) -> f32 {
+        let elapsed = self.started.elapsed().as_secs_f32();
+        match self.config.repeat {
+            Some(period) if period > 0.0 => elapsed % period,
+            _ => elapsed,
+        }
+    }
+
+    fn state(&self, time: f32) -> State {
+        let mut angles = na::Vector3::new(self.config.roll, self.config.pitch, self.config.heading);
+        let mut angle_rates = na::Vector3::zeros();
+        let mut acceleration = na::Vector3::zeros();
+
+        for motion in &self.config.motion {
+            let change = na::Vector3::new(motion.roll, motion.pitch, motion.heading);
+            let elapsed = time - motion.start;
+            if motion.duration <= 0.0 || elapsed >= motion.duration {
+                angles += change;
+            } else if elapsed >= 0.0 {
+                angles += change * elapsed / motion.duration;
+                angle_rates += change / motion.duration;
+                acceleration += na::Vector3::from(motion.acceleration);
+            }
+        }
+
+        // pitch and heading turn the opposite way to rotations about the left and up axes
+        State {
+            orientation: na::UnitQuaternion::from_euler_angles(
+                angles.x.to_radians(),
+                -angles.y.to_radians(),
+                -angles.z.to_radians(),
+            ),
+            acceleration,
+            rate: na::Vector3::new(angle_rates.x, -angle_rates.y, -angle_rates.z),
+        }
+    }
+
+    /// Writes a new accelerometer, gyrometer and temperature reading to the data registers
+    fn sample(&mut self) {
+        if self.banks[0][PWR_MGMT_1 as usize] & SLEEP != 0 {
+            return;
+        }
+
+        let state = self.state(self.script_time());
+        let accel_noise = self.noise(self.config.accel_noise);
+        let gyro_noise = self.noise(self.config.gyro_noise);
+
+        let gravity = state
+            .orientation
+            .inverse_transform_vector(&na::Vector3::z());
+        let accelerometer = (gravity + state.acceleration + accel_noise)
+            * AccelRange::from_fs_sel(self.banks[2][ACCEL_CONFIG as usize] >> 1).sensitivity();
+        let gyrometer = (state.rate + gyro_noise)
+            * GyroRange::from_fs_sel(self.banks[2][GYRO_CONFIG_1 as usize] >> 1).sensitivity();
+        let temperature = (TEMPERATURE - TEMP_OFFSET) * TEMP_SENSITIVITY;
+
+        let words = [
+            accelerometer.x,
+            accelerometer.y,
+            accelerometer.z,
+            gyrometer.x,
+            gyrometer.y,
+            gyrometer.z,
+            temperature,
+        ];
+        for (index, word) in words.iter().enumerate() {
+            let register = ACCEL_XOUT_H as usize + index * 2;
+            self.banks[0][register..register + 2].copy_from_slice(&saturate(*word).to_be_bytes());
+        }
+    }
+
+    /// Writes a new measurement to the magnetometer data registers
+    fn sample_magnetometer(&mut self) {
+        let st1 = ak09916::ST1 as usize;
+        if self.magnetometer[ak09916::CNTL2 as usize] == ak09916::POWER_DOWN {
+            self.magnetometer[st1] = 0;
+            return;
+        }
+
+        let state = self.state(self.script_time());
+        let earth_field = na::Vector3::new(EARTH_FIELD_NORTH, 0.0, EARTH_FIELD_UP);
+        let field = state.orientation.inverse_transform_vector(&earth_field) / ak09916::SENSITIVITY;
+
+        // the magnetometer y and z axes point opposite to the accelerometer's
+        let words = [field.x, -field.y, -field.z];
+        self.magnetometer[st1] = ak09916::DRDY;
+        for (index, word) in words.iter().enumerate() {
+            let register = st1 + 1 + index * 2;
+            self.magnetometer[register..register + 2]
+                .copy_from_slice(&saturate(*word).to_le_bytes());
+        }
+        self.magnetometer[st1 + ak09916::MEASUREMENT_LEN - 1] = 0;
+    }
+
+    fn noise(&mut self, amplitude: f32) -> na::Vector3<f32> {
+        if amplitude > 0.0 {
+            na::Vector3::from_fn(|_, _| self.rng.gen_range(-amplitude..amplitude))
+        } else {
+            na::Vector3::zeros()
+        }
+    }
+
+    /// Registers of the device at the address, sampling new readings when data is read
+    fn registers(&mut self, address: u16, register: u8) -> io::Result<&mut [u8]> {
+        if address == self.address {
+            if self.bank == 0 && register == ACCEL_XOUT_H {
+                self.sample();
+            }
+            Ok(&mut self.banks[self.bank][..])
+        } else if address == ak09916::ADDRESS && self.bypass() {
+            if register == ak09916::ST1 {
+                self.sample_magnetometer();
+            }
+            Ok(&mut self.magnetometer[..])
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no device at address {:#x}", address),
+            ))
+        }
+    }
+}
+
+impl I2cBus for SimulatedIcm20948 {
+    fn read_byte(&mut self, address: u16, register: u8) -> io::Result<u8> {
+        let mut value = [0];
+        self.read_block(address, register, &mut value)?;
+        Ok(value[0])
+    }
+
+    fn write_byte(&mut self, address: u16, register: u8, value: u8) -> io::Result<()> {
+        // the bank select and resets act rather than being stored
+        if address == self.address {
+            match register {
+                REG_BANK_SEL => {
+                    self.bank = (value >> 4 & 0x03) as usize;
+                    return Ok(());
+                }
+                PWR_MGMT_1 if self.bank == 0 && value & DEVICE_RESET != 0 => {
+                    self.reset();
+                    return Ok(());
+                }
+                _ => {}
+            }
+        } else if address == ak09916::ADDRESS
+            && register == ak09916::CNTL3
+            && value & ak09916::SRST != 0
+        {
+            self.registers(address, register)?;
+            self.reset_magnetometer();
+            return Ok(());
+        }
+
+        let registers = self.registers(address, register)?;
+        *registers.get_mut(register as usize).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "register out of range")
+        })? = value;
+
+        Ok(())
+    }
+
+    fn read_block(&mut self, address: u16, register: u8, buffer: &mut [u8]) -> io::Result<()> {
+        let registers = self.registers(address, register)?;
+        let start = register as usize;
+        let data = registers
+            .get(start..start + buffer.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "read out of range"))?;
+        buffer.copy_from_slice(data);
+
+        Ok(())
+    }
+}
+
+/// Rounds a reading to a register value, clamped to the full-scale range
+fn saturate(value: f32) -> i16 {
+    value.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
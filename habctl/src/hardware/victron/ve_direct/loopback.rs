@@ -0,0 +1,278 @@
+//! Telemetry from loopback MPPT controllers, for development without hardware
+//!
+//! Either replays a captured VE.Direct stream, or generates frames following the sun through the
+//! day.
+
+use crate::hardware::config;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local, Timelike};
+use hab_victron::ve_direct::{
+    ErrorCode, FrameDecoder, Mppt, MpptFrame, OffReason, StateOfOperation, Value,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Source of frames for a loopback controller
+pub enum Loopback {
+    Replay(Replay),
+    Synthetic(Box<Synthetic>),
+}
+
+impl Loopback {
+    pub fn new(config: &config::Mppt) -> Result<Loopback> {
+        Ok(match &config.replay {
+            Some(path) => Loopback::Replay(Replay::open(path)?),
+            None => Loopback::Synthetic(Box::new(Synthetic::new(config.synthetic.clone())?)),
+        })
+    }
+
+    /// Frame to report at the given time, about once a second
+    pub fn next_frame(&mut self, now: DateTime<Local>) -> MpptFrame {
+        match self {
+            Loopback::Replay(replay) => replay.next_frame(),
+            Loopback::Synthetic(synthetic) => synthetic.next_frame(now),
+        }
+    }
+}
+
+/// Frames decoded from a capture, repeated from the start once all are sent
+pub struct Replay {
+    frames: Vec<MpptFrame>,
+    index: usize,
+}
+
+impl Replay {
+    pub fn open(path: &str) -> Result<Replay> {
+        let capture = std::fs::read(path).with_context(|| format!("read {}", path))?;
+
+        let mut decoder = FrameDecoder::default();
+        let frames: Vec<MpptFrame> = capture
+            .iter()
+            .filter_map(|byte| decoder.decode_byte(*byte))
+            .collect();
+        if frames.is_empty() {
+            bail!("no VE.Direct frames in {}", path);
+        }
+
+        Ok(Replay { frames, index: 0 })
+    }
+
+    pub fn next_frame(&mut self) -> MpptFrame {
+        let frame = self.frames[self.index].clone();
+        self.index = (self.index + 1) % self.frames.len();
+        frame
+    }
+}
+
+/// Controller charging from panels in clear sky
+pub struct Synthetic {
+    config: config::Synthetic,
+    rng: StdRng,
+
+    /// Time of the last frame
+    updated: Option<DateTime<Local>>,
+
+    /// Yields in Wh, kept fractional between frames
+    yield_total: f64,
+    yield_today: f64,
+    yield_yesterday: f64,
+
+    maximum_power_today: u16,
+    maximum_power_yesterday: u16,
+    day_number: u16,
+}
+
+impl Synthetic {
+    pub fn new(config: config::Synthetic) -> Result<Synthetic> {
+        let (sunrise, sunset) = (config.sunrise, config.sunset);
+        if !(0.0 <= sunrise && sunrise < sunset && sunset <= 24.0) {
+            bail!(
+                "sunrise ({}) must be before sunset ({}), both within the day",
+                sunrise,
+                sunset
+            );
+        }
+
+        Ok(Synthetic {
+            config,
+            rng: StdRng::seed_from_u64(0),
+            updated: None,
+            yield_total: 0.0,
+            yield_today: 0.0,
+            yield_yesterday: 0.0,
+            maximum_power_today: 0,
+            maximum_power_yesterday: 0,
+            day_number: 0,
+        })
+    }
+
+    pub fn next_frame(&mut self, now: DateTime<Local>) -> MpptFrame {
+        let elapsed = match self.updated.replace(now) {
+            Some(updated) => {
+                if updated.date() != now.date() {
+                    self.yield_yesterday = self.yield_today;
+                    self.maximum_power_yesterday = self.maximum_power_today;
+                    self.yield_today = 0.0;
+                    self.maximum_power_today = 0;
+                    self.day_number = (self.day_number + 1) % 365;
+                }
+                (now - updated).num_milliseconds().max(0) as f64 / 1000.0
+            }
+            None => 0.0,
+        };
+
+        // fraction of the way from sunrise to sunset
+        let hour = now.num_seconds_from_midnight() as f64 / 3600.0;
+        let day = (hour - self.config.sunrise) / (self.config.sunset - self.config.sunrise);
+
+        let (state, limit) = if !(0.0..1.0).contains(&day) {
+            (StateOfOperation::Off, 0.0)
+        } else if day < 0.35 {
+            (StateOfOperation::Bulk, 1.0)
+        } else if day < 0.6 {
+            (StateOfOperation::Absorption, 0.5)
+        } else {
+            (StateOfOperation::Float, 0.2)
+        };
+
+        let available = self.config.peak_power * (day * std::f64::consts::PI).sin().max(0.0);
+        let power = if state == StateOfOperation::Off {
+            0.0
+        } else {
+            (available * limit * self.rng.gen_range(0.95..1.0)).round()
+        };
+
+        let battery_voltage = match state {
+            StateOfOperation::Bulk => 12.9 + 1.5 * day / 0.35,
+            StateOfOperation::Absorption => 14.4,
+            StateOfOperation::Float => 13.5,
+            _ => 12.7,
+        };
+        let panel_voltage = if available > 0.0 {
+            self.config.panel_voltage * (0.9 + 0.1 * available / self.config.peak_power)
+        } else {
+            0.0
+        };
+
+        let energy = power * elapsed / 3600.0;
+        self.yield_today += energy;
+        self.yield_total += energy;
+        self.maximum_power_today = self.maximum_power_today.max(power as u16);
+
+        // yields are reported in steps of 10 Wh
+        let yield_steps = |wh: f64| (wh / 10.0).floor() as u32 * 10;
+
+        MpptFrame {
+            battery_voltage: Some(round(battery_voltage, 2)),
+            panel_voltage: Some(round(panel_voltage, 2)),
+            panel_power: Some(power as u16),
+            battery_current: Some(round(power / battery_voltage, 2)),
+            load_current: Some(0.2),
+            load_state: Some(true),
+            relay_state: None,
+            off_reason: Some(if state == StateOfOperation::Off {
+                OffReason::NO_INPUT_POWER
+            } else {
+                OffReason::NONE
+            }),
+            yield_total: Some(yield_steps(self.yield_total)),
+            yield_today: Some(yield_steps(self.yield_today)),
+            maximum_power_today: Some(self.maximum_power_today),
+            yield_yesterday: Some(yield_steps(self.yield_yesterday)),
+            maximum_power_yesterday: Some(self.maximum_power_yesterday),
+            error: Some(ErrorCode::NoError),
+            state: Some(state),
+            firmware_version: Value::from("150").ok(),
+            firmware_version_24: None,
+            product_id: Some(0xa05f),
+            serial_number: Value::from("HQ0000LOOP0").ok(),
+            day_number: Some(self.day_number),
+            mppt_status: Some(match state {
+                StateOfOperation::Off => Mppt::Off,
+                StateOfOperation::Bulk => Mppt::MpptTrackerActive,
+                _ => Mppt::VoltageOrCurrentLimited,
+            }),
+        }
+    }
+}
+
+/// Rounds to the resolution the controller reports
+fn round(value: f64, decimals: i32) -> f64 {
+    let scale = 10f64.powi(decimals);
+    (value * scale).round() / scale
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Replay, Synthetic};
+    use crate::hardware::config;
+    use chrono::{Duration, Local, TimeZone};
+    use hab_victron::ve_direct::{Mppt, StateOfOperation};
+
+    #[test]
+    fn synthetic_follows_the_sun() {
+        let mut synthetic = Synthetic::new(config::Synthetic::default()).unwrap();
+        let midnight = Local.ymd(2021, 6, 1).and_hms(0, 0, 0);
+
+        let night = synthetic.next_frame(midnight + Duration::hours(3));
+        assert_eq!(Some(StateOfOperation::Off), night.state);
+        assert_eq!(Some(0), night.panel_power);
+        assert_eq!(Some(Mppt::Off), night.mppt_status);
+
+        let morning = synthetic.next_frame(midnight + Duration::hours(9));
+        assert_eq!(Some(StateOfOperation::Bulk), morning.state);
+        assert!(morning.panel_power.unwrap() > 0);
+        assert!(morning.battery_current.unwrap() > 0.0);
+
+        // one frame a second through the rest of the day
+        let mut frame = morning;
+        let mut time = midnight + Duration::hours(9);
+        while time < midnight + Duration::hours(23) {
+            time = time + Duration::seconds(1);
+            frame = synthetic.next_frame(time);
+            assert!(frame.panel_power.unwrap() <= 400);
+        }
+        assert_eq!(Some(StateOfOperation::Off), frame.state);
+        let yield_today = frame.yield_today.unwrap();
+        assert!(yield_today > 500 && yield_today < 4000, "{}", yield_today);
+
+        let tomorrow = synthetic.next_frame(midnight + Duration::hours(25));
+        assert_eq!(Some(0), tomorrow.yield_today);
+        assert_eq!(Some(yield_today), tomorrow.yield_yesterday);
+        assert_eq!(Some(1), tomorrow.day_number);
+    }
+
+    #[test]
+    fn synthetic_rejects_sunset_not_after_sunrise() {
+        let day = |sunrise, sunset| {
+            Synthetic::new(config::Synthetic {
+                sunrise,
+                sunset,
+                ..config::Synthetic::default()
+            })
+        };
+
+        assert!(day(6.0, 20.0).is_ok());
+        assert!(day(12.0, 12.0).is_err());
+        assert!(day(20.0, 6.0).is_err());
+        assert!(day(6.0, 25.0).is_err());
+        assert!(day(f64::NAN, 20.0).is_err());
+    }
+
+    #[test]
+    fn replay_repeats_capture() {
+        let mut replay = Replay::open(
+            "../hab-victron/test/usb-VictronEnergy_BV_VE_Direct_cable_VE46V0KW-if00-port0",
+        )
+        .unwrap();
+        let first = replay.next_frame();
+        assert_eq!(Some(13.38), first.battery_voltage);
+
+        for _ in 1..298 {
+            replay.next_frame();
+        }
+        assert_eq!(first, replay.next_frame());
+
+        assert!(Replay::open("test/missing").is_err());
+    }
+}
@@ -0,0 +1,243 @@
+//! Leveling assistant
+//!
+//! Computes pitch and roll from the low pass filtered up direction of an IMU, relative to a
+//! reference captured once while parked on known-flat ground, and how much each wheel must be
+//! raised to level the camper.
+
+pub mod config;
+
+use crate::hardware::imu::Icm20948;
+use crate::hardware::Hardware;
+use anyhow::{anyhow, Result};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio::time::{self, Duration, Instant};
+
+/// Interval between accelerometer samples
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Leveling {
+    config: &'static config::Leveling,
+    imu: Arc<Icm20948>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Low pass filtered accelerometer vector, in g
+    accelerometer: Option<na::Vector3<f32>>,
+
+    /// When the filter was last updated
+    updated: Option<Instant>,
+
+    /// Tilt of the IMU when the camper is level
+    reference: Option<Tilt>,
+}
+
+/// Pitch and roll, in degrees
+///
+/// Pitch is positive with the front up, roll is positive with the left side up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tilt {
+    pub pitch: f32,
+    pub roll: f32,
+}
+
+/// Height to raise each wheel, in inches
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Blocks {
+    pub front_left: f32,
+    pub front_right: f32,
+    pub rear_left: f32,
+    pub rear_right: f32,
+}
+
+/// Current leveling state
+#[derive(Clone, Debug, Serialize)]
+pub struct LevelReport {
+    /// Tilt relative to the reference, or to the IMU axes if not calibrated
+    pub tilt: Tilt,
+
+    /// Whether a level reference has been captured
+    pub calibrated: bool,
+
+    pub blocks: Blocks,
+}
+
+impl Leveling {
+    pub fn new(config: &'static config::Leveling, hardware: &Hardware) -> Result<Arc<Leveling>> {
+        let imu = hardware
+            .imu(&config.imu)
+            .ok_or_else(|| anyhow!("leveling imu \"{}\" is not configured", config.imu))?;
+
+        let reference = match std::fs::read_to_string(&config.reference_path) {
+            Ok(contents) => Some(toml::from_str(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Arc::new(Leveling {
+            config,
+            imu,
+            state: Mutex::new(State {
+                reference,
+                ..Default::default()
+            }),
+        }))
+    }
+
+    /// Samples the accelerometer until the process exits
+    pub async fn run(&self) -> Result<()> {
+        let mut interval = time::interval(SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            // the fused orientation keeps people moving about inside out of the up direction
+            let accelerometer = {
+                let frame = self.imu.telemetry.lock().unwrap();
+                frame
+                    .orientation
+                    .map(|orientation| orientation.inverse_transform_vector(&na::Vector3::z()))
+                    .or(frame.accelerometer)
+            };
+            if let Some(accelerometer) = accelerometer {
+                self.update(accelerometer, Instant::now());
+            }
+        }
+    }
+
+    fn update(&self, accelerometer: na::Vector3<f32>, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+
+        state.accelerometer = Some(match (state.accelerometer, state.updated) {
+            (Some(filtered), Some(updated)) => {
+                let dt = now.duration_since(updated).as_secs_f32();
+                let alpha = dt / (self.config.smoothing + dt);
+                filtered + (accelerometer - filtered) * alpha
+            }
+            _ => accelerometer,
+        });
+        state.updated = Some(now);
+    }
+
+    /// Current tilt and blocks, if the accelerometer has been read
+    pub fn report(&self) -> Option<LevelReport> {
+        let state = self.state.lock().unwrap();
+        let tilt = tilt(&state.accelerometer?);
+
+        let tilt = match state.reference {
+            Some(reference) => Tilt {
+                pitch: tilt.pitch - reference.pitch,
+                roll: tilt.roll - reference.roll,
+            },
+            None => tilt,
+        };
+
+        Some(LevelReport {
+            tilt,
+            calibrated: state.reference.is_some(),
+            blocks: blocks(&tilt, self.config.wheelbase, self.config.track),
+        })
+    }
+
+    /// Captures the current tilt as level and stores it for future runs
+    pub fn calibrate(&self) -> Result<Tilt> {
+        let mut state = self.state.lock().unwrap();
+        let reference = tilt(
+            &state
+                .accelerometer
+                .ok_or_else(|| anyhow!("no accelerometer reading yet"))?,
+        );
+
+        std::fs::write(&self.config.reference_path, toml::to_string(&reference)?)?;
+        log::info!("leveling reference captured: {:?}", reference);
+        state.reference = Some(reference);
+
+        Ok(reference)
+    }
+}
+
+/// Tilt of the IMU from the direction of gravity
+fn tilt(accelerometer: &na::Vector3<f32>) -> Tilt {
+    let (x, y, z) = (accelerometer.x, accelerometer.y, accelerometer.z);
+    Tilt {
+        pitch: x.atan2((y * y + z * z).sqrt()).to_degrees(),
+        roll: y.atan2(z).to_degrees(),
+    }
+}
+
+/// Height each wheel must be raised for all of them to reach the highest one
+fn blocks(tilt: &Tilt, wheelbase: f32, track: f32) -> Blocks {
+    // height of a wheel relative to the center, from its position forward and to the left
+    let height = |forward: f32, left: f32| {
+        forward * tilt.pitch.to_radians().sin() + left * tilt.roll.to_radians().sin()
+    };
+
+    let front_left = height(wheelbase / 2.0, track / 2.0);
+    let front_right = height(wheelbase / 2.0, -track / 2.0);
+    let rear_left = height(-wheelbase / 2.0, track / 2.0);
+    let rear_right = height(-wheelbase / 2.0, -track / 2.0);
+
+    let highest = front_left.max(front_right).max(rear_left).max(rear_right);
+
+    Blocks {
+        front_left: highest - front_left,
+        front_right: highest - front_right,
+        rear_left: highest - rear_left,
+        rear_right: highest - rear_right,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{blocks, tilt, Tilt};
+    use nalgebra as na;
+
+    fn assert_near(expected: f32, actual: f32) {
+        assert!(
+            (expected - actual).abs() < 0.01,
+            "expected {} but got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn tilt_from_gravity() {
+        let flat = tilt(&na::Vector3::new(0.0, 0.0, 1.0));
+        assert_near(0.0, flat.pitch);
+        assert_near(0.0, flat.roll);
+
+        // front up 2 degrees
+        let angle = 2.0f32.to_radians();
+        let nose_up = tilt(&na::Vector3::new(angle.sin(), 0.0, angle.cos()));
+        assert_near(2.0, nose_up.pitch);
+        assert_near(0.0, nose_up.roll);
+
+        // right side up 3 degrees
+        let angle = 3.0f32.to_radians();
+        let right_up = tilt(&na::Vector3::new(0.0, -angle.sin(), angle.cos()));
+        assert_near(0.0, right_up.pitch);
+        assert_near(-3.0, right_up.roll);
+    }
+
+    #[test]
+    fn blocks_raise_low_wheels() {
+        // front is 2" higher over a 120" wheelbase: raise both rear wheels
+        let pitch = (2.0f32 / 120.0).asin().to_degrees();
+        let level = blocks(&Tilt { pitch, roll: 0.0 }, 120.0, 70.0);
+        assert_near(0.0, level.front_left);
+        assert_near(0.0, level.front_right);
+        assert_near(2.0, level.rear_left);
+        assert_near(2.0, level.rear_right);
+
+        // left is 1.5" lower over a 70" track: raise both left wheels
+        let roll = -(1.5f32 / 70.0).asin().to_degrees();
+        let level = blocks(&Tilt { pitch: 0.0, roll }, 120.0, 70.0);
+        assert_near(1.5, level.front_left);
+        assert_near(0.0, level.front_right);
+        assert_near(1.5, level.rear_left);
+        assert_near(0.0, level.rear_right);
+    }
+}
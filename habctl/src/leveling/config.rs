@@ -0,0 +1,33 @@
+use serde::Deserialize;
+
+/// Leveling assistant, computing blocks for each wheel from an IMU
+///
+/// The IMU is expected to be mounted with x towards the front of the camper, y towards the left
+/// side and z up.
+#[derive(Deserialize, Debug)]
+pub struct Leveling {
+    /// Name of the IMU in `[hardware.imu]`
+    pub imu: String,
+
+    /// Distance between the front and rear axles, in inches
+    pub wheelbase: f32,
+
+    /// Distance between the left and right wheels, in inches
+    pub track: f32,
+
+    /// Time constant of the accelerometer low pass filter, in seconds
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f32,
+
+    /// File storing the level reference captured by calibration
+    #[serde(default = "default_reference_path")]
+    pub reference_path: String,
+}
+
+fn default_smoothing() -> f32 {
+    5.0
+}
+
+fn default_reference_path() -> String {
+    "leveling-reference.toml".to_string()
+}
@@ -33,11 +33,9 @@
 //     log::debug!("shutting down");
 // }
 
-#[macro_use]
-extern crate bitflags;
-
 mod config;
 mod hardware;
+mod leveling;
 mod web;
 
 use anyhow::Result;
@@ -45,6 +43,7 @@ use std::sync::Arc;
 use tokio::runtime::Runtime;
 
 use crate::config::Config;
+use crate::leveling::Leveling;
 
 fn main() -> Result<()> {
     let rt = Runtime::new()?;
@@ -56,15 +55,32 @@ fn main() -> Result<()> {
 
         log::debug!("loading config");
         Config::load()?;
+        let build = &Config::get().build;
+        log::info!("{} {}", build.name, build.version);
         dbg!(Config::get());
 
         log::debug!("building hardware interfaces");
         let hardware = Arc::new(hardware::Hardware::default());
 
+        let leveling = match &Config::get().leveling {
+            Some(config) => Some(Leveling::new(config, &hardware)?),
+            None => None,
+        };
+
         log::debug!("starting services");
         tokio::try_join!(
-            web::serve(Config::get().web.listen_addr, hardware.clone()),
+            web::serve(
+                Config::get().web.listen_addr,
+                hardware.clone(),
+                leveling.clone()
+            ),
             hardware.run(),
+            async {
+                match &leveling {
+                    Some(leveling) => leveling.run().await,
+                    None => Ok(()),
+                }
+            },
         )?;
 
         log::debug!("exiting");
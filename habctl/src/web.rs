@@ -5,14 +5,21 @@ mod files;
 mod socket;
 
 use crate::hardware::Hardware;
+use crate::leveling::Leveling;
 use anyhow::Result;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use warp::Filter;
 
-pub async fn serve(addr: impl Into<SocketAddr>, hardware: Arc<Hardware>) -> Result<()> {
-    let routes = socket::ui_socket()
-        .or(api::api(hardware))
+pub async fn serve(
+    addr: impl Into<SocketAddr>,
+    hardware: Arc<Hardware>,
+    leveling: Option<Arc<Leveling>>,
+) -> Result<()> {
+    let routes = socket::ui_socket(hardware.clone())
+        .or(api::api(hardware.clone()))
+        .or(api::magnetometer(hardware))
+        .or(api::leveling(leveling))
         .or(files::static_files());
 
     warp::serve(routes).run(addr).await;
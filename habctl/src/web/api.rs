@@ -1,22 +1,241 @@
+use crate::hardware::imu::motion::MotionEvent;
+use crate::hardware::imu::{Icm20948, ImuFrame};
+use crate::hardware::victron::ve_direct::{MpptTelemetry, VeDirectMppt};
 use crate::hardware::Hardware;
+use crate::leveling::Leveling;
+use serde::Serialize;
 use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::Duration;
 use warp::Filter;
 
+/// Time without a frame after which a device is reported as stale
+const STALE_AFTER: Duration = Duration::from_secs(5);
+
+/// Telemetry of all devices, or of the MPPT controllers or a single device
 pub fn api(
     hardware: Arc<Hardware>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("api")
+    let all = warp::path!("api")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and_then(reply::telemetry);
+
+    let mppts = warp::path!("api" / "mppt")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and_then(reply::mppts);
+
+    let mppt = warp::path!("api" / "mppt" / String)
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and_then(reply::mppt);
+
+    let imu = warp::path!("api" / "imu" / String)
         .and(warp::get())
         .and(with_hardware(hardware))
-        .and_then(reply::telemetry)
+        .and_then(reply::imu);
+
+    all.or(mppts).or(mppt).or(imu)
+}
+
+/// Magnetometer calibration of an IMU, started before turning the level camper through a full
+/// circle
+pub fn magnetometer(
+    hardware: Arc<Hardware>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let start = warp::path!("api" / "imu" / String / "magnetometer" / "calibration" / "start")
+        .and(warp::post())
+        .and(with_hardware(hardware.clone()))
+        .and_then(reply::start_magnetometer_calibration);
+
+    let finish = warp::path!("api" / "imu" / String / "magnetometer" / "calibration" / "finish")
+        .and(warp::post())
+        .and(with_hardware(hardware))
+        .and_then(reply::finish_magnetometer_calibration);
+
+    start.or(finish)
+}
+
+/// Leveling report and calibration, which are not found unless leveling is configured
+pub fn leveling(
+    leveling: Option<Arc<Leveling>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let report = warp::path!("api" / "leveling")
+        .and(warp::get())
+        .and(with_leveling(leveling.clone()))
+        .and_then(reply::level_report);
+
+    let calibrate = warp::path!("api" / "leveling" / "calibrate")
+        .and(warp::post())
+        .and(with_leveling(leveling))
+        .and_then(reply::calibrate);
+
+    report.or(calibrate)
+}
+
+#[derive(Serialize)]
+struct HardwareStatus {
+    mppt: Vec<DeviceStatus<MpptTelemetry>>,
+    imu: Vec<DeviceStatus<ImuTelemetry>>,
+}
+
+/// Latest telemetry of a device and how fresh it is
+#[derive(Serialize)]
+struct DeviceStatus<T> {
+    name: String,
+    connection: Connection,
+
+    /// Seconds since the last frame was received
+    age: Option<f32>,
+
+    telemetry: T,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Connection {
+    /// No frame has been received yet
+    Waiting,
+
+    /// Frames are being received
+    Connected,
+
+    /// No frame has been received for a while
+    Stale,
+}
+
+impl Connection {
+    fn from_age(age: Option<Duration>) -> Connection {
+        match age {
+            None => Connection::Waiting,
+            Some(age) if age > STALE_AFTER => Connection::Stale,
+            Some(_) => Connection::Connected,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ImuTelemetry {
+    #[serde(flatten)]
+    frame: ImuFrame,
+
+    /// Most recent motion events, oldest first
+    events: Vec<MotionEvent>,
+}
+
+impl<T> DeviceStatus<T> {
+    fn new(name: &str, age: Option<Duration>, telemetry: T) -> DeviceStatus<T> {
+        DeviceStatus {
+            name: name.to_owned(),
+            connection: Connection::from_age(age),
+            age: age.map(|age| age.as_secs_f32()),
+            telemetry,
+        }
+    }
+}
+
+fn mppt_status(mppt: &VeDirectMppt) -> DeviceStatus<MpptTelemetry> {
+    let telemetry = mppt.telemetry.lock().unwrap().clone();
+    DeviceStatus::new(mppt.name(), mppt.age(), telemetry)
+}
+
+fn imu_status(imu: &Icm20948) -> DeviceStatus<ImuTelemetry> {
+    let telemetry = ImuTelemetry {
+        frame: imu.telemetry.lock().unwrap().clone(),
+        events: imu.events.lock().unwrap().iter().cloned().collect(),
+    };
+    DeviceStatus::new(imu.name(), imu.age(), telemetry)
 }
 
 mod reply {
     use super::*;
+    use warp::http::StatusCode;
 
     pub async fn telemetry(hardware: Arc<Hardware>) -> Result<impl warp::Reply, Infallible> {
-        Ok(warp::reply::json(&hardware))
+        Ok(warp::reply::json(&HardwareStatus {
+            mppt: hardware
+                .mppts()
+                .iter()
+                .map(|mppt| mppt_status(mppt))
+                .collect(),
+            imu: hardware.imus().iter().map(|imu| imu_status(imu)).collect(),
+        }))
+    }
+
+    pub async fn mppts(hardware: Arc<Hardware>) -> Result<impl warp::Reply, Infallible> {
+        let mppts: Vec<_> = hardware
+            .mppts()
+            .iter()
+            .map(|mppt| mppt_status(mppt))
+            .collect();
+        Ok(warp::reply::json(&mppts))
+    }
+
+    pub async fn mppt(
+        name: String,
+        hardware: Arc<Hardware>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mppt = hardware.mppt(&name).ok_or_else(warp::reject::not_found)?;
+        Ok(warp::reply::json(&mppt_status(&mppt)))
+    }
+
+    pub async fn imu(
+        name: String,
+        hardware: Arc<Hardware>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let imu = hardware.imu(&name).ok_or_else(warp::reject::not_found)?;
+        Ok(warp::reply::json(&imu_status(&imu)))
+    }
+
+    pub async fn start_magnetometer_calibration(
+        name: String,
+        hardware: Arc<Hardware>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let imu = hardware.imu(&name).ok_or_else(warp::reject::not_found)?;
+        imu.start_magnetometer_calibration();
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    pub async fn finish_magnetometer_calibration(
+        name: String,
+        hardware: Arc<Hardware>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let imu = hardware.imu(&name).ok_or_else(warp::reject::not_found)?;
+        Ok(match imu.finish_magnetometer_calibration() {
+            Ok(calibration) => {
+                warp::reply::with_status(warp::reply::json(&calibration), StatusCode::OK)
+            }
+            Err(e) => {
+                log::error!("IMU {}: magnetometer calibration failed: {}", name, e);
+                warp::reply::with_status(warp::reply::json(&e.to_string()), StatusCode::CONFLICT)
+            }
+        })
+    }
+
+    pub async fn level_report(leveling: Arc<Leveling>) -> Result<impl warp::Reply, Infallible> {
+        Ok(match leveling.report() {
+            Some(report) => warp::reply::with_status(warp::reply::json(&report), StatusCode::OK),
+            None => warp::reply::with_status(
+                warp::reply::json(&"no accelerometer reading yet"),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+        })
+    }
+
+    pub async fn calibrate(leveling: Arc<Leveling>) -> Result<impl warp::Reply, Infallible> {
+        Ok(match leveling.calibrate() {
+            Ok(reference) => {
+                warp::reply::with_status(warp::reply::json(&reference), StatusCode::OK)
+            }
+            Err(e) => {
+                log::error!("leveling calibration failed: {}", e);
+                warp::reply::with_status(
+                    warp::reply::json(&e.to_string()),
+                    StatusCode::SERVICE_UNAVAILABLE,
+                )
+            }
+        })
     }
 }
 
@@ -25,3 +244,31 @@ fn with_hardware(
 ) -> impl Filter<Extract = (Arc<Hardware>,), Error = Infallible> + Clone {
     warp::any().map(move || hardware.clone())
 }
+
+fn with_leveling(
+    leveling: Option<Arc<Leveling>>,
+) -> impl Filter<Extract = (Arc<Leveling>,), Error = warp::Rejection> + Clone {
+    warp::any().and_then(move || {
+        let leveling = leveling.clone();
+        async move { leveling.ok_or_else(warp::reject::not_found) }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Connection, STALE_AFTER};
+    use std::time::Duration;
+
+    #[test]
+    fn connection_from_age() {
+        assert_eq!(Connection::Waiting, Connection::from_age(None));
+        assert_eq!(
+            Connection::Connected,
+            Connection::from_age(Some(Duration::from_millis(500)))
+        );
+        assert_eq!(
+            Connection::Stale,
+            Connection::from_age(Some(STALE_AFTER + Duration::from_secs(1)))
+        );
+    }
+}
@@ -1,50 +1,264 @@
-use chrono::{DateTime, Utc};
+mod protocol;
+
+use chrono::Utc;
+use futures::stream::SplitStream;
 use futures::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
+use protocol::{Data, ImuUpdate, MpptUpdate, Protocol, Request, VERSION};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::watch;
 use tokio::time::{self, Duration};
-use warp::ws::{Message, WebSocket, Ws};
+use warp::http::StatusCode;
+use warp::ws::{WebSocket, Ws};
 use warp::{Filter, Reply};
 
 use crate::config::Config;
+use crate::hardware::Hardware;
+
+/// Shortest update interval a client may set, in milliseconds
+const MIN_UPDATE_INTERVAL: u64 = 50;
+
+/// What a client has asked to be sent
+#[derive(Debug, Clone)]
+struct Settings {
+    /// Devices to send frames from, all if not set
+    devices: Option<HashSet<String>>,
+
+    interval: Duration,
+}
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Data {
-    Empty,
-    SystemTime(DateTime<Utc>),
+impl Settings {
+    fn subscribed(&self, device: &str) -> bool {
+        match &self.devices {
+            Some(devices) => devices.contains(device),
+            None => true,
+        }
+    }
 }
 
 /// UI Websocket at /socket/ui
-pub fn ui_socket() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+///
+/// The protocol is the first supported subprotocol the client offers, or version 1 bincode if it
+/// offers none.
+pub fn ui_socket(
+    hardware: Arc<Hardware>,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
     warp::path!("socket" / "ui")
         .and(warp::ws())
-        .map(move |ws: Ws| ws.on_upgrade(move |socket| socket_connected(socket)))
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .map(move |ws: Ws, offered: Option<String>| {
+            let protocol = match &offered {
+                Some(offered) => Protocol::negotiate(offered),
+                None => Some(Protocol::default()),
+            };
+
+            match protocol {
+                Some(protocol) => {
+                    let hardware = hardware.clone();
+                    let reply =
+                        ws.on_upgrade(move |socket| socket_connected(socket, hardware, protocol));
+                    if offered.is_some() {
+                        warp::reply::with_header(
+                            reply,
+                            "sec-websocket-protocol",
+                            protocol.to_string(),
+                        )
+                        .into_response()
+                    } else {
+                        reply.into_response()
+                    }
+                }
+                None => warp::reply::with_status(
+                    format!(
+                        "unsupported protocol, expected habctl.v{0}.bincode or habctl.v{0}.json",
+                        VERSION
+                    ),
+                    StatusCode::BAD_REQUEST,
+                )
+                .into_response(),
+            }
+        })
 }
 
 /// Socket has connected
-async fn socket_connected(ws: WebSocket) {
-    let (mut ws_send, mut ws_recv) = ws.split();
+async fn socket_connected(ws: WebSocket, hardware: Arc<Hardware>, protocol: Protocol) {
+    log::debug!("Web client connected with {}", protocol);
+    let (mut ws_send, ws_recv) = ws.split();
 
-    // handle messages from the web client
-    tokio::spawn(async move {
-        while let Some(msg) = ws_recv.next().await {
-            log::debug!("Received {:?}", msg);
-        }
-        log::debug!("Exiting receive task");
+    let (settings_send, mut settings) = watch::channel(Settings {
+        devices: None,
+        interval: Duration::from_millis(Config::get().web.update_interval),
     });
 
-    // periodically send telemetry until disconnected
-    let mut interval = time::interval(Duration::from_millis(Config::get().web.update_interval));
+    // handle messages from the web client
+    tokio::spawn(receive(ws_recv, settings_send, protocol));
+
+    // periodically send telemetry that has changed until disconnected
+    let mut sent = HashMap::new();
+    let mut sequence = 0;
+    let mut interval = time::interval(settings.borrow().interval);
     loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            changed = settings.changed() => {
+                if changed.is_err() {
+                    log::debug!("Exiting send task: receive task has exited");
+                    break;
+                }
+                interval = time::interval(settings.borrow().interval);
+                continue;
+            }
+        }
+
+        let mut messages = vec![Data::SystemTime(Utc::now())];
+        messages.extend(changed(latest(&hardware), &settings.borrow(), &mut sent));
+
         // send telemetry, exiting handler on error
-        let msg = Data::SystemTime(Utc::now());
-        if let Err(e) = ws_send
-            .send(Message::binary(bincode::serialize(&msg).unwrap()))
-            .await
-        {
-            log::debug!("Exiting send task: {:?}", e);
-            break;
+        for msg in messages {
+            let msg = match msg.encode(protocol, sequence) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log::error!("Failed to encode {:?}: {:#}", msg, e);
+                    continue;
+                }
+            };
+            sequence += 1;
+
+            if let Err(e) = ws_send.send(msg).await {
+                log::debug!("Exiting send task: {:?}", e);
+                return;
+            }
         }
+    }
+}
+
+/// Applies requests from the web client to its settings until it disconnects
+async fn receive(
+    mut ws_recv: SplitStream<WebSocket>,
+    settings: watch::Sender<Settings>,
+    protocol: Protocol,
+) {
+    while let Some(msg) = ws_recv.next().await {
+        let msg = match msg {
+            Ok(msg) if msg.is_binary() || msg.is_text() => msg,
+            Ok(msg) => {
+                log::debug!("Received {:?}", msg);
+                continue;
+            }
+            Err(e) => {
+                log::debug!("Receive error: {:?}", e);
+                break;
+            }
+        };
+
+        let request = match Request::decode(protocol, msg.as_bytes()) {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                log::debug!("Skipping unknown request {:?}", msg);
+                continue;
+            }
+            Err(e) => {
+                log::warn!("Invalid request from web client: {:#}", e);
+                continue;
+            }
+        };
+        log::debug!("Received {:?}", request);
+
+        settings.send_modify(|settings| match request {
+            Request::Subscribe(devices) => {
+                settings.devices = Some(devices.into_iter().collect());
+            }
+            Request::SetRate(interval) => {
+                settings.interval = Duration::from_millis(interval.max(MIN_UPDATE_INTERVAL));
+            }
+        });
+    }
+    log::debug!("Exiting receive task");
+}
+
+/// Latest frame from each device that has received one
+fn latest(hardware: &Hardware) -> Vec<Data> {
+    let mppts = hardware.mppts().iter().filter_map(|mppt| {
+        let telemetry = mppt.telemetry.lock().unwrap().clone();
+        telemetry.timestamp.map(|_| {
+            Data::Mppt(MpptUpdate {
+                name: mppt.name().to_owned(),
+                timestamp: telemetry.timestamp,
+                frame: telemetry.frame,
+            })
+        })
+    });
+
+    let imus = hardware.imus().iter().filter_map(|imu| {
+        let frame = imu.telemetry.lock().unwrap().clone();
+        frame.timestamp.map(|_| {
+            Data::Imu(ImuUpdate {
+                name: imu.name().to_owned(),
+                frame,
+            })
+        })
+    });
+
+    mppts.chain(imus).collect()
+}
+
+/// Frames from subscribed devices that differ from the last ones sent
+fn changed(latest: Vec<Data>, settings: &Settings, sent: &mut HashMap<String, Data>) -> Vec<Data> {
+    latest
+        .into_iter()
+        .filter(|data| {
+            let device = data.device().unwrap_or_default();
+            if !settings.subscribed(device) || sent.get(device) == Some(data) {
+                return false;
+            }
+
+            sent.insert(device.to_owned(), data.clone());
+            true
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{changed, Data, MpptUpdate, Settings};
+    use hab_victron::ve_direct::MpptFrame;
+    use std::collections::HashMap;
+    use tokio::time::Duration;
+
+    fn mppt(name: &str, battery_voltage: f64) -> Data {
+        Data::Mppt(MpptUpdate {
+            name: name.to_owned(),
+            timestamp: Some(1.0),
+            frame: MpptFrame {
+                battery_voltage: Some(battery_voltage),
+                ..MpptFrame::default()
+            },
+        })
+    }
+
+    #[test]
+    fn sends_changed_frames_of_subscribed_devices() {
+        let mut settings = Settings {
+            devices: None,
+            interval: Duration::from_secs(1),
+        };
+        let mut sent = HashMap::new();
+
+        let frames = vec![mppt("big", 13.2), mppt("lil", 13.3)];
+        assert_eq!(frames, changed(frames.clone(), &settings, &mut sent));
+        assert!(changed(frames, &settings, &mut sent).is_empty());
+
+        let frames = vec![mppt("big", 13.4), mppt("lil", 13.5)];
+        assert_eq!(
+            vec![mppt("big", 13.4), mppt("lil", 13.5)],
+            changed(frames, &settings, &mut sent)
+        );
 
-        interval.tick().await;
+        settings.devices = Some(vec!["lil".to_owned()].into_iter().collect());
+        let frames = vec![mppt("big", 13.6), mppt("lil", 13.7)];
+        assert_eq!(
+            vec![mppt("lil", 13.7)],
+            changed(frames, &settings, &mut sent)
+        );
     }
 }
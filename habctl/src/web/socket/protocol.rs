@@ -0,0 +1,561 @@
+//! Messages exchanged with the web client over the UI socket
+//!
+//! Every message is an envelope of the protocol version, the message type by name, a sequence
+//! number and the payload, so a reordered or added message type can't be mistaken for another.
+//! The encoding and version are negotiated at connect as a WebSocket subprotocol, such as
+//! `habctl.v1.bincode` or `habctl.v1.json`.
+//!
+//! Payloads of a version don't change; changing one needs a new version. Peers skip message
+//! types they don't know, so types can be added without one.
+
+use crate::hardware::imu::ImuFrame;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use hab_victron::ve_direct::MpptFrame;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use warp::ws::Message;
+
+/// Current protocol version
+pub const VERSION: u16 = 1;
+
+/// Subprotocol name prefix, followed by the version and encoding
+const PROTOCOL_PREFIX: &str = "habctl.v";
+
+/// Message sent to the web client
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
+    SystemTime(DateTime<Utc>),
+
+    /// Latest frame from an MPPT controller
+    Mppt(MpptUpdate),
+
+    /// Latest frame from an IMU
+    Imu(ImuUpdate),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MpptUpdate {
+    pub name: String,
+    pub timestamp: Option<f32>,
+    pub frame: MpptFrame,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImuUpdate {
+    pub name: String,
+    pub frame: ImuFrame,
+}
+
+impl Data {
+    /// Device the message is from
+    pub fn device(&self) -> Option<&str> {
+        match self {
+            Data::Mppt(update) => Some(&update.name),
+            Data::Imu(update) => Some(&update.name),
+            Data::SystemTime(_) => None,
+        }
+    }
+
+    pub fn encode(&self, protocol: Protocol, sequence: u64) -> Result<Message> {
+        match self {
+            Data::SystemTime(time) => protocol.encode("system_time", sequence, time),
+            Data::Mppt(update) => protocol.encode("mppt", sequence, update),
+            Data::Imu(update) => protocol.encode("imu", sequence, update),
+        }
+    }
+
+    /// Decodes a message as the client does, or `None` if its type is unknown
+    #[cfg(test)]
+    pub fn decode(protocol: Protocol, message: &[u8]) -> Result<Option<Data>> {
+        let envelope = protocol.open(message)?;
+        Ok(match envelope.kind.as_str() {
+            "system_time" => Some(Data::SystemTime(envelope.payload.decode()?)),
+            "mppt" => Some(Data::Mppt(envelope.payload.decode()?)),
+            "imu" => Some(Data::Imu(envelope.payload.decode()?)),
+            _ => None,
+        })
+    }
+}
+
+/// Message received from the web client
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    /// Only send frames from the named devices, instead of from all of them
+    Subscribe(Vec<String>),
+
+    /// Send updates every given number of milliseconds, instead of the configured interval
+    SetRate(u64),
+}
+
+impl Request {
+    /// Encodes a message as the client does
+    #[cfg(test)]
+    pub fn encode(&self, protocol: Protocol, sequence: u64) -> Result<Message> {
+        match self {
+            Request::Subscribe(devices) => protocol.encode("subscribe", sequence, devices),
+            Request::SetRate(interval) => protocol.encode("set_rate", sequence, interval),
+        }
+    }
+
+    /// Decodes a message, or `None` if its type is unknown
+    pub fn decode(protocol: Protocol, message: &[u8]) -> Result<Option<Request>> {
+        let envelope = protocol.open(message)?;
+        Ok(match envelope.kind.as_str() {
+            "subscribe" => Some(Request::Subscribe(envelope.payload.decode()?)),
+            "set_rate" => Some(Request::SetRate(envelope.payload.decode()?)),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Envelope<P> {
+    version: u16,
+
+    #[serde(rename = "type")]
+    kind: String,
+
+    /// Count of messages sent before this one on the connection
+    sequence: u64,
+
+    payload: P,
+}
+
+/// Payload still in its encoding, until the message type is known
+enum Payload {
+    Bincode(Vec<u8>),
+    Json(serde_json::Value),
+}
+
+impl Payload {
+    fn decode<T: DeserializeOwned>(self) -> Result<T> {
+        Ok(match self {
+            Payload::Bincode(bytes) => bincode::deserialize(&bytes)?,
+            Payload::Json(value) => serde_json::from_value(value)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Binary messages, in the bincode 1 default format
+    Bincode,
+
+    /// Text messages
+    Json,
+}
+
+/// Version and encoding negotiated for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protocol {
+    pub version: u16,
+    pub encoding: Encoding,
+}
+
+impl Default for Protocol {
+    /// Used when the client doesn't ask for a subprotocol
+    fn default() -> Self {
+        Protocol {
+            version: VERSION,
+            encoding: Encoding::Bincode,
+        }
+    }
+}
+
+impl Protocol {
+    /// Parses a subprotocol name, if it is one that is supported
+    pub fn parse(name: &str) -> Option<Protocol> {
+        let (version, encoding) = name.strip_prefix(PROTOCOL_PREFIX)?.split_once('.')?;
+        let version = version.parse().ok().filter(|version| *version == VERSION)?;
+        let encoding = match encoding {
+            "bincode" => Encoding::Bincode,
+            "json" => Encoding::Json,
+            _ => return None,
+        };
+
+        Some(Protocol { version, encoding })
+    }
+
+    /// First supported subprotocol of those offered in a `Sec-WebSocket-Protocol` header
+    pub fn negotiate(offered: &str) -> Option<Protocol> {
+        offered.split(',').map(str::trim).find_map(Protocol::parse)
+    }
+
+    fn encode<T: Serialize>(self, kind: &str, sequence: u64, payload: &T) -> Result<Message> {
+        let kind = kind.to_owned();
+        Ok(match self.encoding {
+            Encoding::Bincode => Message::binary(bincode::serialize(&Envelope {
+                version: self.version,
+                kind,
+                sequence,
+                payload: bincode::serialize(payload)?,
+            })?),
+            Encoding::Json => Message::text(serde_json::to_string(&Envelope {
+                version: self.version,
+                kind,
+                sequence,
+                payload,
+            })?),
+        })
+    }
+
+    /// Reads the envelope of a message, checking it is of this version
+    fn open(self, message: &[u8]) -> Result<Envelope<Payload>> {
+        let envelope = match self.encoding {
+            Encoding::Bincode => {
+                let envelope: Envelope<Vec<u8>> = bincode::deserialize(message)?;
+                Envelope {
+                    version: envelope.version,
+                    kind: envelope.kind,
+                    sequence: envelope.sequence,
+                    payload: Payload::Bincode(envelope.payload),
+                }
+            }
+            Encoding::Json => {
+                let envelope: Envelope<serde_json::Value> = serde_json::from_slice(message)?;
+                Envelope {
+                    version: envelope.version,
+                    kind: envelope.kind,
+                    sequence: envelope.sequence,
+                    payload: Payload::Json(envelope.payload),
+                }
+            }
+        };
+
+        if envelope.version != self.version {
+            bail!(
+                "{} message #{} is version {}, expected {}",
+                envelope.kind,
+                envelope.sequence,
+                envelope.version,
+                self.version
+            );
+        }
+
+        Ok(envelope)
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoding = match self.encoding {
+            Encoding::Bincode => "bincode",
+            Encoding::Json => "json",
+        };
+        write!(f, "{}{}.{}", PROTOCOL_PREFIX, self.version, encoding)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Data, Encoding, ImuUpdate, MpptUpdate, Protocol, Request};
+    use crate::hardware::imu::fusion::EulerAngles;
+    use crate::hardware::imu::motion::MotionEvent;
+    use crate::hardware::imu::ImuFrame;
+    use chrono::{TimeZone, Utc};
+    use hab_victron::ve_direct::{MpptFrame, StateOfOperation};
+    use nalgebra as na;
+    use serde_json::json;
+
+    const BINCODE: Protocol = Protocol {
+        version: 1,
+        encoding: Encoding::Bincode,
+    };
+
+    const JSON: Protocol = Protocol {
+        version: 1,
+        encoding: Encoding::Json,
+    };
+
+    fn data() -> Vec<Data> {
+        vec![
+            Data::SystemTime(Utc.ymd(2021, 6, 1).and_hms(12, 0, 0)),
+            Data::Mppt(MpptUpdate {
+                name: "big".to_owned(),
+                timestamp: Some(1.0),
+                frame: MpptFrame {
+                    battery_voltage: Some(13.38),
+                    state: Some(StateOfOperation::Bulk),
+                    ..MpptFrame::default()
+                },
+            }),
+            Data::Imu(ImuUpdate {
+                name: "hab".to_owned(),
+                frame: ImuFrame {
+                    timestamp: Some(1.0),
+                    heading: Some(90.0),
+                    ..ImuFrame::default()
+                },
+            }),
+        ]
+    }
+
+    #[test]
+    fn negotiate_protocol() {
+        assert_eq!(
+            Some(JSON),
+            Protocol::negotiate("habctl.v2.json, habctl.v1.json, habctl.v1.bincode")
+        );
+        assert_eq!("habctl.v1.bincode", BINCODE.to_string());
+        assert_eq!(Some(BINCODE), Protocol::parse(&BINCODE.to_string()));
+        assert_eq!(None, Protocol::negotiate("habctl.v2.bincode, chat"));
+        assert_eq!(None, Protocol::parse("habctl.v1.xml"));
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        for protocol in [BINCODE, JSON] {
+            for (sequence, data) in data().into_iter().enumerate() {
+                let message = data.encode(protocol, sequence as u64).unwrap();
+                assert_eq!(
+                    Some(data),
+                    Data::decode(protocol, message.as_bytes()).unwrap()
+                );
+            }
+
+            for request in [
+                Request::Subscribe(vec!["big".to_owned()]),
+                Request::SetRate(250),
+            ] {
+                let message = request.encode(protocol, 0).unwrap();
+                assert_eq!(
+                    Some(request),
+                    Request::decode(protocol, message.as_bytes()).unwrap()
+                );
+            }
+        }
+    }
+
+    /// Version 1 messages as sent by existing clients, which must keep decoding
+    #[test]
+    fn version_1_requests() {
+        #[rustfmt::skip]
+        let set_rate = [
+            0x01, 0x00,
+            0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            b's', b'e', b't', b'_', b'r', b'a', b't', b'e',
+            0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xfa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(
+            Some(Request::SetRate(250)),
+            Request::decode(BINCODE, &set_rate).unwrap()
+        );
+        assert_eq!(
+            &set_rate[..],
+            Request::SetRate(250).encode(BINCODE, 7).unwrap().as_bytes()
+        );
+
+        let subscribe = r#"{"version":1,"type":"subscribe","sequence":0,"payload":["big","hab"]}"#;
+        assert_eq!(
+            Some(Request::Subscribe(vec!["big".to_owned(), "hab".to_owned()])),
+            Request::decode(JSON, subscribe.as_bytes()).unwrap()
+        );
+    }
+
+    /// Version 1 messages as expected by existing clients
+    #[test]
+    fn version_1_data() {
+        let time = Data::SystemTime(Utc.ymd(2021, 6, 1).and_hms(12, 0, 0));
+        assert_eq!(
+            r#"{"version":1,"type":"system_time","sequence":3,"payload":"2021-06-01T12:00:00Z"}"#,
+            time.encode(JSON, 3).unwrap().to_str().unwrap()
+        );
+
+        let encoded = time.encode(BINCODE, 3).unwrap();
+        assert_eq!(
+            [0x01, 0x00, 0x0b, 0, 0, 0, 0, 0, 0, 0],
+            encoded.as_bytes()[..10]
+        );
+        assert_eq!(b"system_time", &encoded.as_bytes()[10..21]);
+    }
+
+    /// Version 1 MPPT and IMU messages, pinning every field of the frames
+    #[test]
+    fn version_1_frames() {
+        let time = Utc.ymd(2021, 6, 1).and_hms(12, 0, 0);
+        let mppt = Data::Mppt(MpptUpdate {
+            name: "big".to_owned(),
+            timestamp: Some(1.0),
+            frame: MpptFrame {
+                battery_voltage: Some(13.5),
+                panel_power: Some(120),
+                state: Some(StateOfOperation::Bulk),
+                ..MpptFrame::default()
+            },
+        });
+        let imu = Data::Imu(ImuUpdate {
+            name: "hab".to_owned(),
+            frame: ImuFrame {
+                timestamp: Some(1.0),
+                accelerometer: Some(na::Vector3::new(0.0, 0.0, 1.0)),
+                heading: Some(90.0),
+                orientation: Some(na::UnitQuaternion::identity()),
+                euler: Some(EulerAngles {
+                    roll: 0.0,
+                    pitch: 0.0,
+                    yaw: 90.0,
+                }),
+                motion: Some(MotionEvent {
+                    start: time,
+                    end: time,
+                    peak_g: 0.5,
+                    tilt: 2.0,
+                }),
+                temperature: Some(25.0),
+                ..ImuFrame::default()
+            },
+        });
+
+        #[rustfmt::skip]
+        let mppt_bincode = [
+            0x01, 0x00,
+            0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'm', b'p', b'p', b't',
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // name
+            0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'b', b'i', b'g',
+            // timestamp
+            0x01, 0x00, 0x00, 0x80, 0x3f,
+            // battery_voltage, panel_voltage, panel_power
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2b, 0x40,
+            0x00,
+            0x01, 0x78, 0x00,
+            // battery_current through error
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // state
+            0x01, 0x03, 0x00, 0x00, 0x00,
+            // firmware_version through mppt_status
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let mppt_json = json!({
+            "version": 1,
+            "type": "mppt",
+            "sequence": 0,
+            "payload": {
+                "name": "big",
+                "timestamp": 1.0,
+                "frame": {
+                    "battery_voltage": 13.5,
+                    "panel_voltage": null,
+                    "panel_power": 120,
+                    "battery_current": null,
+                    "load_current": null,
+                    "load_state": null,
+                    "relay_state": null,
+                    "off_reason": null,
+                    "yield_total": null,
+                    "yield_today": null,
+                    "maximum_power_today": null,
+                    "yield_yesterday": null,
+                    "maximum_power_yesterday": null,
+                    "error": null,
+                    "state": "Bulk",
+                    "firmware_version": null,
+                    "firmware_version_24": null,
+                    "product_id": null,
+                    "serial_number": null,
+                    "day_number": null,
+                    "mppt_status": null,
+                },
+            },
+        });
+
+        #[rustfmt::skip]
+        let imu_bincode = [
+            0x01, 0x00,
+            0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'i', b'm', b'u',
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // name
+            0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'a', b'b',
+            // timestamp, gyrometer
+            0x01, 0x00, 0x00, 0x80, 0x3f,
+            0x00,
+            // accelerometer
+            0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x3f,
+            // magnetometer, heading
+            0x00,
+            0x01, 0x00, 0x00, 0xb4, 0x42,
+            // orientation
+            0x01, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x3f,
+            // euler
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xb4, 0x42,
+            // motion
+            0x01,
+            0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            b'2', b'0', b'2', b'1', b'-', b'0', b'6', b'-', b'0', b'1',
+            b'T', b'1', b'2', b':', b'0', b'0', b':', b'0', b'0', b'Z',
+            0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            b'2', b'0', b'2', b'1', b'-', b'0', b'6', b'-', b'0', b'1',
+            b'T', b'1', b'2', b':', b'0', b'0', b':', b'0', b'0', b'Z',
+            0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00, 0x40,
+            // temperature
+            0x01, 0x00, 0x00, 0xc8, 0x41,
+        ];
+        let imu_json = json!({
+            "version": 1,
+            "type": "imu",
+            "sequence": 0,
+            "payload": {
+                "name": "hab",
+                "frame": {
+                    "timestamp": 1.0,
+                    "gyrometer": null,
+                    "accelerometer": [0.0, 0.0, 1.0],
+                    "magnetometer": null,
+                    "heading": 90.0,
+                    "orientation": [0.0, 0.0, 0.0, 1.0],
+                    "euler": { "roll": 0.0, "pitch": 0.0, "yaw": 90.0 },
+                    "motion": {
+                        "start": "2021-06-01T12:00:00Z",
+                        "end": "2021-06-01T12:00:00Z",
+                        "peak_g": 0.5,
+                        "tilt": 2.0,
+                    },
+                    "temperature": 25.0,
+                },
+            },
+        });
+
+        for (data, bincode, json) in [
+            (mppt, &mppt_bincode[..], mppt_json),
+            (imu, &imu_bincode, imu_json),
+        ] {
+            assert_eq!(bincode, data.encode(BINCODE, 0).unwrap().as_bytes());
+            assert_eq!(Some(data.clone()), Data::decode(BINCODE, bincode).unwrap());
+
+            let encoded = data.encode(JSON, 0).unwrap();
+            assert_eq!(
+                json,
+                serde_json::from_slice::<serde_json::Value>(encoded.as_bytes()).unwrap()
+            );
+            assert_eq!(
+                Some(data),
+                Data::decode(JSON, json.to_string().as_bytes()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_types_are_skipped() {
+        let message = r#"{"version":1,"type":"battery","sequence":0,"payload":{"soc":0.5}}"#;
+        assert_eq!(None, Data::decode(JSON, message.as_bytes()).unwrap());
+        assert_eq!(None, Request::decode(JSON, message.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let message = r#"{"version":2,"type":"set_rate","sequence":0,"payload":250}"#;
+        assert!(Request::decode(JSON, message.as_bytes()).is_err());
+
+        let message = r#"{"version":1,"type":"set_rate","sequence":0,"payload":"fast"}"#;
+        assert!(Request::decode(JSON, message.as_bytes()).is_err());
+    }
+}